@@ -0,0 +1,102 @@
+// 分流规则匹配基准测试：预编译规则 vs 原有的逐条线性扫描
+// 运行: cargo bench --bench rule_set
+#![allow(dead_code)]
+
+#[path = "../src/bypass.rs"]
+mod bypass;
+#[path = "../src/rule_set.rs"]
+mod rule_set;
+
+use rule_set::CompiledRules;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RULE_COUNT: usize = 30_000;
+const LOOKUPS: usize = 20_000;
+
+// 原有实现：每次请求都对每条规则做小写化和格式化
+fn linear_scan(host: &str, direct_domains: &[String]) -> bool {
+    let host_lower = host.to_lowercase();
+    for domain in direct_domains {
+        let domain_lower = domain.to_lowercase();
+        if host_lower == domain_lower || host_lower.ends_with(&format!(".{}", domain_lower)) {
+            return true;
+        }
+    }
+    false
+}
+
+fn generate_rules() -> Vec<String> {
+    let tlds = ["com", "net", "org", "io", "cn"];
+    let mut rules: Vec<String> = (0..RULE_COUNT)
+        .map(|i| format!("site{}.example{}.{}", i, i % 97, tlds[i % tlds.len()]))
+        .collect();
+    rules.push("10.20.0.0/16".to_string());
+    rules
+}
+
+fn generate_hosts() -> Vec<String> {
+    (0..LOOKUPS)
+        .map(|i| match i % 4 {
+            // 命中子域名
+            0 => format!(
+                "www.site{}.example{}.com",
+                i * 5 % RULE_COUNT,
+                i * 5 % RULE_COUNT % 97
+            ),
+            // 命中规则本身
+            1 => format!(
+                "site{}.example{}.net",
+                (i * 5 + 1) % RULE_COUNT,
+                ((i * 5 + 1) % RULE_COUNT) % 97
+            ),
+            // 未命中
+            2 => format!("cdn{}.unknown-host.org", i),
+            _ => format!("api.service{}.google.com", i),
+        })
+        .collect()
+}
+
+fn measure<F: FnMut(&str) -> bool>(name: &str, hosts: &[String], mut f: F) -> Duration {
+    let start = Instant::now();
+    let mut hits = 0usize;
+    for host in hosts {
+        if black_box(f(black_box(host))) {
+            hits += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<12} {:>10.2?} 总耗时, {:>10.2?}/次, 命中 {}",
+        name,
+        elapsed,
+        elapsed / hosts.len() as u32,
+        hits
+    );
+    elapsed
+}
+
+fn main() {
+    let rules = generate_rules();
+    let hosts = generate_hosts();
+    println!("规则数: {}, 查询数: {}", rules.len(), hosts.len());
+
+    let start = Instant::now();
+    let compiled = CompiledRules::compile(&rules);
+    println!(
+        "编译耗时: {:.2?} ({} 条规则)",
+        start.elapsed(),
+        compiled.len()
+    );
+
+    // 线性扫描太慢，只取一部分查询
+    let linear_hosts = &hosts[..hosts.len() / 20];
+    let linear = measure("linear_scan", linear_hosts, |host| {
+        linear_scan(host, &rules)
+    });
+    let trie = measure("compiled", &hosts, |host| compiled.find(host).is_some());
+
+    let linear_per = linear.as_nanos() as f64 / linear_hosts.len() as f64;
+    let trie_per = trie.as_nanos() as f64 / hosts.len() as f64;
+    println!("加速比: {:.0}x", linear_per / trie_per);
+}
@@ -0,0 +1,164 @@
+use crate::proxy_server::RouteReason;
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const LOG_FILE_NAME: &str = "access.log";
+
+// 访问日志设置：每个请求或隧道写一行 JSON，文件超过大小后轮转
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AccessLogSettings {
+    pub enabled: bool,
+    // 单个日志文件的最大大小（MB），超过后轮转为 access.1.log
+    pub max_file_size_mb: u64,
+    // 保留的已轮转文件数，更早的文件被删除
+    pub max_files: usize,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_file_size_mb: 10,
+            max_files: 5,
+        }
+    }
+}
+
+impl AccessLogSettings {
+    fn max_file_size(&self) -> u64 {
+        self.max_file_size_mb.max(1) * 1024 * 1024
+    }
+}
+
+// 一条访问记录
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    pub time: String, // 连接开始时间（RFC 3339，本地时区）
+    pub client: String,
+    pub method: String,              // HTTP 方法、CONNECT 或 SOCKS5
+    pub target: Option<String>,      // 请求的 URL 或 "host:port"
+    pub reason: Option<RouteReason>, // 路由决策的原因
+    pub rule: Option<String>,        // 命中的规则原文
+    pub upstream: Option<String>,    // direct、http://host:port 或 socks5://host:port
+    pub status: Option<u16>,         // 目标返回的 HTTP 状态码，或代理自身的响应（200/407/502）
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+// 访问日志写入器；dir 为空时不写入
+pub struct AccessLog {
+    dir: Option<PathBuf>,
+    settings: ArcSwap<AccessLogSettings>,
+    file: Mutex<Option<LogFile>>, // 首次写入时打开
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl AccessLog {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            settings: ArcSwap::default(),
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    pub fn configure(&self, settings: &AccessLogSettings) {
+        if **self.settings.load() == *settings {
+            return;
+        }
+        info!("访问日志: {:?}", settings);
+        self.settings.store(Arc::new(settings.clone()));
+        // 关闭后释放文件；重新启用时重新打开
+        if !settings.enabled {
+            *self.file.lock().unwrap() = None;
+        }
+    }
+
+    pub fn record(&self, entry: &AccessLogEntry) {
+        let settings = self.settings.load();
+        let Some(dir) = self.dir.as_deref().filter(|_| settings.enabled) else {
+            return;
+        };
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("序列化访问日志失败: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = write_line(dir, &settings, &mut file, line.as_bytes()) {
+            warn!("写入访问日志失败: {}", e);
+            *file = None;
+        }
+    }
+}
+
+fn open(dir: &Path) -> std::io::Result<LogFile> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(LOG_FILE_NAME))?;
+    let size = file.metadata()?.len();
+    Ok(LogFile { file, size })
+}
+
+fn write_line(
+    dir: &Path,
+    settings: &AccessLogSettings,
+    file: &mut Option<LogFile>,
+    line: &[u8],
+) -> std::io::Result<()> {
+    let log = match file {
+        Some(log) => log,
+        None => file.insert(open(dir)?),
+    };
+    // 写入后会超过大小限制时先轮转（包括上次运行留下的文件）
+    if log.size > 0 && log.size + line.len() as u64 > settings.max_file_size() {
+        *file = None;
+        rotate(dir, settings.max_files)?;
+        *file = Some(open(dir)?);
+    }
+    let log = file.as_mut().unwrap();
+    log.file.write_all(line)?;
+    log.size += line.len() as u64;
+    Ok(())
+}
+
+// access.log -> access.1.log -> access.2.log ...，超出保留数量的文件被删除
+fn rotate(dir: &Path, max_files: usize) -> std::io::Result<()> {
+    let rotated = |i: usize| dir.join(format!("access.{}.log", i));
+    let _ = fs::remove_file(rotated(max_files.max(1)));
+    for i in (1..max_files).rev() {
+        let _ = fs::rename(rotated(i), rotated(i + 1));
+    }
+    if max_files == 0 {
+        fs::remove_file(dir.join(LOG_FILE_NAME))
+    } else {
+        fs::rename(dir.join(LOG_FILE_NAME), rotated(1))
+    }
+}
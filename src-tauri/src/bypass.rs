@@ -0,0 +1,254 @@
+use std::net::IpAddr;
+
+// IP 网段（CIDR），单个 IP 视为 /32 或 /128
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    // 解析 "10.0.0.0/8"、"fe80::/10" 或单个 IP 地址
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (addr_part, prefix_part) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr_part
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .ok()?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix_part {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max_prefix)?,
+            None => max_prefix,
+        };
+        Some(Self { addr, prefix })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    // 检查 IP 是否在网段内（IPv4 映射的 IPv6 地址按 IPv4 处理）
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// 单条绕过规则
+#[derive(Debug, Clone)]
+enum BypassRule {
+    // "*"：所有主机
+    All,
+    // "<local>"：不含点号的主机名
+    Local,
+    // IP 地址或 CIDR 网段
+    Cidr(IpCidr, Option<u16>),
+    // 域名后缀："example.com" / ".example.com" 匹配自身及子域名，"*.example.com" 只匹配子域名
+    Domain {
+        suffix: String,
+        include_self: bool,
+        port: Option<u16>,
+    },
+    // 其他通配符模式，如 Windows 的 "192.168.*"、"*internal*"
+    Wildcard(String, Option<u16>),
+}
+
+// 代理绕过列表，统一处理 NO_PROXY、GNOME ignore-hosts、KDE NoProxyFor 和 Windows ProxyOverride
+#[derive(Debug, Clone, Default)]
+pub struct BypassList {
+    rules: Vec<BypassRule>,
+}
+
+impl BypassList {
+    // 解析绕过列表，逗号、分号和空白都视为分隔符
+    pub fn parse(list: &str) -> Self {
+        let rules = list
+            .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+            .filter_map(parse_rule)
+            .collect();
+        Self { rules }
+    }
+
+    // 检查主机（可带端口）是否命中绕过列表
+    pub fn matches(&self, host: &str, port: Option<u16>) -> bool {
+        let host = normalize_host(host);
+        let ip = host.parse::<IpAddr>().ok();
+
+        self.rules.iter().any(|rule| match rule {
+            BypassRule::All => true,
+            BypassRule::Local => ip.is_none() && !host.contains('.'),
+            BypassRule::Cidr(cidr, rule_port) => {
+                port_matches(*rule_port, port) && ip.is_some_and(|ip| cidr.contains(&ip))
+            }
+            BypassRule::Domain {
+                suffix,
+                include_self,
+                port: rule_port,
+            } => {
+                port_matches(*rule_port, port)
+                    && ip.is_none()
+                    && domain_matches(&host, suffix, *include_self)
+            }
+            BypassRule::Wildcard(pattern, rule_port) => {
+                port_matches(*rule_port, port) && wildcard_matches(pattern, &host)
+            }
+        })
+    }
+}
+
+// 统一主机名格式：小写、去掉 IPv6 方括号和末尾的点
+pub fn normalize_host(host: &str) -> String {
+    host.trim()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_lowercase()
+}
+
+// 检查主机名是否等于后缀或是其子域名
+pub fn domain_matches(host: &str, suffix: &str, include_self: bool) -> bool {
+    if host.len() == suffix.len() {
+        return include_self && host == suffix;
+    }
+    host.len() > suffix.len()
+        && host.ends_with(suffix)
+        && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+}
+
+fn port_matches(rule_port: Option<u16>, port: Option<u16>) -> bool {
+    match rule_port {
+        Some(p) => port == Some(p),
+        None => true,
+    }
+}
+
+fn parse_rule(entry: &str) -> Option<BypassRule> {
+    let entry = entry.trim().to_lowercase();
+    if entry.is_empty() {
+        return None;
+    }
+    if entry == "*" {
+        return Some(BypassRule::All);
+    }
+    if entry == "<local>" {
+        return Some(BypassRule::Local);
+    }
+
+    // 去掉可能存在的协议前缀，如 "http://example.com"
+    let entry = match entry.split_once("://") {
+        Some((_, rest)) => rest,
+        None => entry.as_str(),
+    };
+    let entry = entry.trim_end_matches('/');
+
+    let (host, port) = split_host_port(entry);
+    if host.is_empty() {
+        return None;
+    }
+
+    if let Some(cidr) = IpCidr::parse(host) {
+        return Some(BypassRule::Cidr(cidr, port));
+    }
+
+    let host = host.trim_end_matches('.');
+    if let Some(suffix) = host.strip_prefix("*.") {
+        if !suffix.contains('*') {
+            return Some(BypassRule::Domain {
+                suffix: suffix.to_string(),
+                include_self: false,
+                port,
+            });
+        }
+    }
+    if host.contains('*') {
+        return Some(BypassRule::Wildcard(host.to_string(), port));
+    }
+
+    let suffix = host.trim_start_matches('.');
+    if suffix.is_empty() {
+        return None;
+    }
+    Some(BypassRule::Domain {
+        suffix: suffix.to_string(),
+        include_self: true,
+        port,
+    })
+}
+
+// 拆分 "host:port"、"[::1]:port"；包含多个冒号的视为 IPv6 地址，不带端口
+fn split_host_port(entry: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = entry.strip_prefix('[') {
+        if let Some((host, after)) = rest.split_once(']') {
+            let port = after.strip_prefix(':').and_then(|p| p.parse().ok());
+            return (host, port);
+        }
+        return (rest, None);
+    }
+
+    if entry.matches(':').count() == 1 {
+        if let Some((host, port)) = entry.split_once(':') {
+            if let Ok(port) = port.parse::<u16>() {
+                return (host, Some(port));
+            }
+            if port == "*" {
+                return (host, None);
+            }
+        }
+    }
+    (entry, None)
+}
+
+// 简单的 "*" 通配符匹配
+fn wildcard_matches(pattern: &str, text: &str) -> bool {
+    let p = pattern.as_bytes();
+    let t = text.as_bytes();
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<usize> = None;
+    let mut star_ti = 0;
+
+    while ti < t.len() {
+        if pi < p.len() && p[pi] == b'*' {
+            star = Some(pi);
+            star_ti = ti;
+            pi += 1;
+        } else if pi < p.len() && p[pi] == t[ti] {
+            pi += 1;
+            ti += 1;
+        } else if let Some(s) = star {
+            pi = s + 1;
+            star_ti += 1;
+            ti = star_ti;
+        } else {
+            return false;
+        }
+    }
+
+    while pi < p.len() && p[pi] == b'*' {
+        pi += 1;
+    }
+    pi == p.len()
+}
@@ -0,0 +1,327 @@
+use crate::access_log::AccessLogEntry;
+use crate::proxy_server::RouteReason;
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// 隧道超过此时间没有数据时显示为空闲
+const IDLE_AFTER: Duration = Duration::from_secs(10);
+
+// 连接状态
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub enum ConnectionState {
+    Handshaking, // 读取请求、认证、连接目标中
+    Tunneling,   // 正在转发数据
+    Idle,        // 已建立隧道但近期没有数据
+}
+
+// 请求和路由信息
+#[derive(Debug, Clone, Default)]
+struct RequestInfo {
+    method: Option<String>,   // HTTP 方法、CONNECT 或 SOCKS5
+    target: Option<String>,   // 请求的 URL 或 "host:port"
+    upstream: Option<String>, // direct、http://host:port 或 socks5://host:port
+    reason: Option<RouteReason>,
+    rule: Option<String>,
+}
+
+// 当前请求的开始时间和开始时的字节数，同一连接上的每个请求单独计时和计量
+#[derive(Debug)]
+struct RequestStart {
+    time: DateTime<Local>,
+    at: Instant,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+// 单个客户端连接的信息，处理过程中逐步填写
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client: SocketAddr,
+    started: DateTime<Local>,
+    started_at: Instant,
+    request: Mutex<RequestInfo>,
+    request_start: Mutex<RequestStart>,
+    status: AtomicU16, // 0 表示尚未确定
+    tunneling: AtomicBool,
+    closed: AtomicBool,        // 已通过连接列表关闭或停止代理时强制关闭
+    last_active_ms: AtomicU64, // 最近一次有数据的时间，相对 started_at 的毫秒数
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+}
+
+impl ConnectionInfo {
+    fn new(id: u64, client: SocketAddr) -> Self {
+        let started = Local::now();
+        let started_at = Instant::now();
+        Self {
+            id,
+            client,
+            started,
+            started_at,
+            request: Mutex::default(),
+            request_start: Mutex::new(RequestStart {
+                time: started,
+                at: started_at,
+                bytes_up: 0,
+                bytes_down: 0,
+            }),
+            status: AtomicU16::new(0),
+            tunneling: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            last_active_ms: AtomicU64::new(0),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_method(&self, method: &str) {
+        self.request.lock().unwrap().method = Some(method.to_string());
+    }
+
+    pub fn set_target(&self, target: &str) {
+        self.request.lock().unwrap().target = Some(target.to_string());
+    }
+
+    // 路由决策结果，连接上游之前记录，连接失败时也会出现在访问日志中
+    pub fn set_route(&self, upstream: &str, reason: Option<RouteReason>, rule: Option<&str>) {
+        let mut request = self.request.lock().unwrap();
+        request.upstream = Some(upstream.to_string());
+        request.reason = reason;
+        request.rule = rule.map(|r| r.to_string());
+    }
+
+    // 已连接到目标，开始转发
+    pub fn set_tunneling(&self) {
+        self.touch();
+        self.tunneling.store(true, Ordering::Relaxed);
+    }
+
+    // 连接是否已被关闭，等待中的操作据此提前结束
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_status(&self, status: u16) {
+        self.status.store(status, Ordering::Relaxed);
+    }
+
+    // 从目标返回的第一段数据中读取 HTTP 状态码，只对尚未确定状态的连接（普通 HTTP 请求）生效
+    pub fn record_response(&self, head: &[u8]) {
+        if self.status.load(Ordering::Relaxed) != 0 || !head.starts_with(b"HTTP/") {
+            return;
+        }
+        let status = head
+            .split(|&b| b == b' ')
+            .nth(1)
+            .and_then(|code| std::str::from_utf8(code).ok())
+            .and_then(|code| code.parse().ok());
+        if let Some(status) = status {
+            self.set_status(status);
+        }
+    }
+
+    pub fn add_up(&self, bytes: u64) {
+        self.bytes_up.fetch_add(bytes, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn add_down(&self, bytes: u64) {
+        self.bytes_down.fetch_add(bytes, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        let now = self.started_at.elapsed().as_millis() as u64;
+        self.last_active_ms.store(now, Ordering::Relaxed);
+    }
+
+    fn entry(&self) -> ConnectionEntry {
+        let elapsed = self.started_at.elapsed();
+        let last_active = Duration::from_millis(self.last_active_ms.load(Ordering::Relaxed));
+        let state = if !self.tunneling.load(Ordering::Relaxed) {
+            ConnectionState::Handshaking
+        } else if elapsed.saturating_sub(last_active) >= IDLE_AFTER {
+            ConnectionState::Idle
+        } else {
+            ConnectionState::Tunneling
+        };
+        let request = self.request.lock().unwrap().clone();
+        ConnectionEntry {
+            id: self.id,
+            client: self.client.to_string(),
+            target: request.target,
+            route: request.upstream,
+            started: self.started.timestamp() as u64,
+            duration_secs: elapsed.as_secs(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            state,
+        }
+    }
+
+    // 当前请求的访问日志记录，连接结束时写入；没有读到请求的连接返回 None
+    pub fn access_entry(&self) -> Option<AccessLogEntry> {
+        let request = self.request.lock().unwrap().clone();
+        let start = self.request_start.lock().unwrap();
+        let status = self.status.load(Ordering::Relaxed);
+        Some(AccessLogEntry {
+            time: start.time.to_rfc3339_opts(SecondsFormat::Millis, false),
+            client: self.client.to_string(),
+            method: request.method?,
+            target: request.target,
+            reason: request.reason,
+            rule: request.rule,
+            upstream: request.upstream,
+            status: (status != 0).then_some(status),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed) - start.bytes_up,
+            bytes_down: self.bytes_down.load(Ordering::Relaxed) - start.bytes_down,
+            duration_ms: start.at.elapsed().as_millis() as u64,
+        })
+    }
+
+    // 同一连接上的一个请求处理完毕：返回它的访问日志记录，之后的请求重新计时和计量
+    pub fn finish_request(&self) -> Option<AccessLogEntry> {
+        let entry = self.access_entry();
+        self.request.lock().unwrap().method = None;
+        self.status.store(0, Ordering::Relaxed);
+        *self.request_start.lock().unwrap() = RequestStart {
+            time: Local::now(),
+            at: Instant::now(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+        };
+        entry
+    }
+}
+
+// 返回给前端的连接条目
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionEntry {
+    pub id: u64,
+    pub client: String,
+    pub target: Option<String>,
+    pub route: Option<String>, // 上游：direct、http://host:port 或 socks5://host:port
+    pub started: u64,          // 开始时间（Unix 秒）
+    pub duration_secs: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub state: ConnectionState,
+}
+
+struct TrackedConnection {
+    stream: TcpStream, // 客户端连接的克隆句柄，关闭它会使读写该连接的线程立即返回
+    info: Arc<ConnectionInfo>,
+}
+
+// 进行中的客户端连接，用于连接列表、关闭单个连接以及停止代理时等待或强制关闭
+#[derive(Default)]
+pub struct ConnectionTracker {
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, TrackedConnection>>,
+    idle: Condvar,
+}
+
+// 连接处理线程持有，线程结束时自动注销
+pub struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+    info: Arc<ConnectionInfo>,
+}
+
+impl ConnectionGuard {
+    pub fn info(&self) -> &Arc<ConnectionInfo> {
+        &self.info
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self.tracker.active.lock().unwrap();
+        active.remove(&self.info.id);
+        if active.is_empty() {
+            self.tracker.idle.notify_all();
+        }
+    }
+}
+
+impl ConnectionTracker {
+    // 登记客户端连接
+    pub fn register(
+        self: &Arc<Self>,
+        stream: &TcpStream,
+        client: SocketAddr,
+    ) -> Option<ConnectionGuard> {
+        let stream = stream.try_clone().ok()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let info = Arc::new(ConnectionInfo::new(id, client));
+        self.active.lock().unwrap().insert(
+            id,
+            TrackedConnection {
+                stream,
+                info: Arc::clone(&info),
+            },
+        );
+        Some(ConnectionGuard {
+            tracker: Arc::clone(self),
+            info,
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    // 当前连接列表，按开始时间排序
+    pub fn list(&self) -> Vec<ConnectionEntry> {
+        let mut entries: Vec<ConnectionEntry> = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|c| c.info.entry())
+            .collect();
+        entries.sort_by_key(|e| e.id);
+        entries
+    }
+
+    // 关闭指定连接：关闭客户端连接后隧道两个方向都会停止
+    pub fn close(&self, id: u64) -> bool {
+        match self.active.lock().unwrap().get(&id) {
+            Some(connection) => {
+                connection.info.closed.store(true, Ordering::Relaxed);
+                let _ = connection.stream.shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 等待所有连接结束，超过截止时间返回 false
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut active = self.active.lock().unwrap();
+        while !active.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            active = self.idle.wait_timeout(active, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    // 强制关闭所有连接，返回关闭的数量
+    pub fn abort_all(&self) -> usize {
+        let active = self.active.lock().unwrap();
+        for connection in active.values() {
+            connection.info.closed.store(true, Ordering::Relaxed);
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        active.len()
+    }
+}
@@ -0,0 +1,573 @@
+use arc_swap::ArcSwap;
+use base64::Engine;
+use chrono::{DateTime, Local, SecondsFormat};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const MAX_HEAD_BYTES: usize = 64 * 1024; // 响应头的最大记录长度
+
+// 抓包设置：记录经过代理的明文 HTTP 请求和响应，可导出为 HAR 1.2 文件
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct HarSettings {
+    pub enabled: bool,
+    // 请求体和响应体各自的最大记录字节数，超出部分截断
+    pub max_body_bytes: usize,
+    // 内存中保留的最大条目数，超出后丢弃最早的条目
+    pub max_entries: usize,
+}
+
+impl Default for HarSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_body_bytes: 256 * 1024,
+            max_entries: 1000,
+        }
+    }
+}
+
+// HAR 1.2 文档结构，只包含本程序能够提供的字段
+#[derive(Debug, Clone, Serialize)]
+pub struct HarDocument {
+    pub log: HarLog,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HarLog {
+    pub version: &'static str,
+    pub creator: HarCreator,
+    pub entries: Vec<HarEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HarCreator {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarEntry {
+    pub started_date_time: String,
+    pub time: f64,
+    pub request: HarRequest,
+    pub response: HarResponse,
+    pub cache: HarCache,
+    pub timings: HarTimings,
+    pub connection: String, // 本地代理的连接编号，与连接列表一致
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    pub cookies: Vec<HarNameValue>,
+    pub headers: Vec<HarNameValue>,
+    pub query_string: Vec<HarNameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<HarPostData>,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarResponse {
+    pub status: u16,
+    pub status_text: String,
+    pub http_version: String,
+    pub cookies: Vec<HarNameValue>,
+    pub headers: Vec<HarNameValue>,
+    pub content: HarContent,
+    #[serde(rename = "redirectURL")]
+    pub redirect_url: String,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HarNameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarPostData {
+    pub mime_type: String,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarContent {
+    pub size: i64,
+    pub mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HarCache {}
+
+// 各阶段耗时（毫秒），-1 表示不适用
+#[derive(Debug, Clone, Serialize)]
+pub struct HarTimings {
+    pub blocked: f64,
+    pub dns: f64,
+    pub connect: f64,
+    pub send: f64,
+    pub wait: f64,
+    pub receive: f64,
+    pub ssl: f64,
+}
+
+// 抓包记录器：保存已完成的条目
+#[derive(Default)]
+pub struct HarRecorder {
+    settings: ArcSwap<HarSettings>,
+    entries: Mutex<VecDeque<HarEntry>>,
+}
+
+impl HarRecorder {
+    pub fn configure(&self, settings: &HarSettings) {
+        if **self.settings.load() != *settings {
+            info!("HAR 抓包: {:?}", settings);
+            self.settings.store(Arc::new(settings.clone()));
+        }
+    }
+
+    // 开始记录一个明文 HTTP 请求，request 为客户端发来的请求；未启用抓包时返回 None
+    pub fn begin_http(
+        self: &Arc<Self>,
+        connection: u64,
+        url: &str,
+        request: &str,
+    ) -> Option<Arc<HarCapture>> {
+        let settings = self.settings.load();
+        if !settings.enabled {
+            return None;
+        }
+        let capture = HarCapture::new(self, connection, url, settings.max_body_bytes);
+        // 连接目标失败时也能记录请求方法和请求头
+        let (head, _) = split_head(request.as_bytes());
+        capture.exchange.lock().unwrap().request_head = String::from_utf8_lossy(head).to_string();
+        Some(Arc::new(capture))
+    }
+
+    // 开始记录一个 CONNECT 隧道，内容已加密，只记录耗时
+    pub fn begin_tunnel(
+        self: &Arc<Self>,
+        connection: u64,
+        target: &str,
+    ) -> Option<Arc<HarCapture>> {
+        let settings = self.settings.load();
+        if !settings.enabled {
+            return None;
+        }
+        let mut capture = HarCapture::new(self, connection, target, 0);
+        capture.tunnel = true;
+        Some(Arc::new(capture))
+    }
+
+    fn push(&self, entry: HarEntry) {
+        let max_entries = self.settings.load().max_entries.max(1);
+        let mut entries = self.entries.lock().unwrap();
+        while entries.len() >= max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn export(&self) -> HarDocument {
+        HarDocument {
+            log: HarLog {
+                version: "1.2",
+                creator: HarCreator {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries: self.entries.lock().unwrap().iter().cloned().collect(),
+            },
+        }
+    }
+}
+
+// 请求和响应的原始数据及各阶段时间点
+#[derive(Default)]
+struct Exchange {
+    max_body: usize,
+    request_head: String,
+    request_body: Vec<u8>,
+    request_body_size: usize,
+    request_body_remaining: usize, // 按请求头（Content-Length 或分块传输）还应有的请求体字节数
+    response: Vec<u8>,             // 响应头及截断后的响应体
+    response_size: usize,
+    connected: Option<Instant>,
+    sent: Option<Instant>,
+    first_byte: Option<Instant>,
+    last_byte: Option<Instant>,
+    error: Option<String>,
+}
+
+impl Exchange {
+    fn append_request_body(&mut self, data: &[u8]) {
+        let data = &data[..data.len().min(self.request_body_remaining)];
+        self.request_body_remaining -= data.len();
+        self.request_body_size += data.len();
+        let room = self.max_body.saturating_sub(self.request_body.len());
+        self.request_body
+            .extend_from_slice(&data[..data.len().min(room)]);
+    }
+}
+
+// 一次请求的抓包记录，连接结束（最后一个引用释放）时写入记录器
+pub struct HarCapture {
+    recorder: Arc<HarRecorder>,
+    connection: u64,
+    url: String, // 明文请求的完整 URL，或隧道的 "host:port"
+    tunnel: bool,
+    started: DateTime<Local>,
+    started_at: Instant,
+    exchange: Mutex<Exchange>,
+}
+
+impl HarCapture {
+    fn new(recorder: &Arc<HarRecorder>, connection: u64, url: &str, max_body: usize) -> Self {
+        Self {
+            recorder: Arc::clone(recorder),
+            connection,
+            url: url.to_string(),
+            tunnel: false,
+            started: Local::now(),
+            started_at: Instant::now(),
+            exchange: Mutex::new(Exchange {
+                max_body,
+                ..Default::default()
+            }),
+        }
+    }
+
+    // 已连接到目标或上游代理
+    pub fn connected(&self) {
+        self.exchange.lock().unwrap().connected = Some(Instant::now());
+    }
+
+    // 请求已发送，request 为实际发往目标的请求头和已读取的请求体
+    pub fn sent(&self, request: &[u8]) {
+        let (head, body) = split_head(request);
+        let mut exchange = self.exchange.lock().unwrap();
+        exchange.request_head = String::from_utf8_lossy(head).to_string();
+        let headers = parse_headers(exchange.request_head.lines().skip(1));
+        exchange.request_body_remaining = if is_chunked(&headers) {
+            usize::MAX
+        } else {
+            header_value(&headers, "content-length")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0)
+        };
+        exchange.append_request_body(body);
+        exchange.sent = Some(Instant::now());
+    }
+
+    pub fn failed(&self, error: &str) {
+        self.exchange.lock().unwrap().error = Some(error.to_string());
+    }
+
+    // 隧道中客户端发往目标的数据（请求体的剩余部分）
+    pub fn on_upload(&self, data: &[u8]) {
+        self.exchange.lock().unwrap().append_request_body(data);
+    }
+
+    // 隧道中目标返回的数据
+    pub fn on_download(&self, data: &[u8]) {
+        let now = Instant::now();
+        let mut exchange = self.exchange.lock().unwrap();
+        exchange.first_byte.get_or_insert(now);
+        exchange.last_byte = Some(now);
+        exchange.response_size += data.len();
+        let limit = head_len(&exchange.response).unwrap_or(MAX_HEAD_BYTES) + exchange.max_body;
+        let room = limit.saturating_sub(exchange.response.len());
+        exchange
+            .response
+            .extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    fn to_entry(&self, exchange: &Exchange) -> HarEntry {
+        let ended = Instant::now();
+        let millis = |from: Instant, to: Option<Instant>| {
+            to.map_or(0.0, |to| {
+                to.saturating_duration_since(from).as_secs_f64() * 1000.0
+            })
+        };
+        let connected = exchange.connected.unwrap_or(ended);
+        let (send, wait, receive) = if self.tunnel {
+            (0.0, 0.0, millis(connected, Some(ended)))
+        } else {
+            let sent = exchange.sent.unwrap_or(connected);
+            let first_byte = exchange.first_byte.unwrap_or(sent);
+            (
+                millis(connected, exchange.sent),
+                millis(sent, exchange.first_byte),
+                millis(first_byte, exchange.last_byte),
+            )
+        };
+        let timings = HarTimings {
+            blocked: -1.0,
+            dns: -1.0,
+            connect: millis(self.started_at, exchange.connected),
+            send,
+            wait,
+            receive,
+            ssl: -1.0,
+        };
+        let (request, response) = if self.tunnel {
+            (self.tunnel_request(), tunnel_response(exchange))
+        } else {
+            (self.http_request(exchange), http_response(exchange))
+        };
+        HarEntry {
+            started_date_time: self.started.to_rfc3339_opts(SecondsFormat::Millis, false),
+            time: timings.connect.max(0.0) + send + wait + receive,
+            request,
+            response,
+            cache: HarCache {},
+            timings,
+            connection: self.connection.to_string(),
+            comment: exchange.error.clone(),
+        }
+    }
+
+    fn http_request(&self, exchange: &Exchange) -> HarRequest {
+        let mut lines = exchange.request_head.lines();
+        let mut parts = lines.next().unwrap_or_default().split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let http_version = parts.nth(1).unwrap_or("HTTP/1.1").to_string();
+        let headers = parse_headers(lines);
+        let query_string = url::Url::parse(&self.url)
+            .map(|url| {
+                url.query_pairs()
+                    .map(|(name, value)| HarNameValue {
+                        name: name.to_string(),
+                        value: value.to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let post_data = (exchange.request_body_size > 0).then(|| {
+            let body = if is_chunked(&headers) {
+                decode_chunked(&exchange.request_body)
+            } else {
+                exchange.request_body.clone()
+            };
+            let (text, _) = body_text(&body);
+            HarPostData {
+                mime_type: header_value(&headers, "content-type").unwrap_or_default(),
+                text,
+                comment: truncated(exchange.request_body.len(), exchange.request_body_size),
+            }
+        });
+        HarRequest {
+            method,
+            url: self.url.clone(),
+            http_version,
+            cookies: vec![],
+            headers,
+            query_string,
+            post_data,
+            headers_size: exchange.request_head.len() as i64 + 4,
+            body_size: exchange.request_body_size as i64,
+        }
+    }
+
+    fn tunnel_request(&self) -> HarRequest {
+        HarRequest {
+            method: "CONNECT".to_string(),
+            url: format!("https://{}", self.url),
+            http_version: "HTTP/1.1".to_string(),
+            cookies: vec![],
+            headers: vec![],
+            query_string: vec![],
+            post_data: None,
+            headers_size: -1,
+            body_size: 0,
+        }
+    }
+}
+
+impl Drop for HarCapture {
+    fn drop(&mut self) {
+        let entry = self.to_entry(&self.exchange.lock().unwrap());
+        self.recorder.push(entry);
+    }
+}
+
+fn tunnel_response(exchange: &Exchange) -> HarResponse {
+    let established = exchange.connected.is_some() && exchange.error.is_none();
+    HarResponse {
+        status: if established { 200 } else { 0 },
+        status_text: if established {
+            "Connection established".to_string()
+        } else {
+            String::new()
+        },
+        http_version: "HTTP/1.1".to_string(),
+        cookies: vec![],
+        headers: vec![],
+        content: HarContent {
+            size: 0,
+            mime_type: String::new(),
+            text: None,
+            encoding: None,
+            comment: Some("加密隧道，未记录内容".to_string()),
+        },
+        redirect_url: String::new(),
+        headers_size: -1,
+        body_size: -1,
+    }
+}
+
+fn http_response(exchange: &Exchange) -> HarResponse {
+    let Some(head_len) = head_len(&exchange.response) else {
+        // 没有收到完整的响应头
+        return HarResponse {
+            status: 0,
+            status_text: String::new(),
+            http_version: String::new(),
+            cookies: vec![],
+            headers: vec![],
+            content: HarContent {
+                size: 0,
+                mime_type: String::new(),
+                text: None,
+                encoding: None,
+                comment: None,
+            },
+            redirect_url: String::new(),
+            headers_size: -1,
+            body_size: -1,
+        };
+    };
+    let head = String::from_utf8_lossy(&exchange.response[..head_len - 4]);
+    let mut lines = head.lines();
+    let mut status_line = lines.next().unwrap_or_default().splitn(3, ' ');
+    let http_version = status_line.next().unwrap_or_default().to_string();
+    let status = status_line.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+    let status_text = status_line.next().unwrap_or_default().to_string();
+    let headers = parse_headers(lines);
+
+    let body_size = exchange.response_size - head_len;
+    let captured = &exchange.response[head_len..];
+    let body = if is_chunked(&headers) {
+        decode_chunked(captured)
+    } else {
+        captured.to_vec()
+    };
+    let (text, encoding) = body_text(&body);
+    HarResponse {
+        status,
+        status_text,
+        http_version,
+        cookies: vec![],
+        redirect_url: header_value(&headers, "location").unwrap_or_default(),
+        content: HarContent {
+            size: body.len() as i64,
+            mime_type: header_value(&headers, "content-type").unwrap_or_default(),
+            text: (!body.is_empty()).then_some(text),
+            encoding,
+            comment: truncated(captured.len(), body_size),
+        },
+        headers,
+        headers_size: head_len as i64,
+        body_size: body_size as i64,
+    }
+}
+
+// 请求头结束位置（包含空行）
+fn head_len(data: &[u8]) -> Option<usize> {
+    data.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+fn split_head(data: &[u8]) -> (&[u8], &[u8]) {
+    match head_len(data) {
+        Some(len) => (&data[..len - 4], &data[len..]),
+        None => (data, &[]),
+    }
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<HarNameValue> {
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| HarNameValue {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+        .collect()
+}
+
+fn header_value(headers: &[HarNameValue], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value.clone())
+}
+
+fn is_chunked(headers: &[HarNameValue]) -> bool {
+    header_value(headers, "transfer-encoding")
+        .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"))
+}
+
+// 文本内容原样保存，二进制内容使用 base64
+fn body_text(body: &[u8]) -> (String, Option<&'static str>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (
+            base64::engine::general_purpose::STANDARD.encode(body),
+            Some("base64"),
+        ),
+    }
+}
+
+fn truncated(captured: usize, total: usize) -> Option<String> {
+    (captured < total).then(|| format!("内容超过记录上限，只保留前 {} 字节", captured))
+}
+
+// 解码分块传输的响应体，数据不完整时返回已解码的部分
+fn decode_chunked(mut data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    while let Some(line_end) = data.windows(2).position(|w| w == b"\r\n") {
+        let size = std::str::from_utf8(&data[..line_end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok());
+        let Some(size) = size.filter(|&size| size > 0) else {
+            break;
+        };
+        data = &data[line_end + 2..];
+        body.extend_from_slice(&data[..size.min(data.len())]);
+        if data.len() < size + 2 {
+            break;
+        }
+        data = &data[size + 2..];
+    }
+    body
+}
@@ -0,0 +1,257 @@
+use crate::bypass::{domain_matches, normalize_host};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+// 常用的 User-Agent，供设置界面选择后填入 User-Agent 规则
+pub const USER_AGENT_PRESETS: &[(&str, &str)] = &[
+    (
+        "Chrome (Windows)",
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36",
+    ),
+    (
+        "Chrome (macOS)",
+        "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36",
+    ),
+    (
+        "Firefox (Windows)",
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:132.0) Gecko/20100101 Firefox/132.0",
+    ),
+    (
+        "Safari (iPhone)",
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 18_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0 Mobile/15E148 Safari/604.1",
+    ),
+    (
+        "Chrome (Android)",
+        "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Mobile Safari/537.36",
+    ),
+];
+
+// 请求头规则：转发请求前按顺序执行所有匹配目标主机的规则
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct HeaderRuleSettings {
+    pub rules: Vec<HeaderRule>,
+}
+
+// 默认去掉 Accept-Encoding（使响应不压缩）和非标准的 Proxy-Connection
+impl Default for HeaderRuleSettings {
+    fn default() -> Self {
+        let remove = |name: &str| HeaderRule {
+            host: "*".to_string(),
+            action: HeaderAction::Remove {
+                name: name.to_string(),
+            },
+        };
+        Self {
+            rules: vec![remove("Accept-Encoding"), remove("Proxy-Connection")],
+        }
+    }
+}
+
+// 单条规则：host 为 "*"（所有主机）、域名（同时匹配子域名）或 IP
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HeaderRule {
+    pub host: String,
+    #[serde(flatten)]
+    pub action: HeaderAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum HeaderAction {
+    // 追加请求头，已有同名请求头时保留原有的
+    Add {
+        name: String,
+        value: String,
+    },
+    // 设置请求头，替换所有同名请求头
+    Set {
+        name: String,
+        value: String,
+    },
+    // 删除所有同名请求头
+    Remove {
+        name: String,
+    },
+    // 对请求头的值按正则表达式替换，replacement 中可用 $1 引用分组
+    Replace {
+        name: String,
+        pattern: String,
+        replacement: String,
+    },
+    // 预设：替换 User-Agent
+    UserAgent {
+        value: String,
+    },
+    // 预设：Referer 策略
+    Referer {
+        policy: RefererPolicy,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RefererPolicy {
+    NoReferrer, // 不发送 Referer
+    Origin,     // 只发送来源的协议、主机和端口
+    SameOrigin, // 来源与目标主机不同时不发送
+}
+
+impl HeaderRuleSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        for rule in &self.rules {
+            if rule.host.trim().is_empty() {
+                return Err(format!("请求头规则缺少主机: {:?}", rule));
+            }
+            match &rule.action {
+                HeaderAction::Add { name, .. }
+                | HeaderAction::Set { name, .. }
+                | HeaderAction::Remove { name }
+                    if name.trim().is_empty() =>
+                {
+                    return Err(format!("请求头规则缺少请求头名称: {:?}", rule));
+                }
+                HeaderAction::Replace { name, pattern, .. } => {
+                    if name.trim().is_empty() {
+                        return Err(format!("请求头规则缺少请求头名称: {:?}", rule));
+                    }
+                    Regex::new(pattern)
+                        .map_err(|e| format!("无效的正则表达式 {}: {}", pattern, e))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum CompiledAction {
+    Add(String, String),
+    Set(String, String),
+    Remove(String),
+    Replace(String, Regex, String),
+    Referer(RefererPolicy),
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    host: String, // 规范化后的主机，"*" 表示所有主机
+    action: CompiledAction,
+}
+
+// 编译后的请求头规则，随配置快照更新
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    rules: Vec<CompiledRule>,
+}
+
+impl HeaderRules {
+    // 编译规则，无效的正则表达式跳过
+    pub fn compile(settings: &HeaderRuleSettings) -> Self {
+        let rules = settings
+            .rules
+            .iter()
+            .filter_map(|rule| {
+                let action = match &rule.action {
+                    HeaderAction::Add { name, value } => {
+                        CompiledAction::Add(name.trim().to_string(), value.clone())
+                    }
+                    HeaderAction::Set { name, value } => {
+                        CompiledAction::Set(name.trim().to_string(), value.clone())
+                    }
+                    HeaderAction::Remove { name } => {
+                        CompiledAction::Remove(name.trim().to_string())
+                    }
+                    HeaderAction::Replace {
+                        name,
+                        pattern,
+                        replacement,
+                    } => match Regex::new(pattern) {
+                        Ok(regex) => CompiledAction::Replace(
+                            name.trim().to_string(),
+                            regex,
+                            replacement.clone(),
+                        ),
+                        Err(e) => {
+                            warn!("忽略无效的请求头规则 {}: {}", pattern, e);
+                            return None;
+                        }
+                    },
+                    HeaderAction::UserAgent { value } => {
+                        CompiledAction::Set("User-Agent".to_string(), value.clone())
+                    }
+                    HeaderAction::Referer { policy } => CompiledAction::Referer(*policy),
+                };
+                let host = normalize_host(rule.host.trim().trim_start_matches("*."));
+                Some(CompiledRule { host, action })
+            })
+            .collect();
+        Self { rules }
+    }
+
+    // 按顺序执行匹配目标主机的规则，headers 为 (名称, 值)
+    pub fn apply(&self, host: &str, headers: &mut Vec<(String, String)>) {
+        let host = normalize_host(host);
+        for rule in &self.rules {
+            if rule.host != "*" && !domain_matches(&host, &rule.host, true) {
+                continue;
+            }
+            match &rule.action {
+                CompiledAction::Add(name, value) => headers.push((name.clone(), value.clone())),
+                CompiledAction::Set(name, value) => {
+                    // 保留第一个同名请求头的位置，其余的删除
+                    let mut found = false;
+                    headers.retain_mut(|(n, v)| {
+                        if !n.eq_ignore_ascii_case(name) {
+                            return true;
+                        }
+                        if found {
+                            return false;
+                        }
+                        found = true;
+                        *v = value.clone();
+                        true
+                    });
+                    if !found {
+                        headers.push((name.clone(), value.clone()));
+                    }
+                }
+                CompiledAction::Remove(name) => {
+                    headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+                }
+                CompiledAction::Replace(name, regex, replacement) => {
+                    for (n, value) in headers.iter_mut() {
+                        if n.eq_ignore_ascii_case(name) {
+                            *value = regex.replace_all(value, replacement.as_str()).into_owned();
+                        }
+                    }
+                }
+                CompiledAction::Referer(policy) => apply_referer_policy(*policy, &host, headers),
+            }
+        }
+    }
+}
+
+fn apply_referer_policy(policy: RefererPolicy, host: &str, headers: &mut Vec<(String, String)>) {
+    headers.retain_mut(|(name, value)| {
+        if !name.eq_ignore_ascii_case("referer") {
+            return true;
+        }
+        let referer = url::Url::parse(value).ok();
+        match policy {
+            RefererPolicy::NoReferrer => false,
+            RefererPolicy::Origin => match referer {
+                Some(referer) => {
+                    *value = format!("{}/", referer.origin().ascii_serialization());
+                    true
+                }
+                None => false,
+            },
+            RefererPolicy::SameOrigin => referer
+                .and_then(|referer| referer.host_str().map(normalize_host))
+                .is_some_and(|referer_host| referer_host == host),
+        }
+    });
+}
@@ -0,0 +1,285 @@
+use crate::proxy_server::is_timeout;
+use std::io::{self, Read};
+
+const MAX_HEAD_SIZE: usize = 64 * 1024; // 请求头、响应头或分块大小行的最大长度
+const READ_SIZE: usize = 16 * 1024;
+
+// 消息体的长度（HTTP/1.1 分帧方式）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    UntilClose, // 只用于响应：没有长度信息时读到连接关闭为止
+}
+
+// 按 HTTP 消息读取连接上的数据：先读出完整的消息头，再按长度读取消息体，
+// 多读到的数据（如客户端提前发送的下一个请求）留给下一个消息
+pub struct MessageReader<S> {
+    stream: S,
+    buf: Vec<u8>,
+    pos: usize, // buf[pos..] 为已读取但尚未处理的数据
+}
+
+impl<S: Read> MessageReader<S> {
+    // buffered 为在此之前已从连接读出的数据
+    pub fn new(stream: S, buffered: &[u8]) -> Self {
+        Self {
+            stream,
+            buf: buffered.to_vec(),
+            pos: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    // 取出已读取但尚未处理的数据，转为隧道前需要先转发给另一侧
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        let rest = self.buf.split_off(self.pos);
+        self.buf.clear();
+        self.pos = 0;
+        rest
+    }
+
+    // 读取一个完整的消息头（含结尾的空行）；消息开始前连接已关闭时返回 None
+    // 读取超时时调用 wait，由它决定继续等待还是返回错误
+    pub fn read_head(
+        &mut self,
+        wait: &mut dyn FnMut() -> io::Result<()>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut scanned = 0;
+        loop {
+            // 消息之间可能有多余的空行，忽略
+            while self.buf[self.pos..].starts_with(b"\r\n") {
+                self.pos += 2;
+            }
+            let pending = &self.buf[self.pos..];
+            if let Some(end) = pending[scanned.min(pending.len())..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+            {
+                let end = scanned.min(pending.len()) + end + 4;
+                let head = pending[..end].to_vec();
+                self.pos += end;
+                return Ok(Some(head));
+            }
+            if pending.len() > MAX_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "消息头过长"));
+            }
+            scanned = pending.len().saturating_sub(3);
+            if self.fill(wait)? == 0 {
+                return match self.pos == self.buf.len() {
+                    true => Ok(None),
+                    false => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "消息头不完整")),
+                };
+            }
+        }
+    }
+
+    // 按长度读取消息体，依次交给 sink；数据原样转发，分块传输的分块大小行和尾部字段也一并转发
+    pub fn read_body(
+        &mut self,
+        length: BodyLength,
+        wait: &mut dyn FnMut() -> io::Result<()>,
+        sink: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        match length {
+            BodyLength::Empty => Ok(()),
+            BodyLength::Fixed(size) => self.copy_exact(size, wait, sink),
+            BodyLength::UntilClose => loop {
+                if self.pos == self.buf.len() && self.fill(wait)? == 0 {
+                    return Ok(());
+                }
+                sink(&self.buf[self.pos..])?;
+                self.pos = self.buf.len();
+            },
+            BodyLength::Chunked => loop {
+                let line = self.copy_line(wait, sink)?;
+                let size = parse_chunk_size(&line)?;
+                if size == 0 {
+                    // 尾部字段，以空行结束
+                    while self.copy_line(wait, sink)? != b"\r\n" {}
+                    return Ok(());
+                }
+                // 分块数据及其后的 CRLF
+                self.copy_exact(size + 2, wait, sink)?;
+            },
+        }
+    }
+
+    fn copy_exact(
+        &mut self,
+        mut remaining: u64,
+        wait: &mut dyn FnMut() -> io::Result<()>,
+        sink: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        while remaining > 0 {
+            if self.pos == self.buf.len() && self.fill(wait)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "消息体不完整"));
+            }
+            let available =
+                (self.buf.len() - self.pos).min(remaining.min(usize::MAX as u64) as usize);
+            sink(&self.buf[self.pos..self.pos + available])?;
+            self.pos += available;
+            remaining -= available as u64;
+        }
+        Ok(())
+    }
+
+    // 读取一行（含结尾的 LF）并转发，返回该行
+    fn copy_line(
+        &mut self,
+        wait: &mut dyn FnMut() -> io::Result<()>,
+        sink: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(end) = self.buf[self.pos..].iter().position(|&b| b == b'\n') {
+                let line = self.buf[self.pos..self.pos + end + 1].to_vec();
+                self.pos += end + 1;
+                sink(&line)?;
+                return Ok(line);
+            }
+            if self.buf.len() - self.pos > MAX_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "分块大小行过长"));
+            }
+            if self.fill(wait)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "分块数据不完整",
+                ));
+            }
+        }
+    }
+
+    // 从连接读取更多数据，返回读取的字节数，0 表示连接已关闭
+    fn fill(&mut self, wait: &mut dyn FnMut() -> io::Result<()>) -> io::Result<usize> {
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        let len = self.buf.len();
+        self.buf.resize(len + READ_SIZE, 0);
+        let result = loop {
+            match self.stream.read(&mut self.buf[len..]) {
+                Ok(n) => break Ok(n),
+                Err(e) if is_timeout(&e) => {
+                    if let Err(e) = wait() {
+                        break Err(e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.buf.truncate(len + *result.as_ref().unwrap_or(&0));
+        result
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> io::Result<u64> {
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(';').next().unwrap_or_default().trim();
+    u64::from_str_radix(size, 16)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "无效的分块大小"))
+}
+
+// 消息头中的字段，不区分大小写
+fn headers<'a>(head: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    head.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .filter(move |(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// 字段值（逗号分隔）中是否包含指定的标记，如 Connection: close
+pub fn has_token(head: &str, name: &str, token: &str) -> bool {
+    headers(head, name)
+        .flat_map(|value| value.split(','))
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+// 响应的状态码
+pub fn status_code(head: &str) -> Option<u16> {
+    head.split_whitespace().nth(1)?.parse().ok()
+}
+
+// 请求体的长度：分块传输或 Content-Length，都没有时没有请求体
+pub fn request_body_length(head: &str) -> io::Result<BodyLength> {
+    if headers(head, "transfer-encoding").next().is_some() {
+        return match has_token(head, "transfer-encoding", "chunked") {
+            true => Ok(BodyLength::Chunked),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "不支持的请求传输编码",
+            )),
+        };
+    }
+    match headers(head, "content-length").next() {
+        Some(value) => value
+            .parse()
+            .map(|size| match size {
+                0 => BodyLength::Empty,
+                size => BodyLength::Fixed(size),
+            })
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "无效的 Content-Length")),
+        None => Ok(BodyLength::Empty),
+    }
+}
+
+// 响应体的长度：HEAD 请求和 1xx、204、304 响应没有响应体，没有长度信息时读到连接关闭
+pub fn response_body_length(head: &str, method: &str) -> BodyLength {
+    let status = status_code(head).unwrap_or(0);
+    if method.eq_ignore_ascii_case("HEAD")
+        || (100..200).contains(&status)
+        || status == 204
+        || status == 304
+    {
+        return BodyLength::Empty;
+    }
+    if headers(head, "transfer-encoding").next().is_some() {
+        return match has_token(head, "transfer-encoding", "chunked") {
+            true => BodyLength::Chunked,
+            false => BodyLength::UntilClose,
+        };
+    }
+    match headers(head, "content-length")
+        .next()
+        .and_then(|value| value.parse().ok())
+    {
+        Some(0) => BodyLength::Empty,
+        Some(size) => BodyLength::Fixed(size),
+        None => BodyLength::UntilClose,
+    }
+}
+
+// 发送方是否要求在这个消息之后关闭连接：Connection: close，或 HTTP/1.0 未声明 keep-alive
+// 客户端发给代理的请求可能用 Proxy-Connection 代替 Connection
+pub fn wants_close(head: &str) -> bool {
+    let first_line = head.lines().next().unwrap_or_default();
+    let http10 = first_line.starts_with("HTTP/1.0") || first_line.ends_with("HTTP/1.0");
+    let connection =
+        |token| has_token(head, "connection", token) || has_token(head, "proxy-connection", token);
+    connection("close") || (http10 && !connection("keep-alive"))
+}
+
+// 删除指定的字段
+pub fn remove_header(head: &str, name: &str) -> String {
+    head.split_inclusive("\r\n")
+        .enumerate()
+        .filter(|(i, line)| {
+            *i == 0
+                || !line
+                    .split_once(':')
+                    .is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        })
+        .map(|(_, line)| line)
+        .collect()
+}
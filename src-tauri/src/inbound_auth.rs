@@ -0,0 +1,94 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+
+// 认证失败时返回的 407 响应
+pub const PROXY_AUTH_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+Proxy-Authenticate: Basic realm=\"liuyao-proxy\"\r\n\
+Content-Length: 0\r\n\
+Connection: close\r\n\r\n";
+
+// 本地代理的入站认证（客户端连接本地代理时使用），与上游代理的用户名密码分开保存
+#[derive(Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct InboundAuth {
+    pub enabled: bool,
+    pub username: String,
+    pub password: String,
+    // 本机客户端（包括应用自身的 WebView）是否也需要认证，默认不需要
+    pub apply_to_loopback: bool,
+}
+
+// 输出调试信息时隐藏密码
+impl fmt::Debug for InboundAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InboundAuth")
+            .field("enabled", &self.enabled)
+            .field("username", &self.username)
+            .field("password", &"***")
+            .field("apply_to_loopback", &self.apply_to_loopback)
+            .finish()
+    }
+}
+
+impl InboundAuth {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && (self.username.is_empty() || self.password.is_empty()) {
+            return Err("启用入站认证时用户名和密码不能为空".to_string());
+        }
+        // SOCKS5 用户名密码认证中长度字段只有一个字节
+        if self.username.len() > 255 || self.password.len() > 255 {
+            return Err("用户名和密码不能超过 255 字节".to_string());
+        }
+        Ok(())
+    }
+
+    // 该客户端是否需要认证
+    pub fn required_for(&self, client_ip: &IpAddr) -> bool {
+        self.enabled && (self.apply_to_loopback || !client_ip.to_canonical().is_loopback())
+    }
+
+    pub fn check(&self, username: &[u8], password: &[u8]) -> bool {
+        constant_time_eq(username, self.username.as_bytes())
+            & constant_time_eq(password, self.password.as_bytes())
+    }
+
+    // 检查 HTTP 请求头中的 Proxy-Authorization: Basic 凭据
+    pub fn check_http_request(&self, request: &str) -> bool {
+        let header = request
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("proxy-authorization")
+                    .then(|| value.trim())
+            });
+        let Some(header) = header else {
+            return false;
+        };
+        let Some((scheme, encoded)) = header.split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(encoded.trim()) else {
+            return false;
+        };
+        match decoded.iter().position(|&b| b == b':') {
+            Some(colon) => self.check(&decoded[..colon], &decoded[colon + 1..]),
+            None => false,
+        }
+    }
+}
+
+// 比较时不因首个不同字节提前返回，避免通过响应时间猜测密码
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
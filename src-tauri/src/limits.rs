@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// 并发连接限制，各项为 0 表示不限制
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ConnectionLimits {
+    // 同时处理的连接总数上限
+    pub max_connections: usize,
+    // 单个客户端地址的连接数上限
+    pub max_per_client: usize,
+    // 单个目标主机的上游连接数上限
+    pub max_per_host: usize,
+    // 达到上限时排队等待的最长时间（毫秒），超时后拒绝连接
+    pub queue_timeout_ms: u64,
+    // 同时排队等待的连接数上限，队列已满时立即拒绝
+    pub max_queued: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 512,
+            max_per_client: 256,
+            max_per_host: 128,
+            queue_timeout_ms: 5000,
+            max_queued: 256,
+        }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_millis(self.queue_timeout_ms)
+    }
+}
+
+// 当前并发情况
+#[derive(Debug, Clone, Serialize)]
+pub struct ConcurrencyStats {
+    pub active: usize,             // 正在处理的连接数
+    pub peak: usize,               // 启动以来的最高并发数
+    pub queued: usize,             // 正在排队等待的连接数
+    pub rejected: u64,             // 排队超时或队列已满被拒绝的连接数
+    pub clients: Vec<ActiveCount>, // 各客户端地址的连接数
+    pub hosts: Vec<ActiveCount>,   // 各目标主机的上游连接数
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveCount {
+    pub key: String,
+    pub active: usize,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    active: usize,
+    peak: usize,
+    queued: usize,
+    rejected: u64,
+    per_client: HashMap<IpAddr, usize>,
+    per_host: HashMap<String, usize>,
+}
+
+// 并发计数和排队：名额不足时在条件变量上等待，连接结束归还名额时唤醒
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    state: Mutex<LimiterState>,
+    released: Condvar,
+}
+
+// 客户端连接占用的名额，释放时归还
+pub struct ClientSlot {
+    limiter: Arc<ConnectionLimiter>,
+    client: IpAddr,
+}
+
+// 上游连接占用的目标主机名额，释放时归还
+pub struct HostSlot {
+    limiter: Arc<ConnectionLimiter>,
+    host: String,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.active -= 1;
+        decrement(&mut state.per_client, &self.client);
+        self.limiter.released.notify_all();
+    }
+}
+
+impl Drop for HostSlot {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        decrement(&mut state.per_host, &self.host);
+        self.limiter.released.notify_all();
+    }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+fn under_limit(current: usize, limit: usize) -> bool {
+    limit == 0 || current < limit
+}
+
+impl ConnectionLimiter {
+    // 占用一个客户端连接名额（同时受总数和单客户端上限限制），超时返回 None
+    pub fn acquire_client(
+        self: &Arc<Self>,
+        client: IpAddr,
+        limits: &ConnectionLimits,
+    ) -> Option<ClientSlot> {
+        let state = self.wait_until(limits, |state| {
+            under_limit(state.active, limits.max_connections)
+                && under_limit(
+                    state.per_client.get(&client).copied().unwrap_or(0),
+                    limits.max_per_client,
+                )
+        });
+        let mut state = state?;
+        state.active += 1;
+        state.peak = state.peak.max(state.active);
+        *state.per_client.entry(client).or_insert(0) += 1;
+        Some(ClientSlot {
+            limiter: Arc::clone(self),
+            client,
+        })
+    }
+
+    // 占用一个目标主机的上游连接名额，超时返回 None
+    pub fn acquire_host(
+        self: &Arc<Self>,
+        host: &str,
+        limits: &ConnectionLimits,
+    ) -> Option<HostSlot> {
+        let host = host.to_ascii_lowercase();
+        let state = self.wait_until(limits, |state| {
+            under_limit(
+                state.per_host.get(&host).copied().unwrap_or(0),
+                limits.max_per_host,
+            )
+        });
+        let mut state = state?;
+        *state.per_host.entry(host.clone()).or_insert(0) += 1;
+        Some(HostSlot {
+            limiter: Arc::clone(self),
+            host,
+        })
+    }
+
+    // 等待直到满足条件，返回持有的锁；队列已满或超时则记入拒绝数并返回 None
+    fn wait_until(
+        &self,
+        limits: &ConnectionLimits,
+        ready: impl Fn(&LimiterState) -> bool,
+    ) -> Option<std::sync::MutexGuard<'_, LimiterState>> {
+        let mut state = self.state.lock().unwrap();
+        if ready(&state) {
+            return Some(state);
+        }
+        if !under_limit(state.queued, limits.max_queued) {
+            state.rejected += 1;
+            return None;
+        }
+
+        let deadline = Instant::now() + limits.queue_timeout();
+        state.queued += 1;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                state.queued -= 1;
+                state.rejected += 1;
+                return None;
+            }
+            state = self.released.wait_timeout(state, deadline - now).unwrap().0;
+            if ready(&state) {
+                state.queued -= 1;
+                return Some(state);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> ConcurrencyStats {
+        let state = self.state.lock().unwrap();
+        let sorted = |mut counts: Vec<ActiveCount>| {
+            counts.sort_by(|a, b| b.active.cmp(&a.active).then(a.key.cmp(&b.key)));
+            counts
+        };
+        ConcurrencyStats {
+            active: state.active,
+            peak: state.peak,
+            queued: state.queued,
+            rejected: state.rejected,
+            clients: sorted(
+                state
+                    .per_client
+                    .iter()
+                    .map(|(ip, &active)| ActiveCount {
+                        key: ip.to_string(),
+                        active,
+                    })
+                    .collect(),
+            ),
+            hosts: sorted(
+                state
+                    .per_host
+                    .iter()
+                    .map(|(host, &active)| ActiveCount {
+                        key: host.clone(),
+                        active,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::bypass::IpCidr;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_PORT_RANGE_END: u16 = 8180;
+const LISTEN_BACKLOG: i32 = 128;
+
+// 本地代理的监听设置
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ListenSettings {
+    // 监听地址，如 "127.0.0.1"、"0.0.0.0"、"192.168.1.10"
+    pub bind_addrs: Vec<String>,
+    // 端口；设置 port_range_end 时为起始端口
+    pub port: u16,
+    // 端口被占用时依次尝试到此端口，为空表示固定端口
+    pub port_range_end: Option<u16>,
+    // 同时监听 IPv4 地址对应的 IPv6 地址（127.0.0.1 -> ::1，0.0.0.0 -> ::）
+    pub ipv6: bool,
+    // 允许访问的客户端网段，回环地址始终允许
+    pub allowed_clients: Vec<String>,
+}
+
+impl Default for ListenSettings {
+    fn default() -> Self {
+        Self {
+            bind_addrs: vec!["127.0.0.1".to_string()],
+            port: DEFAULT_PORT,
+            port_range_end: Some(DEFAULT_PORT_RANGE_END),
+            ipv6: false,
+            allowed_clients: vec![],
+        }
+    }
+}
+
+impl ListenSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        self.bind_ips()?;
+        if self.port == 0 {
+            return Err("端口不能为 0".to_string());
+        }
+        if let Some(end) = self.port_range_end {
+            if end < self.port {
+                return Err(format!("端口范围无效: {}-{}", self.port, end));
+            }
+        }
+        if let Some(invalid) = self
+            .allowed_clients
+            .iter()
+            .find(|c| IpCidr::parse(c).is_none())
+        {
+            return Err(format!("无效的客户端网段: {}", invalid));
+        }
+        Ok(())
+    }
+
+    // 实际要监听的 IP 列表（已去重）
+    pub fn bind_ips(&self) -> Result<Vec<IpAddr>, String> {
+        let mut ips = Vec::new();
+        for addr in &self.bind_addrs {
+            let addr = addr.trim().trim_start_matches('[').trim_end_matches(']');
+            let ip: IpAddr = addr
+                .parse()
+                .map_err(|_| format!("无效的监听地址: {}", addr))?;
+            ips.push(ip);
+            if self.ipv6 {
+                match ip {
+                    IpAddr::V4(v4) if v4.is_loopback() => ips.push(Ipv6Addr::LOCALHOST.into()),
+                    IpAddr::V4(v4) if v4.is_unspecified() => ips.push(Ipv6Addr::UNSPECIFIED.into()),
+                    _ => {}
+                }
+            }
+        }
+        if ips.is_empty() {
+            return Err("至少需要一个监听地址".to_string());
+        }
+        let mut unique = Vec::new();
+        for ip in ips {
+            if !unique.contains(&ip) {
+                unique.push(ip);
+            }
+        }
+        Ok(unique)
+    }
+
+    // 是否只监听回环地址
+    pub fn is_loopback_only(&self) -> bool {
+        self.bind_ips()
+            .map(|ips| ips.iter().all(|ip| ip.is_loopback()))
+            .unwrap_or(true)
+    }
+}
+
+// 绑定所有监听地址；端口范围内依次尝试，所有地址都能绑定同一端口时成功
+pub fn bind_listeners(settings: &ListenSettings) -> Result<(u16, Vec<TcpListener>), String> {
+    settings.validate()?;
+    let ips = settings.bind_ips()?;
+    let end = settings.port_range_end.unwrap_or(settings.port);
+
+    let mut last_error = String::new();
+    for port in settings.port..=end {
+        let mut listeners = Vec::with_capacity(ips.len());
+        for ip in &ips {
+            match bind_one(SocketAddr::new(*ip, port)) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => {
+                    last_error = format!("{}:{} - {}", ip, port, e);
+                    break;
+                }
+            }
+        }
+        if listeners.len() == ips.len() {
+            return Ok((port, listeners));
+        }
+    }
+    Err(format!("无法绑定监听地址: {}", last_error))
+}
+
+// IPv6 套接字设置为仅 IPv6，避免与同端口的 IPv4 监听冲突
+fn bind_one(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // 与 std::net::TcpListener::bind 保持一致：Unix 上允许快速重用刚释放的端口
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+// 从本机访问代理使用的地址：优先回环地址，监听 0.0.0.0 / :: 时使用对应的回环地址
+pub fn local_proxy_addr(listen_addrs: &[SocketAddr]) -> Option<SocketAddr> {
+    let local = |addr: &SocketAddr| match addr.ip() {
+        IpAddr::V4(v4) if v4.is_unspecified() => {
+            Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()))
+        }
+        IpAddr::V6(v6) if v6.is_unspecified() => {
+            Some(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()))
+        }
+        ip if ip.is_loopback() => Some(*addr),
+        _ => None,
+    };
+    listen_addrs
+        .iter()
+        .filter(|a| a.is_ipv4())
+        .chain(listen_addrs.iter().filter(|a| a.is_ipv6()))
+        .find_map(local)
+        .or_else(|| listen_addrs.first().copied())
+}
+
+// 客户端访问控制：回环地址始终允许，其他地址需在允许的网段内
+#[derive(Debug, Clone, Default)]
+pub struct ClientAcl {
+    allowed: Vec<IpCidr>,
+}
+
+impl ClientAcl {
+    pub fn new(settings: &ListenSettings) -> Self {
+        Self {
+            allowed: settings
+                .allowed_clients
+                .iter()
+                .filter_map(|c| IpCidr::parse(c))
+                .collect(),
+        }
+    }
+
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        ip.to_canonical().is_loopback() || self.allowed.iter().any(|c| c.contains(ip))
+    }
+}
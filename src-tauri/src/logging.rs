@@ -0,0 +1,211 @@
+use chrono::{Local, SecondsFormat};
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+const BUFFER_CAPACITY: usize = 2000; // 内存中保留的最近日志条数
+const ROOT_MODULE: &str = "main"; // 本程序根模块（main.rs）的模块名
+
+// 一条日志，seq 递增，前端据此增量获取
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub time: String,
+    pub level: String,
+    pub module: String,
+    pub message: String,
+}
+
+// 日志级别（off/error/warn/info/debug/trace）：default 对未单独设置的模块生效
+// modules 的键为本程序模块名（如 proxy_server）或依赖库名（如 reqwest），同时作用于其子模块
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LogLevels {
+    pub default: String,
+    pub modules: BTreeMap<String, String>,
+}
+
+struct Filter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>, // 按模块名从长到短排列，先匹配更具体的模块
+}
+
+impl Filter {
+    fn new(levels: &LogLevels) -> Result<Self, String> {
+        let mut modules = levels
+            .modules
+            .iter()
+            .map(|(module, level)| Ok((module.clone(), parse_level(level)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(Self {
+            default: parse_level(&levels.default)?,
+            modules,
+        })
+    }
+
+    fn level_for(&self, module: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(key, _)| {
+                module
+                    .strip_prefix(key.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |a, b| a.max(b))
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level.trim()).map_err(|_| format!("无效的日志级别: {}", level))
+}
+
+// 日志目标（模块路径）去掉本程序的 crate 名，根模块显示为 main
+fn module_name(target: &str) -> &str {
+    match target.strip_prefix(env!("CARGO_CRATE_NAME")) {
+        Some("") => ROOT_MODULE,
+        Some(rest) if rest.starts_with("::") => &rest[2..],
+        _ => target,
+    }
+}
+
+// 按模块过滤日志，输出到标准错误，并保存最近的日志供应用内查看
+struct AppLogger {
+    levels: RwLock<(LogLevels, Filter)>,
+    next_seq: AtomicU64,
+    buffer: Mutex<VecDeque<LogEntry>>,
+}
+
+static LOGGER: Lazy<AppLogger> = Lazy::new(|| {
+    let levels = initial_levels();
+    let filter = Filter::new(&levels).expect("默认日志级别有效");
+    AppLogger {
+        levels: RwLock::new((levels, filter)),
+        next_seq: AtomicU64::new(0),
+        buffer: Mutex::new(VecDeque::with_capacity(BUFFER_CAPACITY)),
+    }
+});
+
+// 初始级别读取 RUST_LOG（如 "info,proxy_server=debug"），未设置时为 info，无效的项被忽略
+fn initial_levels() -> LogLevels {
+    let mut levels = LogLevels {
+        default: "info".to_string(),
+        modules: BTreeMap::new(),
+    };
+    let Ok(spec) = std::env::var("RUST_LOG") else {
+        return levels;
+    };
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((module, level)) if parse_level(level).is_ok() => {
+                levels.modules.insert(
+                    module_name(module.trim()).to_string(),
+                    level.trim().to_lowercase(),
+                );
+            }
+            None if parse_level(directive).is_ok() => levels.default = directive.to_lowercase(),
+            _ => {}
+        }
+    }
+    levels
+}
+
+impl Log for AppLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let levels = self.levels.read().unwrap();
+        metadata.level() <= levels.1.level_for(module_name(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut entry = LogEntry {
+            seq: 0,
+            time: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            level: record.level().to_string(),
+            module: module_name(record.target()).to_string(),
+            message: record.args().to_string(),
+        };
+        let _ = writeln!(
+            std::io::stderr(),
+            "{} {:<5} [{}] {}",
+            entry.time,
+            entry.level,
+            entry.module,
+            entry.message
+        );
+
+        // 在锁内分配序号，保证缓冲区中的序号有序
+        let mut buffer = self.buffer.lock().unwrap();
+        entry.seq = self.next_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        if buffer.len() >= BUFFER_CAPACITY {
+            buffer.pop_front();
+        }
+        buffer.push_back(entry);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+// 安装日志记录器，程序启动时调用一次
+pub fn init() {
+    if log::set_logger(&*LOGGER).is_ok() {
+        log::set_max_level(LOGGER.levels.read().unwrap().1.max_level());
+    }
+}
+
+pub fn levels() -> LogLevels {
+    LOGGER.levels.read().unwrap().0.clone()
+}
+
+// 设置某个模块的日志级别，module 为空时设置默认级别；level 为空时取消该模块的单独设置
+pub fn set_level(module: Option<&str>, level: Option<&str>) -> Result<LogLevels, String> {
+    let mut levels = LOGGER.levels.write().unwrap();
+    let mut updated = levels.0.clone();
+    let module = module
+        .map(|m| module_name(m.trim()))
+        .filter(|m| !m.is_empty());
+    match (module, level) {
+        (None, Some(level)) => updated.default = level.trim().to_lowercase(),
+        (None, None) => return Err("未指定默认日志级别".to_string()),
+        (Some(module), Some(level)) => {
+            updated
+                .modules
+                .insert(module.to_string(), level.trim().to_lowercase());
+        }
+        (Some(module), None) => {
+            updated.modules.remove(module);
+        }
+    }
+    let filter = Filter::new(&updated)?;
+    log::set_max_level(filter.max_level());
+    *levels = (updated.clone(), filter);
+    Ok(updated)
+}
+
+// 最近的日志，since 为上次获取到的最后一条的 seq
+pub fn recent(since: Option<u64>) -> Vec<LogEntry> {
+    let since = since.unwrap_or(0);
+    LOGGER
+        .buffer
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.seq > since)
+        .cloned()
+        .collect()
+}
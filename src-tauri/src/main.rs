@@ -9,19 +9,71 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use tauri::Manager;
+use tauri::{Emitter, Manager};
+mod access_log;
+mod bypass;
+mod connections;
+mod har;
+mod header_rules;
+mod http_message;
+mod inbound_auth;
+mod limits;
+mod listen;
+mod logging;
+mod network_env;
 mod proxy_server;
+use access_log::AccessLogSettings;
+use bypass::IpCidr;
+use connections::ConnectionEntry;
+use har::HarSettings;
+use header_rules::{HeaderRuleSettings, USER_AGENT_PRESETS};
+use inbound_auth::InboundAuth;
+use limits::{ConcurrencyStats, ConnectionLimits};
+use listen::ListenSettings;
 use once_cell::sync::Lazy;
-use proxy_server::{ProxyServer, ProxySettings, ProxyType, load_settings_from_file};
+use proxy_server::{
+    load_settings_from_file, proxy_authority, ProxyServer, ProxySettings, ProxyType,
+    RouteExplanation, StopReport,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
+use std::time::Duration;
 mod read_system_proxy;
-use env_logger;
+mod route_conditions;
+mod rule_import;
+mod rule_set;
+mod rule_stats;
+mod rule_subscription;
+mod throttle;
+mod timeouts;
+mod tls_intercept;
+mod traffic;
+mod url_rewrite;
+use log::{error, info, warn};
+use logging::{LogEntry, LogLevels};
+use network_env::NetworkEnv;
 use read_system_proxy::get_system_proxy_info;
+use route_conditions::{ActiveConditions, ConditionalRule, RoutingProfile};
+use rule_import::{RuleFormat, RuleImportReport};
+use rule_stats::RuleStatsEntry;
+use rule_subscription::RuleSubscription;
 use serde::Serialize;
+use throttle::BandwidthSettings;
+use timeouts::TimeoutSettings;
+use tls_intercept::TlsInterceptSettings;
+use traffic::{TrafficHistory, TrafficSnapshot, TrafficTotals};
+use url_rewrite::UrlRewriteSettings;
 
 // 全局代理服务器实例
 static PROXY_SERVER: Lazy<Mutex<Option<ProxyServer>>> = Lazy::new(|| Mutex::new(None));
 static LOCAL_PROXY_PORT: Lazy<Mutex<u16>> = Lazy::new(|| Mutex::new(0));
+// 主窗口 WebView 使用的代理地址
+static MAIN_WINDOW_PROXY_URL: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+// 正在重新创建主窗口，期间没有窗口也不退出应用
+static RECREATING_MAIN_WINDOW: AtomicBool = AtomicBool::new(false);
+
+// 停止或重启代理时等待进行中连接结束的默认时间
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 5;
 
 #[tauri::command]
 fn get_local_proxy_port() -> u16 {
@@ -34,7 +86,7 @@ fn update_proxy_settings(settings: ProxySettings) -> Result<(), String> {
     if let Ok(proxy_server) = PROXY_SERVER.lock() {
         if let Some(server) = proxy_server.as_ref() {
             server.update_proxy_settings(settings);
-            println!("✅ 代理设置已更新并保存");
+            info!("代理设置已更新并保存");
             Ok(())
         } else {
             Err("代理服务器未启动".to_string())
@@ -156,7 +208,7 @@ struct TestResult {
 // 测试代理连接
 #[tauri::command]
 async fn test_proxy_connectivity(proxy_url: String) -> Result<TestResult, String> {
-    println!("[main] 开始测试代理连接: {}", proxy_url);
+    info!("开始测试代理连接: {}", proxy_url);
 
     use reqwest::Client;
     use std::time::Duration;
@@ -164,7 +216,7 @@ async fn test_proxy_connectivity(proxy_url: String) -> Result<TestResult, String
 
     // 处理直连模式
     if proxy_url == "direct://" {
-        println!("[main] 直连模式测试");
+        info!("直连模式测试");
 
         // 创建不使用代理的客户端
         let client = match Client::builder().timeout(Duration::from_secs(10)).build() {
@@ -252,7 +304,7 @@ async fn test_proxy_connectivity(proxy_url: String) -> Result<TestResult, String
         }
     };
 
-    println!("[main] 解析代理地址: {}://{}:{}", protocol, host, port);
+    info!("解析代理地址: {}://{}:{}", protocol, host, port);
 
     // 测试TCP连接
     let socket_addr = format!("{}:{}", host, port);
@@ -263,7 +315,7 @@ async fn test_proxy_connectivity(proxy_url: String) -> Result<TestResult, String
     .await
     {
         Ok(Ok(_)) => {
-            println!("[main] ✅ TCP连接成功");
+            info!("TCP连接成功");
 
             // 创建HTTP客户端
             let client = match Client::builder()
@@ -335,10 +387,17 @@ async fn test_proxy_connectivity(proxy_url: String) -> Result<TestResult, String
 // 新增：应用系统代理设置命令
 #[tauri::command]
 fn apply_system_proxy() -> Result<(), String> {
-    println!("[main] 开始应用系统代理设置");
+    info!("开始应用系统代理设置");
 
     let system_proxy = get_system_proxy_info();
-    println!("[main] 获取到系统代理信息: {:?}", system_proxy);
+    // 代理地址可能带有认证信息，日志只记录 "host:port"
+    info!(
+        "获取到系统代理信息: 启用={}, HTTP={}, HTTPS={}, SOCKS={}",
+        system_proxy.proxy_enabled,
+        proxy_authority(&system_proxy.http_proxy),
+        proxy_authority(&system_proxy.https_proxy),
+        proxy_authority(&system_proxy.socks_proxy)
+    );
 
     if let Ok(proxy_server) = PROXY_SERVER.lock() {
         if let Some(server) = proxy_server.as_ref() {
@@ -349,28 +408,37 @@ fn apply_system_proxy() -> Result<(), String> {
                 // 系统代理已启用，应用系统代理设置
                 if !system_proxy.http_proxy.is_empty() {
                     settings.http_proxy = Some(system_proxy.http_proxy.clone());
-                    println!("[main] 应用系统HTTP代理: {}", system_proxy.http_proxy);
+                    info!(
+                        "应用系统HTTP代理: {}",
+                        proxy_authority(&system_proxy.http_proxy)
+                    );
                 }
 
                 if !system_proxy.https_proxy.is_empty() {
                     settings.https_proxy = Some(system_proxy.https_proxy.clone());
-                    println!("[main] 应用系统HTTPS代理: {}", system_proxy.https_proxy);
+                    info!(
+                        "应用系统HTTPS代理: {}",
+                        proxy_authority(&system_proxy.https_proxy)
+                    );
                 }
 
                 if !system_proxy.socks_proxy.is_empty() {
                     settings.socks5_proxy = Some(system_proxy.socks_proxy.clone());
-                    println!("[main] 应用系统SOCKS代理: {}", system_proxy.socks_proxy);
+                    info!(
+                        "应用系统SOCKS代理: {}",
+                        proxy_authority(&system_proxy.socks_proxy)
+                    );
                 }
 
                 settings.enabled = true;
-                println!("[main] ✅ 系统代理设置已应用: {:?}", settings);
+                info!("系统代理设置已应用: {}", settings.summary());
             } else {
                 // 系统代理未启用，但仍然设置为系统代理模式，只是暂时不启用
                 settings.http_proxy = None;
                 settings.https_proxy = None;
                 settings.socks5_proxy = None;
                 settings.enabled = false;
-                println!("[main] 系统代理未启用，设置为系统代理模式但暂不启用");
+                info!("系统代理未启用，设置为系统代理模式但暂不启用");
             }
 
             server.update_proxy_settings(settings.clone());
@@ -434,14 +502,14 @@ fn get_direct_domains() -> Result<Vec<String>, String> {
 // 新增：设置直连域名列表
 #[tauri::command]
 fn set_direct_domains(domains: Vec<String>) -> Result<(), String> {
-    println!("[main] 设置直连域名列表: {:?}", domains);
+    info!("设置直连域名列表: {} 个", domains.len());
 
     if let Ok(proxy_server) = PROXY_SERVER.lock() {
         if let Some(server) = proxy_server.as_ref() {
             let mut settings = server.get_proxy_settings();
             settings.direct_domains = domains;
             server.update_proxy_settings(settings);
-            println!("[main] ✅ 直连域名列表已更新");
+            info!("直连域名列表已更新");
             Ok(())
         } else {
             Err("代理服务器未启动".to_string())
@@ -459,7 +527,7 @@ fn add_direct_domain(domain: String) -> Result<(), String> {
     }
 
     let clean_domain = domain.trim().to_lowercase();
-    println!("[main] 添加直连域名: {}", clean_domain);
+    info!("添加直连域名: {}", clean_domain);
 
     if let Ok(proxy_server) = PROXY_SERVER.lock() {
         if let Some(server) = proxy_server.as_ref() {
@@ -473,7 +541,7 @@ fn add_direct_domain(domain: String) -> Result<(), String> {
             {
                 settings.direct_domains.push(clean_domain.clone());
                 server.update_proxy_settings(settings);
-                println!("[main] ✅ 已添加直连域名: {}", clean_domain);
+                info!("已添加直连域名: {}", clean_domain);
                 Ok(())
             } else {
                 Err(format!("域名 {} 已存在于直连列表中", clean_domain))
@@ -490,7 +558,7 @@ fn add_direct_domain(domain: String) -> Result<(), String> {
 #[tauri::command]
 fn remove_direct_domain(domain: String) -> Result<(), String> {
     let clean_domain = domain.trim().to_lowercase();
-    println!("[main] 移除直连域名: {}", clean_domain);
+    info!("移除直连域名: {}", clean_domain);
 
     if let Ok(proxy_server) = PROXY_SERVER.lock() {
         if let Some(server) = proxy_server.as_ref() {
@@ -503,7 +571,7 @@ fn remove_direct_domain(domain: String) -> Result<(), String> {
 
             if settings.direct_domains.len() < original_len {
                 server.update_proxy_settings(settings);
-                println!("[main] ✅ 已移除直连域名: {}", clean_domain);
+                info!("已移除直连域名: {}", clean_domain);
                 Ok(())
             } else {
                 Err(format!("域名 {} 不存在于直连列表中", clean_domain))
@@ -516,9 +584,966 @@ fn remove_direct_domain(domain: String) -> Result<(), String> {
     }
 }
 
+// 新增：获取额外局域网网段
+#[tauri::command]
+fn get_lan_cidrs() -> Result<Vec<String>, String> {
+    if let Ok(proxy_server) = PROXY_SERVER.lock() {
+        if let Some(server) = proxy_server.as_ref() {
+            Ok(server.get_proxy_settings().lan_cidrs)
+        } else {
+            Err("代理服务器未启动".to_string())
+        }
+    } else {
+        Err("无法获取代理服务器锁".to_string())
+    }
+}
+
+// 新增：设置额外局域网网段（如 100.64.0.0/10）
+#[tauri::command]
+fn set_lan_cidrs(cidrs: Vec<String>) -> Result<(), String> {
+    let cidrs: Vec<String> = cidrs
+        .iter()
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+        .collect();
+    if let Some(invalid) = cidrs.iter().find(|c| IpCidr::parse(c).is_none()) {
+        return Err(format!("无效的网段: {}", invalid));
+    }
+    info!("设置局域网网段: {:?}", cidrs);
+
+    if let Ok(proxy_server) = PROXY_SERVER.lock() {
+        if let Some(server) = proxy_server.as_ref() {
+            let mut settings = server.get_proxy_settings();
+            settings.lan_cidrs = cidrs;
+            server.update_proxy_settings(settings);
+            Ok(())
+        } else {
+            Err("代理服务器未启动".to_string())
+        }
+    } else {
+        Err("无法获取代理服务器锁".to_string())
+    }
+}
+
+// 新增：预览规则导入结果（不修改设置）
+#[tauri::command]
+fn preview_rule_import(format: RuleFormat, content: String) -> Result<RuleImportReport, String> {
+    rule_import::parse_rules(format, &content)
+}
+
+// 新增：导入 gfwlist / Clash / SwitchyOmega 规则
+#[tauri::command]
+fn import_rules(
+    format: RuleFormat,
+    content: String,
+    replace: bool,
+) -> Result<RuleImportReport, String> {
+    let report = rule_import::parse_rules(format, &content)?;
+
+    if let Ok(proxy_server) = PROXY_SERVER.lock() {
+        if let Some(server) = proxy_server.as_ref() {
+            let mut settings = server.get_proxy_settings();
+            rule_import::apply_import(&mut settings, &report, replace);
+            server.update_proxy_settings(settings);
+            info!("已导入 {:?} 规则: {} 条", format, report.accepted.len());
+            Ok(report)
+        } else {
+            Err("代理服务器未启动".to_string())
+        }
+    } else {
+        Err("无法获取代理服务器锁".to_string())
+    }
+}
+
+// 新增：路由解释（不发起连接），输入 URL 或 "host:port"
+// 返回命中的规则、上游链路、循环检测结果和解析出的地址，DNS 解析在锁外进行
+#[tauri::command]
+async fn explain_route(target: String) -> Result<RouteExplanation, String> {
+    let context = with_proxy_server(|server| Ok(server.context()))?;
+    proxy_server::explain_route(&target, &context)
+}
+
+// 新增：获取规则命中统计（命中次数、上下行流量、上次命中时间）
+#[tauri::command]
+fn get_rule_stats() -> Result<Vec<RuleStatsEntry>, String> {
+    with_proxy_server(|server| Ok(server.get_rule_stats()))
+}
+
+// 新增：清空规则命中统计
+#[tauri::command]
+fn reset_rule_stats() -> Result<(), String> {
+    with_proxy_server(|server| {
+        server.reset_rule_stats();
+        Ok(())
+    })
+}
+
+// 新增：获取按时间/网络生效的分流规则
+#[tauri::command]
+fn get_conditional_rules() -> Result<Vec<ConditionalRule>, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().conditional_rules))
+}
+
+// 新增：设置按时间/网络生效的分流规则
+#[tauri::command]
+fn set_conditional_rules(rules: Vec<ConditionalRule>) -> Result<(), String> {
+    for rule in &rules {
+        if rule.rule.trim().is_empty() {
+            return Err("规则不能为空".to_string());
+        }
+        rule.condition
+            .validate()
+            .map_err(|e| format!("规则 {} 的条件无效: {}", rule.rule, e))?;
+    }
+    info!("设置条件规则: {} 条", rules.len());
+
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.conditional_rules = rules;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：获取配置方案列表
+#[tauri::command]
+fn get_routing_profiles() -> Result<Vec<RoutingProfile>, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().profiles))
+}
+
+// 新增：设置配置方案列表，按顺序取第一个满足条件的方案生效
+#[tauri::command]
+fn set_routing_profiles(profiles: Vec<RoutingProfile>) -> Result<(), String> {
+    for (i, profile) in profiles.iter().enumerate() {
+        if profile.name.trim().is_empty() {
+            return Err("方案名称不能为空".to_string());
+        }
+        if profiles[..i].iter().any(|p| p.name == profile.name) {
+            return Err(format!("方案名称重复: {}", profile.name));
+        }
+        profile
+            .condition
+            .validate()
+            .map_err(|e| format!("方案 {} 的条件无效: {}", profile.name, e))?;
+    }
+    info!("设置配置方案: {} 个", profiles.len());
+
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.profiles = profiles;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：获取当前生效的配置方案和条件规则
+#[tauri::command]
+fn get_active_conditions() -> Result<ActiveConditions, String> {
+    with_proxy_server(|server| Ok(server.context().snapshot.load().active.clone()))
+}
+
+// 新增：获取检测到的网络环境（默认网关、网卡地址），便于填写网络条件
+#[tauri::command]
+async fn get_network_env() -> Result<NetworkEnv, String> {
+    tauri::async_runtime::spawn_blocking(network_env::detect)
+        .await
+        .map_err(|e| format!("检测网络环境失败: {}", e))
+}
+
+// 生效方案变化事件的内容
+#[derive(Clone, Serialize)]
+struct ProfileChangedPayload {
+    network: NetworkEnv,
+    network_changed: bool, // false 表示仅因时间条件变化
+    active: ActiveConditions,
+}
+
+// 监视网络变化（默认路由、网卡地址、DNS 服务器）并定时重新判断时间/网络条件
+// 网络变化时以及每分钟重新读取系统代理，请求路径上只读缓存；生效方案变化时通知前端
+// 网络变化时关闭已建立的连接（隧道和复用的上游连接），由客户端在新网络上重新连接
+async fn run_network_watcher(app: tauri::AppHandle) {
+    const SYSTEM_PROXY_RELOAD_TICKS: u64 = 6; // 每 6 次检查（1 分钟）重新读取一次系统代理
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    let mut last_network: Option<NetworkEnv> = None;
+    let mut ticks: u64 = 0;
+    loop {
+        interval.tick().await;
+        ticks = (ticks + 1) % SYSTEM_PROXY_RELOAD_TICKS;
+        let reload_due = ticks == 0;
+        let Ok(context) = with_proxy_server(|server| Ok(server.context())) else {
+            continue;
+        };
+
+        let previous = last_network.clone();
+        let checked = tauri::async_runtime::spawn_blocking(move || {
+            let network = network_env::detect();
+            let network_changed = previous.as_ref().is_some_and(|p| p != &network);
+            if network_changed {
+                info!("检测到网络变化: {:?} -> {:?}", previous, network);
+                let closed = context.connections.abort_all();
+                if closed > 0 {
+                    info!("网络变化，已关闭 {} 个连接", closed);
+                }
+            }
+            // 用户可能在系统设置中修改代理而网络不变，因此也定时重新读取
+            if network_changed || reload_due {
+                context.reload_system_proxy();
+            }
+            let changed = context.refresh_conditions(&network);
+            let active = context.snapshot.load().active.clone();
+            (network, network_changed, changed.is_some(), active)
+        })
+        .await;
+
+        let (network, network_changed, conditions_changed, active) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                warn!("网络检查失败: {}", e);
+                continue;
+            }
+        };
+        last_network = Some(network.clone());
+
+        if network_changed || conditions_changed {
+            info!("当前生效方案: {:?}", active.profile);
+            let payload = ProfileChangedPayload {
+                network,
+                network_changed,
+                active,
+            };
+            if let Err(e) = app.emit("proxy-profile-changed", payload) {
+                warn!("发送方案变化事件失败: {}", e);
+            }
+        }
+    }
+}
+
+// 新增：获取监听设置
+#[tauri::command]
+fn get_listen_settings() -> Result<ListenSettings, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().listen))
+}
+
+// 新增：设置监听地址、端口和允许的客户端网段
+// 客户端网段立即生效，监听地址和端口在代理重启（restart_proxy_server）后生效
+#[tauri::command]
+fn set_listen_settings(listen: ListenSettings) -> Result<(), String> {
+    listen.validate()?;
+    if !listen.is_loopback_only() && listen.allowed_clients.is_empty() {
+        warn!("监听非本机地址但未设置允许的客户端网段，仅接受本机连接");
+    }
+    info!("设置监听参数: {:?}", listen);
+
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.listen = listen;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：获取入站认证设置
+#[tauri::command]
+fn get_inbound_auth() -> Result<InboundAuth, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().inbound_auth))
+}
+
+// 新增：设置入站认证（HTTP Proxy-Authorization Basic 和 SOCKS5 用户名密码），立即生效
+#[tauri::command]
+fn set_inbound_auth(auth: InboundAuth) -> Result<(), String> {
+    auth.validate()?;
+    info!(
+        "设置入站认证: 启用={}, 用户名={}, 本机也需认证={}",
+        auth.enabled, auth.username, auth.apply_to_loopback
+    );
+
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.inbound_auth = auth;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：获取并发连接上限
+#[tauri::command]
+fn get_connection_limits() -> Result<ConnectionLimits, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().limits))
+}
+
+// 新增：设置并发连接上限（0 表示不限制），对新连接立即生效
+#[tauri::command]
+fn set_connection_limits(limits: ConnectionLimits) -> Result<(), String> {
+    info!("设置并发连接上限: {:?}", limits);
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.limits = limits;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：获取当前、最高和排队中的连接数，以及各客户端、各目标主机的连接数
+#[tauri::command]
+fn get_concurrency_stats() -> Result<ConcurrencyStats, String> {
+    with_proxy_server(|server| Ok(server.get_concurrency_stats()))
+}
+
+// 新增：获取当前连接列表（客户端、目标、上游、开始时间、流量和状态）
+#[tauri::command]
+fn list_connections() -> Result<Vec<ConnectionEntry>, String> {
+    with_proxy_server(|server| Ok(server.list_connections()))
+}
+
+// 新增：关闭指定连接，隧道两个方向都会立即停止
+#[tauri::command]
+fn close_connection(id: u64) -> Result<(), String> {
+    with_proxy_server(|server| {
+        if server.close_connection(id) {
+            Ok(())
+        } else {
+            Err(format!("连接 #{} 不存在或已结束", id))
+        }
+    })
+}
+
+// 新增：获取各阶段超时设置
+#[tauri::command]
+fn get_timeout_settings() -> Result<TimeoutSettings, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().timeouts))
+}
+
+// 新增：设置连接、握手、首字节和空闲超时（秒，0 表示不限制），对新连接生效
+#[tauri::command]
+fn set_timeout_settings(timeouts: TimeoutSettings) -> Result<(), String> {
+    info!("设置超时: {:?}", timeouts);
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.timeouts = timeouts;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：获取带宽限制设置
+#[tauri::command]
+fn get_bandwidth_settings() -> Result<BandwidthSettings, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().bandwidth))
+}
+
+// 新增：设置全局和按主机的上传/下载限速（字节/秒）及延迟注入，对进行中的连接立即生效
+#[tauri::command]
+fn set_bandwidth_settings(bandwidth: BandwidthSettings) -> Result<(), String> {
+    bandwidth.validate()?;
+    info!("设置带宽限制: {:?}", bandwidth);
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.bandwidth = bandwidth;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 本地代理地址变化事件的内容；port 为 0 表示代理已停止
+#[derive(Clone, Serialize)]
+struct LocalProxyPayload {
+    port: u16,
+    url: Option<String>,
+}
+
+// 记录本地代理端口并设置 WebView 使用的代理环境变量
+// 主窗口的代理地址在创建时确定，地址变化后由 update_main_window_proxy 重新创建主窗口
+fn apply_local_proxy(server: Option<&ProxyServer>) -> LocalProxyPayload {
+    match server {
+        Some(server) => {
+            let url = server.local_url();
+            *LOCAL_PROXY_PORT.lock().unwrap() = server.port;
+            std::env::set_var("HTTP_PROXY", &url);
+            std::env::set_var("HTTPS_PROXY", &url);
+            info!("设置WebView代理环境变量: HTTP_PROXY={}", url);
+            LocalProxyPayload {
+                port: server.port,
+                url: Some(url),
+            }
+        }
+        None => {
+            *LOCAL_PROXY_PORT.lock().unwrap() = 0;
+            std::env::remove_var("HTTP_PROXY");
+            std::env::remove_var("HTTPS_PROXY");
+            info!("已清除WebView代理环境变量");
+            LocalProxyPayload { port: 0, url: None }
+        }
+    }
+}
+
+// 按本地代理地址创建主窗口（配置中 create 为 false，不自动创建）
+// WebView 的代理地址只能在创建窗口时指定
+fn create_main_window(
+    app: &tauri::AppHandle,
+    proxy_url: &str,
+) -> Result<tauri::WebviewWindow, String> {
+    let mut config = app
+        .config()
+        .app
+        .windows
+        .iter()
+        .find(|w| w.label == "main")
+        .cloned()
+        .ok_or("配置中缺少主窗口".to_string())?;
+    config.proxy_url = Some(
+        proxy_url
+            .parse()
+            .map_err(|e| format!("代理地址无效 {}: {}", proxy_url, e))?,
+    );
+    let window = tauri::WebviewWindowBuilder::from_config(app, &config)
+        .and_then(|builder| builder.build())
+        .map_err(|e| format!("创建主窗口失败: {}", e))?;
+    *MAIN_WINDOW_PROXY_URL.lock().unwrap() = Some(proxy_url.to_string());
+    info!("主窗口已创建，WebView 代理: {}", proxy_url);
+    Ok(window)
+}
+
+// 本地代理地址变化后重新创建主窗口，使 WebView 改用新的地址
+// 代理停止时保留原窗口，重新启动到同一地址时不需要重建
+fn update_main_window_proxy(app: &tauri::AppHandle, proxy_url: &str) -> Result<(), String> {
+    if MAIN_WINDOW_PROXY_URL.lock().unwrap().as_deref() == Some(proxy_url) {
+        return Ok(());
+    }
+    info!("本地代理地址已变化，重新创建主窗口: {}", proxy_url);
+
+    RECREATING_MAIN_WINDOW.store(true, Ordering::SeqCst);
+    let result = recreate_main_window(app, proxy_url);
+    RECREATING_MAIN_WINDOW.store(false, Ordering::SeqCst);
+    result
+}
+
+fn recreate_main_window(app: &tauri::AppHandle, proxy_url: &str) -> Result<(), String> {
+    if let Some(window) = app.get_webview_window("main") {
+        window
+            .destroy()
+            .map_err(|e| format!("关闭主窗口失败: {}", e))?;
+    }
+    let window = create_main_window(app, proxy_url)?;
+    window
+        .show()
+        .and_then(|_| window.set_focus())
+        .map_err(|e| format!("显示主窗口失败: {}", e))
+}
+
+fn notify_local_proxy_changed(app: &tauri::AppHandle, payload: LocalProxyPayload) {
+    if let Err(e) = app.emit("local-proxy-changed", payload) {
+        warn!("发送代理地址变化事件失败: {}", e);
+    }
+}
+
+// 新增：停止本地代理，释放监听端口
+// 等待进行中的连接结束，超过 drain_timeout_secs 秒后强制关闭（0 表示立即关闭）
+#[tauri::command]
+async fn stop_proxy_server(
+    app: tauri::AppHandle,
+    drain_timeout_secs: Option<u64>,
+) -> Result<StopReport, String> {
+    let drain_timeout =
+        Duration::from_secs(drain_timeout_secs.unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS));
+    // 先取出实例再停止，等待连接结束期间不占用全局锁
+    let server = PROXY_SERVER
+        .lock()
+        .map_err(|_| "无法获取代理服务器锁".to_string())?
+        .take();
+    let Some(mut server) = server else {
+        return Err("代理服务器未启动".to_string());
+    };
+
+    let report = tauri::async_runtime::spawn_blocking(move || server.stop(drain_timeout))
+        .await
+        .map_err(|e| format!("停止代理失败: {}", e))?;
+    notify_local_proxy_changed(&app, apply_local_proxy(None));
+    Ok(report)
+}
+
+// 新增：重启本地代理，使监听地址和端口等设置生效
+// settings 为空时使用当前设置；代理已停止时按设置（或配置文件）重新启动
+#[tauri::command]
+async fn restart_proxy_server(
+    app: tauri::AppHandle,
+    settings: Option<ProxySettings>,
+    drain_timeout_secs: Option<u64>,
+) -> Result<StopReport, String> {
+    let drain_timeout =
+        Duration::from_secs(drain_timeout_secs.unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS));
+    let server = PROXY_SERVER
+        .lock()
+        .map_err(|_| "无法获取代理服务器锁".to_string())?
+        .take();
+
+    let result = tauri::async_runtime::spawn_blocking(move || match server {
+        Some(mut server) => {
+            let settings = settings.unwrap_or_else(|| server.get_proxy_settings());
+            let result = server.restart(settings, drain_timeout);
+            (Some(server), result)
+        }
+        None => {
+            let settings = match settings {
+                Some(settings) => settings,
+                None => load_settings_from_file().unwrap_or_default(),
+            };
+            match ProxyServer::start(settings) {
+                Ok(server) => (Some(server), Ok(StopReport::default())),
+                Err(e) => (None, Err(e)),
+            }
+        }
+    })
+    .await
+    .map_err(|e| format!("重启代理失败: {}", e))?;
+
+    let (server, result) = result;
+    let payload = apply_local_proxy(server.as_ref());
+    *PROXY_SERVER
+        .lock()
+        .map_err(|_| "无法获取代理服务器锁".to_string())? = server;
+    if let Some(url) = &payload.url {
+        if let Err(e) = update_main_window_proxy(&app, url) {
+            error!("{}", e);
+        }
+    }
+    notify_local_proxy_changed(&app, payload);
+    result
+}
+
+// 在持有代理服务器锁的情况下执行操作
+fn with_proxy_server<T>(f: impl FnOnce(&ProxyServer) -> Result<T, String>) -> Result<T, String> {
+    let proxy_server = PROXY_SERVER
+        .lock()
+        .map_err(|_| "无法获取代理服务器锁".to_string())?;
+    match proxy_server.as_ref() {
+        Some(server) => f(server),
+        None => Err("代理服务器未启动".to_string()),
+    }
+}
+
+// 新增：获取规则订阅列表
+#[tauri::command]
+fn get_rule_subscriptions() -> Result<Vec<RuleSubscription>, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().subscriptions))
+}
+
+// 新增：添加规则订阅，添加后立即刷新一次
+#[tauri::command(rename_all = "camelCase")]
+async fn add_rule_subscription(
+    url: String,
+    format: RuleFormat,
+    refresh_interval_secs: Option<u64>,
+) -> Result<RuleSubscription, String> {
+    let url = url.trim().to_string();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err("订阅地址必须以 http:// 或 https:// 开头".to_string());
+    }
+
+    let subscription = RuleSubscription::new(
+        url,
+        format,
+        refresh_interval_secs.unwrap_or(rule_subscription::DEFAULT_REFRESH_INTERVAL_SECS),
+    );
+    info!(
+        "添加规则订阅: {} ({})",
+        subscription.id,
+        proxy_authority(&subscription.url)
+    );
+
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        if settings
+            .subscriptions
+            .iter()
+            .any(|s| s.url == subscription.url)
+        {
+            return Err(format!("订阅 {} 已存在", subscription.url));
+        }
+        settings.subscriptions.push(subscription.clone());
+        server.update_proxy_settings(settings);
+        Ok(())
+    })?;
+
+    refresh_subscriptions(Some(subscription.id.clone())).await?;
+    get_rule_subscriptions()?
+        .into_iter()
+        .find(|s| s.id == subscription.id)
+        .ok_or("订阅已被删除".to_string())
+}
+
+// 新增：删除规则订阅
+#[tauri::command]
+fn remove_rule_subscription(id: String) -> Result<(), String> {
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        let original_len = settings.subscriptions.len();
+        settings.subscriptions.retain(|s| s.id != id);
+        if settings.subscriptions.len() < original_len {
+            server.update_proxy_settings(settings);
+            info!("已删除规则订阅: {}", id);
+            Ok(())
+        } else {
+            Err(format!("订阅 {} 不存在", id))
+        }
+    })
+}
+
+// 新增：立即刷新指定规则订阅
+#[tauri::command]
+async fn refresh_rule_subscription(id: String) -> Result<RuleSubscription, String> {
+    refresh_subscriptions(Some(id.clone())).await?;
+    let subscription = get_rule_subscriptions()?
+        .into_iter()
+        .find(|s| s.id == id)
+        .ok_or(format!("订阅 {} 不存在", id))?;
+    match &subscription.last_error {
+        Some(e) => Err(e.clone()),
+        None => Ok(subscription),
+    }
+}
+
+// 刷新规则订阅：only_id 为空时刷新所有到期的订阅，返回刷新的数量
+// 下载通过本地代理进行，使用代理自身的分流规则；下载期间不持有代理服务器锁
+async fn refresh_subscriptions(only_id: Option<String>) -> Result<usize, String> {
+    let now = rule_subscription::unix_now();
+    let due: Vec<RuleSubscription> = with_proxy_server(|server| {
+        Ok(server
+            .get_proxy_settings()
+            .subscriptions
+            .into_iter()
+            .filter(|s| match &only_id {
+                Some(id) => &s.id == id,
+                None => s.enabled && s.is_due(now),
+            })
+            .collect())
+    })?;
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    let (proxy_url, auth) = with_proxy_server(|server| {
+        Ok((server.local_url(), server.get_proxy_settings().inbound_auth))
+    })?;
+    let client = rule_subscription::build_client(Some((&proxy_url, &auth)))?;
+    let mut results = Vec::new();
+    for sub in &due {
+        results.push((
+            sub.id.clone(),
+            rule_subscription::fetch_subscription(&client, sub).await,
+        ));
+    }
+
+    // 重新读取最新设置后再合并，避免覆盖下载期间的修改
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        rule_subscription::apply_refresh_results(&mut settings, results, now);
+        if let Err(e) = proxy_server::save_subscription_cache(&settings) {
+            warn!("保存订阅规则缓存失败: {}", e);
+        }
+        server.update_proxy_settings(settings);
+        Ok(due.len())
+    })
+}
+
+// 后台定时检查规则订阅
+async fn run_subscription_scheduler() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        match refresh_subscriptions(None).await {
+            Ok(0) => {}
+            Ok(count) => info!("已刷新 {} 个规则订阅", count),
+            Err(e) => warn!("规则订阅刷新失败: {}", e),
+        }
+    }
+}
+
+// 新增：获取本次运行的流量统计（全局、按目标主机、按上游）
+#[tauri::command]
+fn get_traffic_stats() -> Result<TrafficSnapshot, String> {
+    with_proxy_server(|server| Ok(server.get_traffic_stats()))
+}
+
+// 新增：清空本次运行的流量统计，不影响历史记录
+#[tauri::command]
+fn reset_traffic_stats() -> Result<(), String> {
+    with_proxy_server(|server| {
+        server.context().traffic.reset();
+        Ok(())
+    })
+}
+
+// 新增：获取按天保存的历史流量
+#[tauri::command]
+async fn get_traffic_history() -> Result<TrafficHistory, String> {
+    let context = with_proxy_server(|server| Ok(server.context()))?;
+    tauri::async_runtime::spawn_blocking(move || context.traffic.history())
+        .await
+        .map_err(|e| format!("读取流量历史失败: {}", e))
+}
+
+// 新增：获取访问日志设置
+#[tauri::command]
+fn get_access_log_settings() -> Result<AccessLogSettings, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().access_log))
+}
+
+// 新增：启用或关闭访问日志，设置单个文件大小和保留的文件数
+#[tauri::command]
+fn set_access_log_settings(access_log: AccessLogSettings) -> Result<(), String> {
+    info!("设置访问日志: {:?}", access_log);
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.access_log = access_log;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：获取访问日志所在目录
+#[tauri::command]
+fn get_access_log_dir() -> Result<String, String> {
+    with_proxy_server(|server| {
+        server
+            .context()
+            .access_log
+            .dir()
+            .map(|dir| dir.display().to_string())
+            .ok_or_else(|| "访问日志目录不可用".to_string())
+    })
+}
+
+// 实时流量事件的内容，速率单位为字节/秒
+#[derive(Clone, Serialize)]
+struct TrafficTickPayload {
+    total: TrafficTotals,
+    up_rate: u64,
+    down_rate: u64,
+    active_connections: usize,
+}
+
+// 流量写入历史文件的间隔
+const TRAFFIC_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+// 每秒发送实时流量事件，每分钟将流量写入按天保存的历史
+async fn run_traffic_reporter(app: tauri::AppHandle) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut last_total: Option<TrafficTotals> = None;
+    let mut last_persist = std::time::Instant::now();
+    loop {
+        interval.tick().await;
+        let Ok(context) = with_proxy_server(|server| Ok(server.context())) else {
+            last_total = None;
+            continue;
+        };
+
+        let total = context.traffic.total();
+        // 统计被清空后累计值会变小，此时按 0 计算速率
+        let (up_rate, down_rate) = match last_total {
+            Some(last) => (
+                total.bytes_up.saturating_sub(last.bytes_up),
+                total.bytes_down.saturating_sub(last.bytes_down),
+            ),
+            None => (0, 0),
+        };
+        last_total = Some(total);
+
+        let payload = TrafficTickPayload {
+            total,
+            up_rate,
+            down_rate,
+            active_connections: context.connections.count(),
+        };
+        if let Err(e) = app.emit("traffic-stats", payload) {
+            warn!("发送流量事件失败: {}", e);
+        }
+
+        if last_persist.elapsed() >= TRAFFIC_PERSIST_INTERVAL {
+            last_persist = std::time::Instant::now();
+            let persisted =
+                tauri::async_runtime::spawn_blocking(move || context.traffic.persist()).await;
+            if let Ok(Err(e)) = persisted {
+                warn!("保存流量历史失败: {}", e);
+            }
+        }
+    }
+}
+
+// 新增：获取默认和各模块的日志级别
+#[tauri::command]
+fn get_log_levels() -> LogLevels {
+    logging::levels()
+}
+
+// 新增：设置日志级别，module 为空时设置默认级别，level 为空时取消该模块的单独设置
+#[tauri::command]
+fn set_log_level(module: Option<String>, level: Option<String>) -> Result<LogLevels, String> {
+    let levels = logging::set_level(module.as_deref(), level.as_deref())?;
+    info!("日志级别已更新: {:?}", levels);
+    Ok(levels)
+}
+
+// 新增：获取内存中最近的日志，since 为已获取的最后一条日志的序号
+#[tauri::command]
+fn get_recent_logs(since: Option<u64>) -> Vec<LogEntry> {
+    logging::recent(since)
+}
+
+// 新日志推送到前端的间隔
+const LOG_STREAM_INTERVAL: Duration = Duration::from_millis(500);
+
+// 定期将新增的日志批量发送给前端日志查看器
+async fn run_log_streamer(app: tauri::AppHandle) {
+    let mut interval = tokio::time::interval(LOG_STREAM_INTERVAL);
+    let mut last_seq = logging::recent(None).last().map(|entry| entry.seq);
+    loop {
+        interval.tick().await;
+        let entries = logging::recent(last_seq);
+        let Some(last) = entries.last() else {
+            continue;
+        };
+        last_seq = Some(last.seq);
+        // 发送失败不记录日志，避免每次发送都产生新的日志
+        let _ = app.emit("log-entries", entries);
+    }
+}
+
+// 新增：获取抓包设置
+#[tauri::command]
+fn get_har_settings() -> Result<HarSettings, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().har))
+}
+
+// 新增：启用或关闭抓包，设置记录的请求体/响应体大小和保留的条目数
+#[tauri::command]
+fn set_har_settings(har: HarSettings) -> Result<(), String> {
+    info!("设置抓包: {:?}", har);
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.har = har;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：将已抓取的请求导出为 HAR 文件，返回导出的条目数
+#[tauri::command]
+async fn export_har(path: String) -> Result<usize, String> {
+    let context = with_proxy_server(|server| Ok(server.context()))?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let document = context.har.export();
+        let json = serde_json::to_string_pretty(&document)
+            .map_err(|e| format!("序列化 HAR 失败: {}", e))?;
+        std::fs::write(&path, json).map_err(|e| format!("写入 HAR 文件失败: {}", e))?;
+        let count = document.log.entries.len();
+        info!("已导出 {} 条抓包记录到 {}", count, path);
+        Ok(count)
+    })
+    .await
+    .map_err(|e| format!("导出 HAR 失败: {}", e))?
+}
+
+// 新增：清空已抓取的请求
+#[tauri::command]
+fn clear_har_capture() -> Result<(), String> {
+    with_proxy_server(|server| {
+        server.context().har.clear();
+        Ok(())
+    })
+}
+
+// 新增：获取 HTTPS 解密设置
+#[tauri::command]
+fn get_tls_intercept_settings() -> Result<TlsInterceptSettings, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().tls_intercept))
+}
+
+// 新增：启用或关闭 HTTPS 解密，设置需要解密的主机
+#[tauri::command]
+fn set_tls_intercept_settings(tls_intercept: TlsInterceptSettings) -> Result<(), String> {
+    info!("设置 HTTPS 解密: {:?}", tls_intercept);
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.tls_intercept = tls_intercept;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+// 新增：获取本地 CA 证书（PEM），尚未生成时生成
+#[tauri::command]
+async fn get_ca_certificate() -> Result<String, String> {
+    let context = with_proxy_server(|server| Ok(server.context()))?;
+    tauri::async_runtime::spawn_blocking(move || context.tls.ca_certificate())
+        .await
+        .map_err(|e| format!("读取 CA 证书失败: {}", e))?
+}
+
+// 新增：将本地 CA 证书导出到文件，用于安装为受信任的根证书
+#[tauri::command]
+async fn export_ca_certificate(path: String) -> Result<(), String> {
+    let pem = get_ca_certificate().await?;
+    std::fs::write(&path, pem).map_err(|e| format!("写入 CA 证书失败: {}", e))?;
+    info!("已导出 CA 证书到 {}", path);
+    Ok(())
+}
+
+// 新增：获取请求头规则
+#[tauri::command]
+fn get_header_rules() -> Result<HeaderRuleSettings, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().header_rules))
+}
+
+// 新增：设置请求头规则，规则按顺序执行
+#[tauri::command]
+fn set_header_rules(header_rules: HeaderRuleSettings) -> Result<(), String> {
+    header_rules.validate()?;
+    info!("设置请求头规则: {} 条", header_rules.rules.len());
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.header_rules = header_rules;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
+#[derive(Serialize)]
+struct UserAgentPreset {
+    name: &'static str,
+    value: &'static str,
+}
+
+// 新增：获取常用 User-Agent 预设
+#[tauri::command]
+fn get_user_agent_presets() -> Vec<UserAgentPreset> {
+    USER_AGENT_PRESETS
+        .iter()
+        .map(|&(name, value)| UserAgentPreset { name, value })
+        .collect()
+}
+
+// 新增：获取 URL 改写和重定向规则
+#[tauri::command]
+fn get_url_rewrite_rules() -> Result<UrlRewriteSettings, String> {
+    with_proxy_server(|server| Ok(server.get_proxy_settings().url_rewrite))
+}
+
+// 新增：设置 URL 改写和重定向规则，规则按顺序执行
+#[tauri::command]
+fn set_url_rewrite_rules(url_rewrite: UrlRewriteSettings) -> Result<(), String> {
+    url_rewrite.validate()?;
+    info!("设置 URL 改写规则: {} 条", url_rewrite.rules.len());
+    with_proxy_server(|server| {
+        let mut settings = server.get_proxy_settings();
+        settings.url_rewrite = url_rewrite;
+        server.update_proxy_settings(settings);
+        Ok(())
+    })
+}
+
 #[tauri::command]
 fn apply_manual_proxy() -> Result<(), String> {
-    println!("[main] 开始应用手动代理设置");
+    info!("开始应用手动代理设置");
 
     if let Ok(proxy_server) = PROXY_SERVER.lock() {
         if let Some(server) = proxy_server.as_ref() {
@@ -528,7 +1553,7 @@ fn apply_manual_proxy() -> Result<(), String> {
 
             // 应用设置
             server.update_proxy_settings(settings.clone());
-            println!("[main] ✅ 手动代理设置已应用: {:?}", settings);
+            info!("手动代理设置已应用: {}", settings.summary());
             Ok(())
         } else {
             Err("代理服务器未启动".to_string())
@@ -539,13 +1564,12 @@ fn apply_manual_proxy() -> Result<(), String> {
 }
 
 fn main() {
-    // 设置日志级别
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    // 初始化日志，级别可通过 RUST_LOG 设置，运行时可按模块调整
+    logging::init();
 
     // 运行应用
     if let Err(e) = run_app() {
-        eprintln!("应用程序运行失败: {}", e);
+        error!("应用程序运行失败: {}", e);
         std::process::exit(1);
     }
 }
@@ -579,60 +1603,149 @@ fn run_app() -> Result<(), Box<dyn std::error::Error>> {
             get_direct_domains,
             set_direct_domains,
             add_direct_domain,
-            remove_direct_domain
+            remove_direct_domain,
+            // 局域网网段命令
+            get_lan_cidrs,
+            set_lan_cidrs,
+            // 规则导入命令
+            preview_rule_import,
+            import_rules,
+            // 规则订阅命令
+            get_rule_subscriptions,
+            add_rule_subscription,
+            remove_rule_subscription,
+            refresh_rule_subscription,
+            // 路由诊断命令
+            explain_route,
+            get_rule_stats,
+            reset_rule_stats,
+            // 条件规则与配置方案命令
+            get_conditional_rules,
+            set_conditional_rules,
+            get_routing_profiles,
+            set_routing_profiles,
+            get_active_conditions,
+            get_network_env,
+            // 监听设置命令
+            get_listen_settings,
+            set_listen_settings,
+            get_inbound_auth,
+            set_inbound_auth,
+            // 代理启停命令
+            stop_proxy_server,
+            restart_proxy_server,
+            // 并发限制命令
+            get_connection_limits,
+            set_connection_limits,
+            get_concurrency_stats,
+            // 连接管理命令
+            list_connections,
+            close_connection,
+            // 超时设置命令
+            get_timeout_settings,
+            set_timeout_settings,
+            // 带宽限制命令
+            get_bandwidth_settings,
+            set_bandwidth_settings,
+            // 流量统计命令
+            get_traffic_stats,
+            reset_traffic_stats,
+            get_traffic_history,
+            // 访问日志命令
+            get_access_log_settings,
+            set_access_log_settings,
+            get_access_log_dir,
+            // 日志命令
+            get_log_levels,
+            set_log_level,
+            get_recent_logs,
+            // HAR 抓包命令
+            get_har_settings,
+            set_har_settings,
+            export_har,
+            clear_har_capture,
+            // HTTPS 解密命令
+            get_tls_intercept_settings,
+            set_tls_intercept_settings,
+            get_ca_certificate,
+            export_ca_certificate,
+            // 请求头规则命令
+            get_header_rules,
+            set_header_rules,
+            get_user_agent_presets,
+            // URL 改写命令
+            get_url_rewrite_rules,
+            set_url_rewrite_rules
         ])
         .setup(|app| {
-            // 启动代理服务器
-            let proxy_port = 8080;
-            let proxy_server = proxy_server::ProxyServer::start_auto_port(proxy_port, proxy_port + 100)
-                .expect("Failed to start proxy server");
-            
-            // 保存代理服务器端口
-            *LOCAL_PROXY_PORT.lock().unwrap() = proxy_server.port;
-            
             // 从文件加载代理设置，如果文件不存在则使用默认设置
             let loaded_settings = match load_settings_from_file() {
                 Ok(settings) => {
-                    println!("✅ 成功从配置文件加载代理设置: {:?}", settings);
+                    info!("成功从配置文件加载代理设置: {}", settings.summary());
                     settings
                 },
                 Err(e) => {
-                    println!("⚠️ 加载配置文件失败，使用默认设置: {}", e);
+                    warn!("加载配置文件失败，使用默认设置: {}", e);
                     ProxySettings {
-                        proxy_type: ProxyType::None,
-                        http_proxy: None,
-                        https_proxy: None,
-                        socks5_proxy: None,
-                        username: None,
-                        password: None,
                         enabled: false,
-                        direct_domains: vec![],
+                        ..ProxySettings::default()
                     }
                 }
             };
 
-            // 更新代理服务器设置
-            proxy_server.update_proxy_settings(loaded_settings);
-            
+            // 按设置中的监听地址启动代理服务器，失败时回退到默认监听设置
+            let proxy_server = match ProxyServer::start(loaded_settings.clone()) {
+                Ok(server) => server,
+                Err(e) => {
+                    warn!("按监听设置启动代理失败，使用默认监听设置: {}", e);
+                    ProxyServer::start(ProxySettings {
+                        listen: ListenSettings::default(),
+                        ..loaded_settings
+                    })
+                    .expect("Failed to start proxy server")
+                }
+            };
+
+            // 保存代理服务器端口并设置WebView代理环境变量
+            apply_local_proxy(Some(&proxy_server));
+            let proxy_url = proxy_server.local_url();
+
             // 保存代理服务器实例
             *PROXY_SERVER.lock().unwrap() = Some(proxy_server);
             
-            println!("✅ 代理服务器启动在端口: {}", *LOCAL_PROXY_PORT.lock().unwrap());
+            info!(
+                "代理服务器启动在端口: {}",
+                *LOCAL_PROXY_PORT.lock().unwrap()
+            );
 
-            // 设置系统代理环境变量
-            let proxy_url = format!("http://127.0.0.1:{}", *LOCAL_PROXY_PORT.lock().unwrap());
-            std::env::set_var("HTTP_PROXY", &proxy_url);
-            std::env::set_var("HTTPS_PROXY", &proxy_url);
-            println!("🔧 设置WebView代理环境变量: HTTP_PROXY={}", proxy_url);
+            // 启动规则订阅后台刷新任务
+            tauri::async_runtime::spawn(run_subscription_scheduler());
+            tauri::async_runtime::spawn(run_network_watcher(app.handle().clone()));
+            tauri::async_runtime::spawn(run_traffic_reporter(app.handle().clone()));
+            tauri::async_runtime::spawn(run_log_streamer(app.handle().clone()));
 
-            // 获取主窗口并确保它显示
-            if let Some(window) = app.get_webview_window("main") {
-                window.show()?;
-                window.set_focus()?;
-                println!("✅ 主窗口已显示并获得焦点");
-            }
+            // 按代理实际监听的地址创建主窗口并确保它显示
+            let window = create_main_window(app.handle(), &proxy_url)?;
+            window.show()?;
+            window.set_focus()?;
+            info!("主窗口已显示并获得焦点");
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .map_err(|e| e.into())
+        .build(tauri::generate_context!())?
+        .run(|_app, event| match event {
+            // 重新创建主窗口时会短暂没有窗口，阻止应用因此退出
+            tauri::RunEvent::ExitRequested {
+                code: None, api, ..
+            } if RECREATING_MAIN_WINDOW.load(Ordering::SeqCst) => api.prevent_exit(),
+            // 应用退出前保存最近一分钟内尚未写入历史的流量
+            tauri::RunEvent::Exit => {
+                if let Ok(context) = with_proxy_server(|server| Ok(server.context())) {
+                    if let Err(e) = context.traffic.persist() {
+                        warn!("退出时保存流量历史失败: {}", e);
+                    }
+                }
+            }
+            _ => {}
+        });
+    Ok(())
 }
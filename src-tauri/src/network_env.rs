@@ -0,0 +1,178 @@
+use log::warn;
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use serde::Serialize;
+use std::net::IpAddr;
+#[cfg(target_os = "linux")]
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// 当前所处的网络环境，用于按网络启用规则和配置方案
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NetworkEnv {
+    pub gateways: Vec<IpAddr>,    // 默认网关
+    pub addresses: Vec<IpAddr>,   // 本机网卡地址（不含回环地址）
+    pub dns_servers: Vec<IpAddr>, // DNS 服务器
+}
+
+// 检测当前网络环境
+pub fn detect() -> NetworkEnv {
+    let mut gateways = default_gateways();
+    gateways.sort();
+    gateways.dedup();
+
+    let mut addresses: Vec<IpAddr> = match NetworkInterface::show() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .filter(|iface| !iface.internal)
+            .flat_map(|iface| iface.addr.into_iter().map(|addr| addr.ip()))
+            .filter(|ip| !ip.is_loopback())
+            .collect(),
+        Err(e) => {
+            warn!("读取网卡地址失败: {}", e);
+            vec![]
+        }
+    };
+    addresses.sort();
+    addresses.dedup();
+
+    let mut dns_servers = dns_servers();
+    dns_servers.dedup();
+
+    NetworkEnv {
+        gateways,
+        addresses,
+        dns_servers,
+    }
+}
+
+// Linux / macOS：读取 /etc/resolv.conf 中的 nameserver
+#[cfg(unix)]
+fn dns_servers() -> Vec<IpAddr> {
+    let content = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().split('%').next()?.parse().ok())
+        .collect()
+}
+
+// Windows：读取各网卡的 NameServer（静态）或 DhcpNameServer（DHCP 分配）
+#[cfg(target_os = "windows")]
+fn dns_servers() -> Vec<IpAddr> {
+    use winreg::enums::*;
+    use winreg::RegKey;
+
+    let mut servers = Vec::new();
+    let Ok(interfaces) = RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey("SYSTEM\\CurrentControlSet\\Services\\Tcpip\\Parameters\\Interfaces")
+    else {
+        return servers;
+    };
+    for name in interfaces.enum_keys().flatten() {
+        let Ok(interface) = interfaces.open_subkey(&name) else {
+            continue;
+        };
+        let value = interface
+            .get_value::<String, _>("NameServer")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .or_else(|| interface.get_value::<String, _>("DhcpNameServer").ok())
+            .unwrap_or_default();
+        servers.extend(
+            value
+                .split([' ', ','])
+                .filter_map(|server| server.trim().parse::<IpAddr>().ok()),
+        );
+    }
+    servers
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+fn dns_servers() -> Vec<IpAddr> {
+    vec![]
+}
+
+// Linux：从 /proc/net/route 和 /proc/net/ipv6_route 读取默认路由的网关
+#[cfg(target_os = "linux")]
+fn default_gateways() -> Vec<IpAddr> {
+    let mut gateways = Vec::new();
+
+    // 格式：Iface Destination Gateway Flags ...，地址为小端序十六进制
+    if let Ok(content) = std::fs::read_to_string("/proc/net/route") {
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields[1] != "00000000" {
+                continue;
+            }
+            if let Ok(gateway) = u32::from_str_radix(fields[2], 16) {
+                if gateway != 0 {
+                    gateways.push(IpAddr::V4(Ipv4Addr::from(gateway.to_le_bytes())));
+                }
+            }
+        }
+    }
+
+    // 格式：目标 前缀长度 源 源前缀长度 下一跳 ...，地址为 32 位十六进制
+    if let Ok(content) = std::fs::read_to_string("/proc/net/ipv6_route") {
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 5 || fields[1] != "00" || !fields[0].trim_matches('0').is_empty() {
+                continue;
+            }
+            if let Ok(next_hop) = u128::from_str_radix(fields[4], 16) {
+                if next_hop != 0 {
+                    gateways.push(IpAddr::V6(Ipv6Addr::from(next_hop)));
+                }
+            }
+        }
+    }
+
+    gateways
+}
+
+// macOS：解析 "route -n get default" 输出中的 gateway 行
+#[cfg(target_os = "macos")]
+fn default_gateways() -> Vec<IpAddr> {
+    let output = match std::process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
+        Err(_) => return vec![],
+    };
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("gateway:"))
+        .filter_map(|gateway| gateway.trim().parse().ok())
+        .collect()
+}
+
+// Windows：解析 "route print -4 0.0.0.0" 输出中目标为 0.0.0.0 的路由
+#[cfg(target_os = "windows")]
+fn default_gateways() -> Vec<IpAddr> {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x08000000; // 不弹出控制台窗口
+
+    let output = match std::process::Command::new("route")
+        .args(["print", "-4", "0.0.0.0"])
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
+        Err(_) => return vec![],
+    };
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["0.0.0.0", "0.0.0.0", gateway, ..] => gateway.parse().ok(),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn default_gateways() -> Vec<IpAddr> {
+    vec![]
+}
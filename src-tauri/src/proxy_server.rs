@@ -6,26 +6,56 @@
  * @FilePath: \liuyao_desktop_tauri\src-tauri\src\proxy_server.rs
  * @Description: 这是默认设置,请设置`customMade`, 打开koroFileHeader查看配置 进行设置: https://github.com/OBKoro1/koro1FileHeader/wiki/%E9%85%8D%E7%BD%AE
  */
+use crate::access_log::{AccessLog, AccessLogSettings};
+use crate::bypass::{normalize_host, BypassList, IpCidr};
+use crate::connections::{ConnectionEntry, ConnectionInfo, ConnectionTracker};
+use crate::har::{HarCapture, HarRecorder, HarSettings};
+use crate::header_rules::{HeaderRuleSettings, HeaderRules};
+use crate::http_message::{self, BodyLength, MessageReader};
+use crate::inbound_auth::{InboundAuth, PROXY_AUTH_REQUIRED};
+use crate::limits::{ConcurrencyStats, ConnectionLimiter, ConnectionLimits, HostSlot};
+use crate::listen::{bind_listeners, local_proxy_addr, ClientAcl, ListenSettings};
+use crate::network_env::NetworkEnv;
+use crate::route_conditions::{ActiveConditions, ConditionEnv, ConditionalRule, RoutingProfile};
+use crate::rule_set::CompiledRules;
+use crate::rule_stats::{RuleStats, RuleStatsEntry};
+use crate::rule_subscription::{self, RuleSubscription};
+use crate::throttle::{BandwidthSettings, Direction, HostThrottle, Throttle};
+use crate::timeouts::{TimeoutSettings, TunnelTimeouts};
+use crate::tls_intercept::{TlsInterceptSettings, TlsInterceptor, TlsStream};
+use crate::traffic::{TrafficMeter, TrafficSnapshot, TrafficStats};
+use crate::url_rewrite::{self, UrlRewrite, UrlRewriteSettings, UrlRewrites};
+use arc_swap::ArcSwap;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 // 新增：文件操作和路径管理
 use std::fs;
 use std::path::{Path, PathBuf};
 
 const BUFFER_SIZE: usize = 16 * 1024; // 16KB buffer，对大多数HTTP请求足够
 const WS_BUFFER_SIZE: usize = 32 * 1024; // 32KB for WebSocket，为WebSocket连接提供更大缓冲区
-const TIMEOUT: u64 = 10; // 60秒超时
+const TUNNEL_POLL_INTERVAL: Duration = Duration::from_secs(1); // 隧道检查停止信号和超时的间隔
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50); // 检查停止信号的间隔
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1); // 拒绝连接时读取请求和写入响应的超时
+const SERVICE_UNAVAILABLE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
 
 // 新增：配置文件名称
 const CONFIG_FILE_NAME: &str = "proxy_settings.json";
+const TRAFFIC_HISTORY_FILE_NAME: &str = "traffic_history.json";
+const ACCESS_LOG_DIR_NAME: &str = "logs";
+const CA_DIR_NAME: &str = "ca";
+const SUBSCRIPTION_CACHE_FILE_NAME: &str = "rule_subscriptions.json"; // 订阅下载的规则
 
 // 新增：代理配置结构体
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ProxySettings {
     pub proxy_type: ProxyType,
     pub http_proxy: Option<String>,
@@ -35,6 +65,94 @@ pub struct ProxySettings {
     pub password: Option<String>,
     pub enabled: bool,
     pub direct_domains: Vec<String>, // 新增：直连域名列表
+    #[serde(default)]
+    pub lan_cidrs: Vec<String>, // 新增：额外视为局域网的网段，如 "100.64.0.0/10"
+    #[serde(default)]
+    pub resolve_local_hostnames: bool, // 新增：解析主机名后判断是否为局域网地址
+    #[serde(default)]
+    pub proxy_domains: Vec<String>, // 新增：强制走代理的域名列表
+    #[serde(default)]
+    pub default_route: RouteAction, // 新增：手动模式下未命中任何规则时的动作
+    #[serde(default)]
+    pub subscriptions: Vec<RuleSubscription>, // 新增：远程规则订阅
+    #[serde(default)]
+    pub conditional_rules: Vec<ConditionalRule>, // 新增：按时间/网络生效的分流规则
+    #[serde(default)]
+    pub profiles: Vec<RoutingProfile>, // 新增：按时间/网络自动启用的配置方案
+    #[serde(default)]
+    pub listen: ListenSettings, // 新增：本地代理监听地址、端口和客户端访问控制
+    #[serde(default)]
+    pub inbound_auth: InboundAuth, // 新增：客户端连接本地代理的认证（与上游代理认证分开）
+    #[serde(default)]
+    pub limits: ConnectionLimits, // 新增：全局、单客户端、单目标主机的并发连接上限
+    #[serde(default)]
+    pub timeouts: TimeoutSettings, // 新增：连接、握手、首字节和空闲超时
+    #[serde(default)]
+    pub bandwidth: BandwidthSettings, // 新增：全局和按主机的上传/下载限速、延迟注入
+    #[serde(default)]
+    pub access_log: AccessLogSettings, // 新增：JSON 行格式的访问日志
+    #[serde(default)]
+    pub har: HarSettings, // 新增：抓包并导出为 HAR 文件
+    #[serde(default)]
+    pub tls_intercept: TlsInterceptSettings, // 新增：对指定主机解密 HTTPS
+    #[serde(default)]
+    pub header_rules: HeaderRuleSettings, // 新增：转发前改写请求头
+    #[serde(default)]
+    pub url_rewrite: UrlRewriteSettings, // 新增：转发前改写路径或重定向
+}
+
+// 输出调试信息时隐藏上游代理密码
+impl fmt::Debug for ProxySettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxySettings")
+            .field("proxy_type", &self.proxy_type)
+            .field("http_proxy", &self.http_proxy)
+            .field("https_proxy", &self.https_proxy)
+            .field("socks5_proxy", &self.socks5_proxy)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("enabled", &self.enabled)
+            .field("direct_domains", &self.direct_domains)
+            .field("lan_cidrs", &self.lan_cidrs)
+            .field("resolve_local_hostnames", &self.resolve_local_hostnames)
+            .field("proxy_domains", &self.proxy_domains)
+            .field("default_route", &self.default_route)
+            .field("subscriptions", &self.subscriptions)
+            .field("conditional_rules", &self.conditional_rules)
+            .field("profiles", &self.profiles)
+            .field("listen", &self.listen)
+            .field("inbound_auth", &self.inbound_auth)
+            .field("limits", &self.limits)
+            .field("timeouts", &self.timeouts)
+            .field("bandwidth", &self.bandwidth)
+            .field("access_log", &self.access_log)
+            .field("har", &self.har)
+            .field("tls_intercept", &self.tls_intercept)
+            .field("header_rules", &self.header_rules)
+            .field("url_rewrite", &self.url_rewrite)
+            .finish()
+    }
+}
+
+impl ProxySettings {
+    // 写入日志的设置摘要：日志会进入内存缓冲区并推送到前端，不输出代理地址和密码
+    pub fn summary(&self) -> String {
+        format!(
+            "启用={}, 类型={:?}（{} 条直连规则, {} 条代理规则）",
+            self.enabled,
+            self.proxy_type,
+            self.direct_domains.len(),
+            self.proxy_domains.len()
+        )
+    }
+}
+
+// 新增：分流动作
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
+pub enum RouteAction {
+    #[default]
+    Proxy, // 走代理
+    Direct, // 直连
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -58,12 +176,29 @@ impl Default for ProxySettings {
             password: None,
             enabled: true,
             direct_domains: vec![],
+            lan_cidrs: vec![],
+            resolve_local_hostnames: false,
+            proxy_domains: vec![],
+            default_route: RouteAction::Proxy,
+            subscriptions: vec![],
+            conditional_rules: vec![],
+            profiles: vec![],
+            listen: ListenSettings::default(),
+            inbound_auth: InboundAuth::default(),
+            limits: ConnectionLimits::default(),
+            timeouts: TimeoutSettings::default(),
+            bandwidth: BandwidthSettings::default(),
+            access_log: AccessLogSettings::default(),
+            har: HarSettings::default(),
+            tls_intercept: TlsInterceptSettings::default(),
+            header_rules: HeaderRuleSettings::default(),
+            url_rewrite: UrlRewriteSettings::default(),
         }
     }
 }
 
-// 新增：获取配置文件路径
-fn get_config_file_path() -> Result<PathBuf, String> {
+// 新增：获取配置目录，不存在时创建
+fn get_config_dir() -> Result<PathBuf, String> {
     let app_data_dir = if cfg!(target_os = "windows") {
         std::env::var("APPDATA")
             .map_err(|_| "无法获取APPDATA环境变量".to_string())?
@@ -80,7 +215,12 @@ fn get_config_file_path() -> Result<PathBuf, String> {
             .map_err(|e| format!("创建配置目录失败: {}", e))?;
     }
     
-    Ok(config_dir.join(CONFIG_FILE_NAME))
+    Ok(config_dir)
+}
+
+// 新增：获取配置文件路径
+fn get_config_file_path() -> Result<PathBuf, String> {
+    Ok(get_config_dir()?.join(CONFIG_FILE_NAME))
 }
 
 // 新增：保存配置到文件
@@ -93,7 +233,7 @@ pub fn save_settings_to_file(settings: &ProxySettings) -> Result<(), String> {
     fs::write(&config_path, json_data)
         .map_err(|e| format!("写入配置文件失败: {}", e))?;
     
-    println!("[proxy] ✅ 配置已保存到: {:?}", config_path);
+    info!("配置已保存到: {:?}", config_path);
     Ok(())
 }
 
@@ -102,27 +242,39 @@ pub fn load_settings_from_file() -> Result<ProxySettings, String> {
     let config_path = get_config_file_path()?;
     
     if !config_path.exists() {
-        println!("[proxy] 配置文件不存在，使用默认设置: {:?}", config_path);
+        info!("配置文件不存在，使用默认设置: {:?}", config_path);
         return Ok(ProxySettings::default());
     }
     
     let json_data = fs::read_to_string(&config_path)
         .map_err(|e| format!("读取配置文件失败: {}", e))?;
     
-    let settings: ProxySettings = serde_json::from_str(&json_data)
+    let mut settings: ProxySettings = serde_json::from_str(&json_data)
         .map_err(|e| format!("解析配置文件失败: {}", e))?;
+    rule_subscription::load_cache(
+        &mut settings,
+        &get_config_dir()?.join(SUBSCRIPTION_CACHE_FILE_NAME),
+    );
     
-    println!("[proxy] ✅ 配置已从文件加载: {:?}", config_path);
+    info!("配置已从文件加载: {:?}", config_path);
     Ok(settings)
 }
 
+// 保存订阅下载的规则，与代理设置分开存放
+pub fn save_subscription_cache(settings: &ProxySettings) -> Result<(), String> {
+    rule_subscription::save_cache(
+        settings,
+        &get_config_dir()?.join(SUBSCRIPTION_CACHE_FILE_NAME),
+    )
+}
+
 // 获取系统代理设置
 #[derive(Debug)]
 struct SystemProxyConfig {
     enabled: bool,
     http_proxy: Option<String>,
     https_proxy: Option<String>,
-    bypass_list: Vec<String>,
+    bypass_list: BypassList,
     #[allow(dead_code)] // 允许未使用的字段
     pac_url: Option<String>, // 保留用于未来实现 PAC（代理自动配置）功能
 }
@@ -133,7 +285,7 @@ impl Default for SystemProxyConfig {
             enabled: false,
             http_proxy: None,
             https_proxy: None,
-            bypass_list: Vec::new(),
+            bypass_list: BypassList::default(),
             pac_url: None,
         }
     }
@@ -152,7 +304,25 @@ fn get_system_proxy_config() -> SystemProxyConfig {
         config.enabled = true;
     }
     if let Ok(no_proxy) = std::env::var("no_proxy").or_else(|_| std::env::var("NO_PROXY")) {
-        config.bypass_list = no_proxy.split(',').map(|s| s.to_string()).collect();
+        config.bypass_list = BypassList::parse(&no_proxy);
+    }
+
+    // Linux 桌面环境（GNOME/KDE/XFCE）代理设置，环境变量未设置时使用
+    #[cfg(target_os = "linux")]
+    {
+        if !config.enabled {
+            let info = crate::read_system_proxy::get_system_proxy();
+            if info.proxy_enabled {
+                if !info.http_proxy.is_empty() {
+                    config.http_proxy = Some(info.http_proxy);
+                }
+                if !info.https_proxy.is_empty() {
+                    config.https_proxy = Some(info.https_proxy);
+                }
+                config.bypass_list = BypassList::parse(&info.no_proxy);
+                config.enabled = config.http_proxy.is_some() || config.https_proxy.is_some();
+            }
+        }
     }
 
     // Windows 系统代理设置
@@ -198,7 +368,7 @@ fn get_system_proxy_config() -> SystemProxyConfig {
                 if let Ok(proxy_override) =
                     internet_settings.get_value::<String, _>("ProxyOverride")
                 {
-                    config.bypass_list = proxy_override.split(';').map(|s| s.to_string()).collect();
+                    config.bypass_list = BypassList::parse(&proxy_override);
                 }
 
                 // 读取自动配置脚本URL
@@ -215,85 +385,202 @@ fn get_system_proxy_config() -> SystemProxyConfig {
 }
 
 // 检查是否应该绕过代理
-fn should_bypass_proxy(host: &str, config: &SystemProxyConfig) -> bool {
+fn should_bypass_proxy(host: &str, port: Option<u16>, config: &SystemProxyConfig) -> bool {
     if !config.enabled {
         return true;
     }
 
-    for bypass in &config.bypass_list {
-        if bypass == "<local>" && (host.contains("localhost") || host.contains("127.0.0.1")) {
-            return true;
+    config.bypass_list.matches(host, port)
+}
+
+// 一组直连/代理规则，同一组内直连规则作为例外优先于代理规则
+#[derive(Debug, Default)]
+pub struct RuleLayer {
+    pub direct: CompiledRules,
+    pub proxy: CompiledRules,
+}
+
+impl RuleLayer {
+    fn compile(direct: &[String], proxy: &[String]) -> Self {
+        Self {
+            direct: CompiledRules::compile(direct),
+            proxy: CompiledRules::compile(proxy),
+        }
+    }
+
+    fn find(&self, host: &str) -> Option<(RouteAction, RouteReason, &str)> {
+        if let Some(rule) = self.direct.find(host) {
+            return Some((RouteAction::Direct, RouteReason::DirectRule, rule));
         }
-        if let Some(pattern) = bypass.strip_prefix('*') {
-            if host.ends_with(pattern) {
-                return true;
+        self.proxy
+            .find(host)
+            .map(|rule| (RouteAction::Proxy, RouteReason::ProxyRule, rule))
+    }
+}
+
+// 预编译的分流规则，设置变化时重新编译并整体替换
+// 优先级：生效的条件规则 > 生效方案的规则 > 用户和订阅的直连规则 > 局域网地址 > 用户和订阅的代理规则 > 默认动作
+// 条件规则和方案规则在所有代理类型下都生效，其余规则只在手动代理模式下使用
+#[derive(Debug, Default)]
+pub struct RoutingRules {
+    pub conditional: RuleLayer,     // 当前生效的条件规则
+    pub profile: RuleLayer,         // 当前生效方案的规则
+    pub direct: CompiledRules,      // 直连域名/IP 规则（用户规则在前，订阅规则在后）
+    pub proxy: CompiledRules,       // 强制代理域名/IP 规则（用户规则在前，订阅规则在后）
+    pub lan_cidrs: Vec<IpCidr>,     // 额外的局域网网段
+    pub default_route: RouteAction, // 未命中规则时的动作（可被生效的方案覆盖）
+}
+
+impl RoutingRules {
+    pub fn compile(settings: &ProxySettings, active: &ActiveConditions) -> Self {
+        let mut conditional_direct = Vec::new();
+        let mut conditional_proxy = Vec::new();
+        for rule in active
+            .rules
+            .iter()
+            .filter_map(|&i| settings.conditional_rules.get(i))
+        {
+            match rule.action {
+                RouteAction::Direct => conditional_direct.push(rule.rule.clone()),
+                RouteAction::Proxy => conditional_proxy.push(rule.rule.clone()),
             }
-        } else if host == bypass {
-            return true;
+        }
+
+        let profile = settings
+            .profiles
+            .iter()
+            .find(|p| Some(&p.name) == active.profile.as_ref());
+        let profile_rules = match profile {
+            Some(profile) => RuleLayer::compile(&profile.direct_domains, &profile.proxy_domains),
+            None => RuleLayer::default(),
+        };
+        let default_route = profile
+            .and_then(|p| p.default_route)
+            .unwrap_or(settings.default_route);
+
+        let mut direct_domains = settings.direct_domains.clone();
+        let mut proxy_domains = settings.proxy_domains.clone();
+        for sub in settings.subscriptions.iter().filter(|s| s.enabled) {
+            direct_domains.extend(sub.rules.direct_domains.iter().cloned());
+            proxy_domains.extend(sub.rules.proxy_domains.iter().cloned());
+        }
+
+        Self {
+            conditional: RuleLayer::compile(&conditional_direct, &conditional_proxy),
+            profile: profile_rules,
+            direct: CompiledRules::compile(&direct_domains),
+            proxy: CompiledRules::compile(&proxy_domains),
+            lan_cidrs: settings
+                .lan_cidrs
+                .iter()
+                .filter_map(|cidr| IpCidr::parse(cidr))
+                .collect(),
+            default_route,
         }
     }
-    false
 }
 
-// 智能分流：检查是否应该直连
-fn should_direct_connect(target: &str, settings: &ProxySettings) -> bool {
+// 分流规则的匹配结果
+#[derive(Debug, Clone, PartialEq)]
+struct RuleMatch {
+    action: RouteAction,
+    reason: RouteReason,
+    rule: Option<String>, // 命中的规则原文
+}
+
+// 智能分流：按规则决定直连还是走代理
+fn match_routing_rules(target: &str, settings: &ProxySettings, rules: &RoutingRules) -> RuleMatch {
+    let host = extract_host(target);
+    let matched = |action, reason, rule: Option<&str>| RuleMatch {
+        action,
+        reason,
+        rule: rule.map(|r| r.to_string()),
+    };
+
+    // 生效的条件规则和方案规则优先，覆盖静态规则
+    for layer in [&rules.conditional, &rules.profile] {
+        if let Some((action, reason, rule)) = layer.find(&host) {
+            return matched(action, reason, Some(rule));
+        }
+    }
+
     // 只在手动代理模式下检查 direct_domains
     if settings.proxy_type != ProxyType::Manual {
         // 只允许局域网/localhost 直连
-        let host = extract_host(target);
-        return is_local_address(&host);
+        if is_local_address(&host, settings, rules) {
+            return matched(RouteAction::Direct, RouteReason::LocalAddress, None);
+        }
+        return matched(RouteAction::Proxy, RouteReason::ProxyType, None);
     }
 
     // 手动代理模式下，才检查 direct_domains
-    let host = extract_host(target);
-    let host_lower = host.to_lowercase();
-
-    for domain in &settings.direct_domains {
-        let domain_lower = domain.to_lowercase();
-        if host_lower == domain_lower || host_lower.ends_with(&format!(".{}", domain_lower)) {
-            println!(
-                "[proxy] 手动代理直连命中 - 域名: {}, 匹配规则: {}",
-                host_lower, domain
-            );
-            return true;
-        }
+    if let Some(rule) = rules.direct.find(&host) {
+        return matched(RouteAction::Direct, RouteReason::DirectRule, Some(rule));
     }
 
     // 局域网/localhost 也允许直连
-    is_local_address(&host_lower)
+    if is_local_address(&host, settings, rules) {
+        return matched(RouteAction::Direct, RouteReason::LocalAddress, None);
+    }
+
+    // 强制代理规则优先于默认动作（直连规则作为例外优先级更高）
+    if let Some(rule) = rules.proxy.find(&host) {
+        return matched(RouteAction::Proxy, RouteReason::ProxyRule, Some(rule));
+    }
+
+    matched(rules.default_route, RouteReason::DefaultRoute, None)
 }
 
-// 提取主机名的辅助函数
+// 提取主机名的辅助函数（支持 "[::1]:443" 形式的 IPv6 地址）
 fn extract_host(target: &str) -> String {
-    let host = if target.starts_with("http://") {
-        &target[7..]
-    } else if target.starts_with("https://") {
-        &target[8..]
-    } else {
-        target
-    };
-    host.split(':')
-        .next()
-        .unwrap_or(host)
-        .split('/')
-        .next()
-        .unwrap_or(host)
-        .to_string()
+    let rest = target.split_once("://").map_or(target, |(_, rest)| rest);
+    let authority = rest.split('/').next().unwrap_or(rest);
+    if let Some(v6) = authority.strip_prefix('[') {
+        return v6.split(']').next().unwrap_or(v6).to_string();
+    }
+    authority.split(':').next().unwrap_or(authority).to_string()
+}
+
+// 检查 IP 是否属于本机、私有网络或链路本地地址
+fn is_private_ip(ip: &IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            v4.is_loopback() // 127.0.0.0/8
+                || v4.is_private() // 10.0.0.0/8、172.16.0.0/12、192.168.0.0/16
+                || v4.is_link_local() // 169.254.0.0/16
+                || v4.is_unspecified()
+        }
+        IpAddr::V6(v6) => {
+            let first_segment = v6.segments()[0];
+            v6.is_loopback() // ::1
+                || v6.is_unspecified()
+                || (first_segment & 0xfe00) == 0xfc00 // fc00::/7 ULA
+                || (first_segment & 0xffc0) == 0xfe80 // fe80::/10 链路本地
+        }
+    }
 }
 
 // 检查是否是局域网地址
-fn is_local_address(host: &str) -> bool {
-    if host.starts_with("192.168.")
-        || host.starts_with("10.")
-        || (host.starts_with("172.") && host.len() > 4)
-    {
+fn is_local_address(host: &str, settings: &ProxySettings, rules: &RoutingRules) -> bool {
+    let host = normalize_host(host);
+    if host == "localhost" || host.ends_with(".localhost") {
         return true;
     }
 
-    // 检查 172.16.0.0/12 范围
-    if let Some(third_dot) = host.find('.').and_then(|i| host[i + 1..].find('.')) {
-        if let Ok(second_octet) = host[4..4 + third_dot].parse::<u8>() {
-            if host.starts_with("172.") && second_octet >= 16 && second_octet <= 31 {
+    // 用户配置的额外局域网网段，如 100.64.0.0/10
+    let is_local_ip =
+        |ip: &IpAddr| is_private_ip(ip) || rules.lan_cidrs.iter().any(|c| c.contains(ip));
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return is_local_ip(&ip);
+    }
+
+    // 可选：解析主机名，所有地址都属于局域网时才视为本地
+    if settings.resolve_local_hostnames {
+        if let Ok(addrs) = (host.as_str(), 0).to_socket_addrs() {
+            let ips: Vec<IpAddr> = addrs.map(|addr| addr.ip()).collect();
+            if !ips.is_empty() && ips.iter().all(is_local_ip) {
+                debug!("主机名解析为局域网地址: {} -> {:?}", host, ips);
                 return true;
             }
         }
@@ -302,35 +589,235 @@ fn is_local_address(host: &str) -> bool {
     false
 }
 
-// 获取系统HTTP代理设置
-fn get_system_http_proxy() -> Option<String> {
-    let config = get_system_proxy_config();
-    if config.enabled {
-        config.http_proxy
-    } else {
-        None
+pub struct ProxyServer {
+    pub port: u16,
+    context: Arc<ProxyContext>, // 新增：连接处理共享的上下文
+    accept_threads: Vec<JoinHandle<()>>,
+}
+
+// 停止代理的结果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StopReport {
+    pub drained: usize, // 在截止时间内正常结束的连接数
+    pub aborted: usize, // 超时后被强制关闭的连接数
+}
+
+// 连接处理共享的上下文
+pub struct ProxyContext {
+    pub snapshot: ArcSwap<ProxySnapshot>, // 当前配置快照，整体原子替换
+    pub loop_guard: LoopGuard,            // 循环代理检测
+    pub rule_stats: Arc<RuleStats>,       // 规则命中统计，重启代理时保留
+    pub traffic: Arc<TrafficStats>,       // 流量统计，重启代理时保留
+    pub connections: Arc<ConnectionTracker>, // 进行中的客户端连接
+    pub limiter: Arc<ConnectionLimiter>,  // 并发连接计数和排队
+    pub throttle: Arc<Throttle>,          // 带宽限制
+    pub access_log: Arc<AccessLog>,       // 访问日志，重启代理时保留
+    pub har: Arc<HarRecorder>,            // 抓包记录，重启代理时保留
+    pub tls: Arc<TlsInterceptor>,         // HTTPS 解密，本地 CA 在重启代理时保留
+    stopping: AtomicBool,                 // 已请求停止，接受连接的线程退出
+    system_proxy: ArcSwap<SystemProxyConfig>, // 缓存的系统代理设置，网络变化或设置更新时重新读取
+}
+
+// 跨代理重启保留的统计数据、访问日志、抓包记录和本地 CA
+#[derive(Clone, Default)]
+pub struct SessionStats {
+    pub rule_stats: Arc<RuleStats>,
+    pub traffic: Arc<TrafficStats>,
+    pub access_log: Arc<AccessLog>,
+    pub har: Arc<HarRecorder>,
+    pub tls: Arc<TlsInterceptor>,
+}
+
+impl SessionStats {
+    // 流量历史按天保存在配置目录中，访问日志写入配置目录下的 logs 目录，本地 CA 保存在 ca 目录
+    fn persistent() -> Self {
+        let config_dir = get_config_dir()
+            .map_err(|e| warn!("无法保存流量历史和访问日志: {}", e))
+            .ok();
+        Self {
+            rule_stats: Arc::default(),
+            traffic: Arc::new(TrafficStats::new(
+                config_dir
+                    .as_ref()
+                    .map(|dir| dir.join(TRAFFIC_HISTORY_FILE_NAME)),
+            )),
+            access_log: Arc::new(AccessLog::new(
+                config_dir.as_ref().map(|dir| dir.join(ACCESS_LOG_DIR_NAME)),
+            )),
+            har: Arc::default(),
+            tls: Arc::new(TlsInterceptor::new(
+                config_dir.map(|dir| dir.join(CA_DIR_NAME)),
+            )),
+        }
     }
 }
 
-// 获取系统HTTPS代理设置
-fn get_system_https_proxy() -> Option<String> {
-    let config = get_system_proxy_config();
-    if config.enabled {
-        config.https_proxy.or(config.http_proxy)
-    } else {
-        None
+// 不可变的配置快照：代理设置及其预编译的分流规则
+// 每个连接开始时取一次快照，设置更新不会阻塞或影响进行中的连接
+#[derive(Debug, Default)]
+pub struct ProxySnapshot {
+    pub settings: ProxySettings,
+    pub rules: RoutingRules,
+    pub active: ActiveConditions, // 编译规则时生效的方案和条件规则
+    pub client_acl: ClientAcl,    // 允许访问本地代理的客户端
+    pub headers: HeaderRules,     // 转发请求时执行的请求头规则
+    pub rewrites: UrlRewrites,    // 转发请求前执行的 URL 改写规则
+}
+
+impl ProxySnapshot {
+    pub fn new(settings: ProxySettings) -> Self {
+        let env = ConditionEnv::current(&settings);
+        let active = ActiveConditions::evaluate(&settings, &env);
+        Self::with_active(settings, active)
+    }
+
+    fn with_active(settings: ProxySettings, active: ActiveConditions) -> Self {
+        let rules = RoutingRules::compile(&settings, &active);
+        let client_acl = ClientAcl::new(&settings.listen);
+        let headers = HeaderRules::compile(&settings.header_rules);
+        let rewrites = UrlRewrites::compile(&settings.url_rewrite);
+        Self {
+            settings,
+            rules,
+            active,
+            client_acl,
+            headers,
+            rewrites,
+        }
     }
 }
 
-pub struct ProxyServer {
-    pub port: u16,
-    pub settings: Arc<Mutex<ProxySettings>>, // 新增：代理配置
+impl ProxyContext {
+    pub fn new(
+        settings: ProxySettings,
+        listen_addrs: Vec<SocketAddr>,
+        stats: SessionStats,
+    ) -> Self {
+        let context = Self {
+            snapshot: ArcSwap::from_pointee(ProxySnapshot::new(settings)),
+            loop_guard: LoopGuard::new(listen_addrs),
+            rule_stats: stats.rule_stats,
+            traffic: stats.traffic,
+            access_log: stats.access_log,
+            har: stats.har,
+            tls: stats.tls,
+            connections: Arc::new(ConnectionTracker::default()),
+            limiter: Arc::new(ConnectionLimiter::default()),
+            throttle: Arc::new(Throttle::default()),
+            stopping: AtomicBool::new(false),
+            system_proxy: ArcSwap::from_pointee(SystemProxyConfig::default()),
+        };
+        context.reload_system_proxy();
+        context.apply_settings();
+        context
+    }
+
+    // 将快照中需要单独保存的设置同步到运行时状态
+    fn apply_settings(&self) {
+        let snapshot = self.snapshot.load();
+        self.throttle.configure(&snapshot.settings.bandwidth);
+        self.access_log.configure(&snapshot.settings.access_log);
+        self.har.configure(&snapshot.settings.har);
+        self.tls.configure(&snapshot.settings.tls_intercept);
+    }
+
+    // 重新读取系统代理设置（仅系统代理模式下需要）
+    pub fn reload_system_proxy(&self) {
+        if self.snapshot.load().settings.proxy_type != ProxyType::System {
+            return;
+        }
+        let config = get_system_proxy_config();
+        let previous = self.system_proxy.load();
+        if previous.http_proxy != config.http_proxy || previous.https_proxy != config.https_proxy {
+            info!(
+                "已重新读取系统代理: http={:?}, https={:?}",
+                config.http_proxy, config.https_proxy
+            );
+        } else {
+            debug!("系统代理未变化");
+        }
+        self.system_proxy.store(Arc::new(config));
+    }
+
+    // 重新判断时间/网络条件，生效的方案或条件规则变化时重新编译并替换快照
+    // 返回变化后的生效条件，未变化时返回 None
+    pub fn refresh_conditions(&self, network: &NetworkEnv) -> Option<ActiveConditions> {
+        let current = self.snapshot.load_full();
+        let env = ConditionEnv::now(network.clone());
+        let active = ActiveConditions::evaluate(&current.settings, &env);
+        if active == current.active {
+            return None;
+        }
+
+        info!(
+            "生效条件变化: 方案 {:?} -> {:?}, 条件规则 {:?} -> {:?}",
+            current.active.profile, active.profile, current.active.rules, active.rules
+        );
+        let snapshot = ProxySnapshot::with_active(current.settings.clone(), active.clone());
+        // 设置在此期间被更新过时放弃本次替换，新设置已按最新条件编译
+        let previous = self.snapshot.compare_and_swap(&current, Arc::new(snapshot));
+        if Arc::ptr_eq(&*previous, &current) {
+            Some(active)
+        } else {
+            None
+        }
+    }
+}
+
+// 循环代理检测：记录本地代理实际监听的地址
+#[derive(Debug, Clone, Default)]
+pub struct LoopGuard {
+    listen_addrs: Vec<SocketAddr>,
+}
+
+impl LoopGuard {
+    pub fn new(listen_addrs: Vec<SocketAddr>) -> Self {
+        Self { listen_addrs }
+    }
+
+    pub fn listen_addrs(&self) -> &[SocketAddr] {
+        &self.listen_addrs
+    }
+
+    // 检查解析后的地址是否指向本地代理自身
+    pub fn is_self_addr(&self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.listen_addrs.iter().any(|listen| {
+            listen.port() == addr.port()
+                && (listen.ip() == ip
+                    || (listen.ip().is_unspecified() && is_own_ip(&ip))
+                    || (ip.is_unspecified() && listen.ip().is_loopback()))
+        })
+    }
+
+    // 解析 "host:port" 并检查是否指向本地代理自身
+    pub fn is_self_target(&self, target: &str) -> bool {
+        match target.to_socket_addrs() {
+            Ok(mut addrs) => addrs.any(|addr| self.is_self_addr(&addr)),
+            Err(_) => false,
+        }
+    }
+}
+
+// 检查 IP 是否属于本机（能绑定的地址即为本机网卡地址）
+fn is_own_ip(ip: &IpAddr) -> bool {
+    ip.is_loopback() || ip.is_unspecified() || UdpSocket::bind(SocketAddr::new(*ip, 0)).is_ok()
+}
+
+// 提取代理地址中的 "host:port" 部分（去掉协议、认证信息和路径）
+pub fn proxy_authority(proxy: &str) -> &str {
+    let rest = proxy.split_once("://").map_or(proxy, |(_, rest)| rest);
+    let rest = rest.split('/').next().unwrap_or(rest);
+    rest.rsplit_once('@').map_or(rest, |(_, host)| host)
 }
 
-// 强制直连函数，绕过系统代理
-fn direct_connect(addr: &str) -> std::io::Result<TcpStream> {
-    println!("[proxy] 尝试直连到: {}", addr);
+// 检查上游代理是否指向本地代理自身
+fn upstream_points_to_self(proxy: &str, loop_guard: &LoopGuard) -> bool {
+    loop_guard.is_self_target(proxy_authority(proxy))
+}
 
+// 解析直连目标的地址，目标指向本地代理自身时拒绝
+fn resolve_direct(addr: &str, loop_guard: &LoopGuard) -> std::io::Result<Vec<SocketAddr>> {
     let socket_addrs: Vec<_> = addr.to_socket_addrs()?.collect();
     if socket_addrs.is_empty() {
         return Err(std::io::Error::new(
@@ -339,14 +826,33 @@ fn direct_connect(addr: &str) -> std::io::Result<TcpStream> {
         ));
     }
 
+    // 防止循环代理：目标解析后指向本地代理自身时拒绝连接
+    if let Some(self_addr) = socket_addrs.iter().find(|a| loop_guard.is_self_addr(a)) {
+        warn!("检测到循环代理，拒绝连接: {} -> {}", addr, self_addr);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            "检测到循环代理",
+        ));
+    }
+    Ok(socket_addrs)
+}
+
+// 强制直连函数，绕过系统代理；依次尝试已解析的地址
+fn direct_connect(
+    addr: &str,
+    socket_addrs: Vec<SocketAddr>,
+    connect_timeout: Option<Duration>,
+) -> std::io::Result<TcpStream> {
+    debug!("尝试直连到: {}", addr);
+
     for socket_addr in socket_addrs {
-        match TcpStream::connect_timeout(&socket_addr, Duration::from_secs(TIMEOUT)) {
+        match connect_addr(&socket_addr, connect_timeout) {
             Ok(stream) => {
-                println!("[proxy] ✅ 直连成功: {} -> {}", addr, socket_addr);
+                debug!("直连成功: {} -> {}", addr, socket_addr);
                 return Ok(stream);
             }
             Err(e) => {
-                println!("[proxy] ❌ 直连失败: {} -> {} ({})", addr, socket_addr, e);
+                debug!("直连失败: {} -> {} ({})", addr, socket_addr, e);
                 continue;
             }
         }
@@ -358,134 +864,403 @@ fn direct_connect(addr: &str) -> std::io::Result<TcpStream> {
     ))
 }
 
-// 新增：根据配置选择连接方式
-fn connect_with_proxy_settings(
-    target: &str,
-    settings: &ProxySettings,
-) -> std::io::Result<TcpStream> {
-    // 首先检查代理是否启用
-    if !settings.enabled {
-        println!("[proxy] 代理已禁用，强制直连: {}", target);
-        return direct_connect(target);
+fn connect_addr(addr: &SocketAddr, timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+    match timeout {
+        Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+        None => TcpStream::connect(addr),
+    }
+}
+
+// 连接上游代理服务器，依次尝试解析出的地址，握手阶段使用握手超时
+fn connect_upstream(proxy: &str, timeouts: &TimeoutSettings) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+    for socket_addr in proxy_authority(proxy).to_socket_addrs()? {
+        match connect_addr(&socket_addr, timeouts.connect()) {
+            Ok(stream) => {
+                stream.set_read_timeout(timeouts.handshake())?;
+                stream.set_write_timeout(timeouts.handshake())?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "无法解析代理地址")
+    }))
+}
+
+// 路由决策的原因
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, Hash)]
+pub enum RouteReason {
+    ProxyDisabled,   // 代理已禁用
+    LocalAddress,    // 局域网/localhost 地址
+    DirectRule,      // 命中直连规则
+    ProxyRule,       // 命中强制代理规则
+    DefaultRoute,    // 未命中规则，使用默认动作
+    ProxyType,       // 按代理类型转发
+    SystemBypass,    // 命中系统代理绕过列表
+    UpstreamMissing, // 未配置上游代理
+    UpstreamIsSelf,  // 上游代理指向本地代理自身
+}
+
+impl RouteReason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            RouteReason::ProxyDisabled => "代理已禁用",
+            RouteReason::LocalAddress => "局域网地址",
+            RouteReason::DirectRule => "命中直连规则",
+            RouteReason::ProxyRule => "命中强制代理规则",
+            RouteReason::DefaultRoute => "默认动作",
+            RouteReason::ProxyType => "代理类型",
+            RouteReason::SystemBypass => "系统代理绕过列表",
+            RouteReason::UpstreamMissing => "未配置上游代理",
+            RouteReason::UpstreamIsSelf => "上游代理指向自己",
+        }
+    }
+}
+
+// 最终的连接方式
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "type", content = "proxy")]
+pub enum Route {
+    Direct,
+    Http(String),   // 通过 HTTP 代理 CONNECT
+    Socks5(String), // 通过 SOCKS5 代理
+    Unavailable,    // 系统代理未设置，无法连接
+}
+
+impl Route {
+    // 用于流量统计的上游名称，不含认证信息
+    pub fn label(&self) -> String {
+        match self {
+            Route::Direct => "direct".to_string(),
+            Route::Http(proxy) => format!("http://{}", proxy_authority(proxy)),
+            Route::Socks5(proxy) => format!("socks5://{}", proxy_authority(proxy)),
+            Route::Unavailable => "unavailable".to_string(),
+        }
+    }
+}
+
+// 一次路由决策：连接方式以及做出该决定的规则
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteDecision {
+    pub route: Route,
+    pub reason: RouteReason,
+    pub rule: Option<String>,
+}
+
+impl RouteDecision {
+    fn new(route: Route, reason: RouteReason, rule: Option<String>) -> Self {
+        Self {
+            route,
+            reason,
+            rule,
+        }
+    }
+
+    // 选定上游代理；上游指向本地代理自身时改为直连，防止循环
+    fn upstream(proxy: &str, route: Route, loop_guard: &LoopGuard, matched: RuleMatch) -> Self {
+        if upstream_points_to_self(proxy, loop_guard) {
+            return Self::new(Route::Direct, RouteReason::UpstreamIsSelf, None);
+        }
+        Self::new(route, matched.reason, matched.rule)
     }
+}
 
-    // 防止循环代理：如果目标是本地代理端口，直接连接
-    if target.contains("127.0.0.1:8080") || target.contains("localhost:8080") {
-        println!("[proxy] 检测到循环代理，改为直连: {}", target);
-        return direct_connect(target);
+// 根据配置决定连接方式（不发起连接），连接和路由解释共用同一套判断
+fn decide_route(target: &str, snapshot: &ProxySnapshot, ctx: &ProxyContext) -> RouteDecision {
+    let settings = &snapshot.settings;
+    let loop_guard = &ctx.loop_guard;
+
+    // 首先检查代理是否启用
+    if !settings.enabled {
+        return RouteDecision::new(Route::Direct, RouteReason::ProxyDisabled, None);
     }
 
     // 智能分流：检查是否应该直连
-    if should_direct_connect(target, settings) {
-        return direct_connect(target);
+    let matched = match_routing_rules(target, settings, &snapshot.rules);
+    if matched.action == RouteAction::Direct {
+        return RouteDecision::new(Route::Direct, matched.reason, matched.rule);
     }
 
+    let missing = || RouteDecision::new(Route::Direct, RouteReason::UpstreamMissing, None);
+
     match &settings.proxy_type {
-        ProxyType::None => direct_connect(target),
+        ProxyType::None => RouteDecision::new(Route::Direct, RouteReason::ProxyType, None),
         ProxyType::System => {
-            let config = get_system_proxy_config();
+            let config = ctx.system_proxy.load();
 
-            // 解析目标主机名
-            let host = target.split(':').next().unwrap_or(target);
+            // 解析目标主机名和端口
+            let (host, port) = match parse_target(target) {
+                Ok((host, port)) => (host, Some(port)),
+                Err(_) => (target.to_string(), None),
+            };
 
             // 检查是否应该绕过代理
-            if should_bypass_proxy(host, &config) {
-                println!("[proxy] 目标在代理绕过列表中，直连: {}", target);
-                return direct_connect(target);
+            if should_bypass_proxy(&host, port, &config) {
+                return RouteDecision::new(Route::Direct, RouteReason::SystemBypass, None);
             }
 
             // 根据目标协议选择代理
             let proxy = if target.starts_with("https://") {
-                get_system_https_proxy()
+                config.https_proxy.clone().or(config.http_proxy.clone())
             } else {
-                get_system_http_proxy()
+                config.http_proxy.clone()
             };
 
-            if let Some(proxy) = proxy {
-                // 检查系统代理是否指向自己
-                if proxy.contains("127.0.0.1:8080") || proxy.contains("localhost:8080") {
-                    println!("[proxy] 系统代理指向自己，改为直连: {}", target);
-                    direct_connect(target)
-                } else {
-                    proxy_connect(target, &proxy)
+            match proxy {
+                Some(proxy) => {
+                    RouteDecision::upstream(&proxy, Route::Http(proxy.clone()), loop_guard, matched)
                 }
-            } else {
-                println!("[proxy] 系统代理未设置，返回错误");
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    "系统代理未设置",
-                ))
+                None => RouteDecision::new(Route::Unavailable, RouteReason::UpstreamMissing, None),
             }
         }
-        ProxyType::Http => {
-            if let Some(proxy) = &settings.http_proxy {
-                // 检查HTTP代理是否指向自己
-                if proxy.contains("127.0.0.1:8080") || proxy.contains("localhost:8080") {
-                    println!("[proxy] HTTP代理指向自己，改为直连: {}", target);
-                    direct_connect(target)
-                } else {
-                    proxy_connect(target, proxy)
-                }
-            } else {
-                direct_connect(target)
+        ProxyType::Http => match &settings.http_proxy {
+            Some(proxy) => {
+                RouteDecision::upstream(proxy, Route::Http(proxy.clone()), loop_guard, matched)
             }
-        }
-        ProxyType::Https => {
-            if let Some(proxy) = &settings.https_proxy {
-                // 检查HTTPS代理是否指向自己
-                if proxy.contains("127.0.0.1:8080") || proxy.contains("localhost:8080") {
-                    println!("[proxy] HTTPS代理指向自己，改为直连: {}", target);
-                    direct_connect(target)
-                } else {
-                    proxy_connect(target, proxy)
-                }
-            } else {
-                direct_connect(target)
+            None => missing(),
+        },
+        ProxyType::Https => match &settings.https_proxy {
+            Some(proxy) => {
+                RouteDecision::upstream(proxy, Route::Http(proxy.clone()), loop_guard, matched)
             }
-        }
-        ProxyType::Socks5 => {
-            if let Some(proxy) = &settings.socks5_proxy {
-                socks5_connect(target, proxy, &settings.username, &settings.password)
-            } else {
-                direct_connect(target)
+            None => missing(),
+        },
+        ProxyType::Socks5 => match &settings.socks5_proxy {
+            Some(proxy) => {
+                RouteDecision::upstream(proxy, Route::Socks5(proxy.clone()), loop_guard, matched)
             }
-        }
+            None => missing(),
+        },
         ProxyType::Manual => {
             // 手动模式：根据目标协议选择合适的代理
-            if target.starts_with("https://") {
-                if let Some(proxy) = &settings.https_proxy {
-                    if proxy.contains("127.0.0.1:8080") || proxy.contains("localhost:8080") {
-                        return direct_connect(target);
-                    }
-                    return proxy_connect(target, proxy);
-                }
-            }
-            if let Some(proxy) = &settings.http_proxy {
-                if proxy.contains("127.0.0.1:8080") || proxy.contains("localhost:8080") {
-                    direct_connect(target)
-                } else {
-                    proxy_connect(target, proxy)
+            let proxy = settings
+                .https_proxy
+                .as_ref()
+                .filter(|_| target.starts_with("https://"))
+                .or(settings.http_proxy.as_ref());
+            match proxy {
+                Some(proxy) => {
+                    RouteDecision::upstream(proxy, Route::Http(proxy.clone()), loop_guard, matched)
                 }
-            } else {
-                direct_connect(target)
+                None => missing(),
             }
         }
     }
 }
 
-// 修复：正确的HTTP代理连接实现
-fn proxy_connect(target: &str, proxy: &str) -> std::io::Result<TcpStream> {
-    println!("[proxy] 通过HTTP代理连接: {} -> {}", target, proxy);
+// 已建立的上游连接
+struct Upstream {
+    stream: TcpStream,
+    meter: TrafficMeter,    // 累计规则、目标主机和上游的流量
+    host_slot: HostSlot,    // 占用的目标主机并发名额，连接结束时归还
+    throttle: HostThrottle, // 目标主机的限速句柄
+}
 
-    // 解析代理地址
-    let proxy_url = if proxy.starts_with("http://") {
-        &proxy[7..]
-    } else {
-        proxy
+// 新增：根据配置选择连接方式，返回连接及命中规则的计数器
+fn connect_with_proxy_settings(
+    target: &str,
+    snapshot: &ProxySnapshot,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
+) -> std::io::Result<Upstream> {
+    let settings = &snapshot.settings;
+    let host = extract_host(target);
+    let decision = decide_route(target, snapshot, ctx);
+    match &decision.rule {
+        Some(rule) => debug!(
+            "路由决策: {} -> {:?} ({}: {})",
+            target,
+            decision.route,
+            decision.reason.describe(),
+            rule
+        ),
+        None => debug!(
+            "路由决策: {} -> {:?} ({})",
+            target,
+            decision.route,
+            decision.reason.describe()
+        ),
+    }
+
+    // 连接失败也计入命中次数
+    let action = match decision.route {
+        Route::Direct => RouteAction::Direct,
+        _ => RouteAction::Proxy,
+    };
+    let counter = ctx
+        .rule_stats
+        .record_hit(decision.reason, decision.rule.as_deref(), action);
+    let route = decision.route.label();
+    conn.set_route(&route, Some(decision.reason), decision.rule.as_deref());
+
+    // 不会发起连接的路由（上游缺失、循环代理）不占用目标主机名额
+    let direct_addrs = match &decision.route {
+        Route::Direct => resolve_direct(target, &ctx.loop_guard)?,
+        Route::Unavailable => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "系统代理未设置",
+            ))
+        }
+        _ => Vec::new(),
+    };
+
+    // 延迟注入：模拟高延迟网络；在占用目标主机名额之前等待，连接被关闭或代理停止时立即结束
+    if let Some(latency) = ctx.throttle.latency() {
+        sliced_sleep(latency, || {
+            conn.is_closed() || ctx.stopping.load(Ordering::Relaxed)
+        });
+        if conn.is_closed() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "连接已关闭",
+            ));
+        }
+    }
+
+    // 同一目标主机的连接数达到上限时排队等待
+    let host_slot = ctx
+        .limiter
+        .acquire_host(&host, &settings.limits)
+        .ok_or_else(|| {
+            warn!("目标主机连接数已达上限，拒绝连接: {}", host);
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "目标主机连接数已达上限")
+        })?;
+
+    let timeouts = &settings.timeouts;
+    let stream = match &decision.route {
+        Route::Direct => direct_connect(target, direct_addrs, timeouts.connect()),
+        Route::Http(proxy) => proxy_connect(target, proxy, timeouts),
+        Route::Socks5(proxy) => socks5_connect(
+            target,
+            proxy,
+            &settings.username,
+            &settings.password,
+            timeouts,
+        ),
+        Route::Unavailable => unreachable!("不可用的路由已在占用名额前返回"),
+    }?;
+    conn.set_tunneling();
+    let meter = ctx
+        .traffic
+        .open_connection(&host, &route, Some(counter), Some(Arc::clone(conn)));
+    Ok(Upstream {
+        stream,
+        meter,
+        host_slot,
+        throttle: ctx.throttle.for_host(&host),
+    })
+}
+
+// 路由解释：给定 URL 或 "host:port"，返回完整的决策过程，不发起连接
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteExplanation {
+    pub input: String,
+    pub target: String, // 规范化后的 "host:port"
+    pub proxy_type: ProxyType,
+    pub active: ActiveConditions, // 当前生效的方案和条件规则
+    pub decision: RouteDecision,
+    pub chain: Vec<String>,            // 连接链路：本地代理 -> 上游 -> 目标
+    pub target_addrs: Vec<String>,     // 目标解析出的地址
+    pub upstream_addrs: Vec<String>,   // 上游代理解析出的地址
+    pub resolve_error: Option<String>, // 目标解析失败的原因
+    pub loop_detected: bool,           // 直连目标指向本地代理自身，连接会被拒绝
+}
+
+// 把 URL 或 "host:port" 规范化为 "host:port"，端口缺省时按协议推断
+fn normalize_route_target(input: &str) -> Result<String, String> {
+    let input = input.trim();
+    let (scheme, rest) = match input.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+        None => (String::new(), input),
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    if authority.is_empty() {
+        return Err("目标地址为空".to_string());
+    }
+
+    let has_port = match authority.rfind(']') {
+        Some(bracket) => authority[bracket..].contains(':'),
+        None => authority.contains(':'),
     };
+    if has_port {
+        return Ok(authority.to_string());
+    }
+
+    let port = match scheme.as_str() {
+        "http" | "ws" => 80,
+        "" | "https" | "wss" => 443,
+        _ => return Err(format!("无法推断端口: {}", input)),
+    };
+    Ok(format!("{}:{}", authority, port))
+}
+
+fn resolve_addrs(addr: &str) -> Result<Vec<SocketAddr>, String> {
+    addr.to_socket_addrs()
+        .map(|addrs| addrs.collect())
+        .map_err(|e| e.to_string())
+}
+
+// 解释路由决策
+pub fn explain_route(input: &str, ctx: &ProxyContext) -> Result<RouteExplanation, String> {
+    let target = normalize_route_target(input)?;
+    let snapshot = ctx.snapshot.load_full();
+    let decision = decide_route(&target, &snapshot, ctx);
+
+    let (target_addrs, resolve_error) = match resolve_addrs(&target) {
+        Ok(addrs) => (addrs, None),
+        Err(e) => (vec![], Some(e)),
+    };
+    let loop_detected = decision.route == Route::Direct
+        && target_addrs.iter().any(|a| ctx.loop_guard.is_self_addr(a));
+
+    let listen_addrs: Vec<String> = ctx
+        .loop_guard
+        .listen_addrs
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    let mut chain = vec![format!("本地代理 {}", listen_addrs.join(", "))];
+    let mut upstream_addrs = vec![];
+    match &decision.route {
+        Route::Direct => {}
+        Route::Http(proxy) | Route::Socks5(proxy) => {
+            chain.push(proxy.clone());
+            upstream_addrs = resolve_addrs(proxy_authority(proxy)).unwrap_or_default();
+        }
+        Route::Unavailable => chain.push("系统代理未设置".to_string()),
+    }
+    chain.push(target.clone());
+
+    Ok(RouteExplanation {
+        input: input.to_string(),
+        target,
+        proxy_type: snapshot.settings.proxy_type.clone(),
+        active: snapshot.active.clone(),
+        decision,
+        chain,
+        target_addrs: target_addrs.iter().map(|a| a.to_string()).collect(),
+        upstream_addrs: upstream_addrs.iter().map(|a| a.to_string()).collect(),
+        resolve_error,
+        loop_detected,
+    })
+}
+
+// 修复：正确的HTTP代理连接实现
+fn proxy_connect(
+    target: &str,
+    proxy: &str,
+    timeouts: &TimeoutSettings,
+) -> std::io::Result<TcpStream> {
+    debug!("通过HTTP代理连接: {} -> {}", target, proxy);
 
     // 连接到代理服务器
-    let mut proxy_stream = TcpStream::connect(proxy_url)?;
+    let mut proxy_stream = connect_upstream(proxy, timeouts)?;
 
     // 发送CONNECT请求到代理服务器
     let connect_request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
@@ -497,10 +1272,10 @@ fn proxy_connect(target: &str, proxy: &str) -> std::io::Result<TcpStream> {
     let response_str = String::from_utf8_lossy(&response[..bytes_read]);
 
     if response_str.contains("200 Connection established") {
-        println!("[proxy] HTTP代理隧道建立成功: {}", target);
+        debug!("HTTP代理隧道建立成功: {}", target);
         Ok(proxy_stream)
     } else {
-        println!("[proxy] HTTP代理响应: {}", response_str.trim());
+        warn!("HTTP代理响应: {}", response_str.trim());
         Err(std::io::Error::new(
             std::io::ErrorKind::Other,
             "HTTP proxy connection failed",
@@ -514,10 +1289,11 @@ fn socks5_connect(
     proxy: &str,
     username: &Option<String>,
     password: &Option<String>,
+    timeouts: &TimeoutSettings,
 ) -> std::io::Result<TcpStream> {
-    println!("[proxy] 通过SOCKS5代理连接: {} -> {}", target, proxy);
+    debug!("通过SOCKS5代理连接: {} -> {}", target, proxy);
 
-    let mut stream = TcpStream::connect(proxy)?;
+    let mut stream = connect_upstream(proxy, timeouts)?;
 
     // SOCKS5握手
     socks5_handshake(&mut stream, username, password)?;
@@ -682,31 +1458,121 @@ fn parse_target(target: &str) -> std::io::Result<(String, u16)> {
     }
 }
 
-fn tunnel(a: TcpStream, b: TcpStream) {
-    let timeout = Duration::from_secs(TIMEOUT);
-    let _ = a.set_read_timeout(Some(timeout));
-    let _ = a.set_write_timeout(Some(timeout));
-    let _ = b.set_read_timeout(Some(timeout));
-    let _ = b.set_write_timeout(Some(timeout));
-
-    // 设置TCP_NODELAY以优化性能
-    let _ = a.set_nodelay(true);
-    let _ = b.set_nodelay(true);
+// 隧道双向的数据活动，用于判断首字节超时和空闲超时
+struct TunnelActivity {
+    started: Instant,
+    last_active_ms: AtomicU64, // 任一方向最近一次有数据的时间，相对 started 的毫秒数
+    first_byte: AtomicBool,    // 目标一侧是否已返回数据
+    timeouts: TunnelTimeouts,
+}
 
-    // 克隆流以避免所有权问题
-    let a2b = match a.try_clone() {
-        Ok(stream) => stream,
-        Err(e) => {
-            println!("[proxy] 克隆流失败: {}", e);
-            return;
+impl TunnelActivity {
+    fn new(timeouts: TunnelTimeouts) -> Self {
+        Self {
+            started: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+            first_byte: AtomicBool::new(false),
+            timeouts,
         }
-    };
+    }
 
-    let b2a = match b.try_clone() {
-        Ok(stream) => stream,
-        Err(e) => {
-            println!("[proxy] 克隆流失败: {}", e);
-            return;
+    fn touch(&self, from_target: bool) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_active_ms.store(now, Ordering::Relaxed);
+        if from_target {
+            self.first_byte.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // 超时则返回原因
+    fn expired(&self) -> Option<&'static str> {
+        let elapsed = self.started.elapsed();
+        if !self.first_byte.load(Ordering::Relaxed) {
+            if let Some(first_byte) = self.timeouts.first_byte {
+                if elapsed >= first_byte {
+                    return Some("等待首字节超时");
+                }
+            }
+        }
+        let last_active = Duration::from_millis(self.last_active_ms.load(Ordering::Relaxed));
+        match self.timeouts.idle {
+            Some(idle) if elapsed.saturating_sub(last_active) >= idle => Some("空闲超时"),
+            _ => None,
+        }
+    }
+}
+
+// 隧道一侧的连接：普通 TCP 连接或解密后的 TLS 连接
+pub trait TunnelStream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> std::io::Result<Self>;
+
+    // 底层 TCP 连接，用于设置超时等选项
+    fn socket(&self) -> &TcpStream;
+
+    // 关闭连接，使另一个线程阻塞的读取立即返回
+    fn close(&self) {
+        let _ = self.socket().shutdown(Shutdown::Both);
+    }
+}
+
+impl TunnelStream for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+// 分段等待（限速、延迟注入）：按 TUNNEL_POLL_INTERVAL 分段休眠，stopped 返回 true 时立即返回
+fn sliced_sleep(delay: Duration, stopped: impl Fn() -> bool) {
+    let deadline = Instant::now() + delay;
+    loop {
+        let now = Instant::now();
+        if now >= deadline || stopped() {
+            return;
+        }
+        thread::sleep((deadline - now).min(TUNNEL_POLL_INTERVAL));
+    }
+}
+
+// a 为客户端一侧，b 为目标一侧；meter 用于累计上下行流量
+// 两个方向都没有数据超过空闲时间才关闭，单向长时间无数据（如长轮询）不受影响
+// throttle 为目标主机的限速句柄，每次转发前按限速等待；capture 为抓包记录，转发的数据同时交给它
+fn tunnel<A: TunnelStream, B: TunnelStream>(
+    a: A,
+    b: B,
+    meter: Option<TrafficMeter>,
+    throttle: Option<HostThrottle>,
+    capture: Option<Arc<HarCapture>>,
+    timeouts: TunnelTimeouts,
+) {
+    // 读取以较短间隔超时返回，以便检查停止信号和双向的空闲时间
+    let _ = a.socket().set_read_timeout(Some(TUNNEL_POLL_INTERVAL));
+    let _ = a.socket().set_write_timeout(timeouts.idle);
+    let _ = b.socket().set_read_timeout(Some(TUNNEL_POLL_INTERVAL));
+    let _ = b.socket().set_write_timeout(timeouts.idle);
+    let activity = Arc::new(TunnelActivity::new(timeouts));
+
+    // 设置TCP_NODELAY以优化性能
+    let _ = a.socket().set_nodelay(true);
+    let _ = b.socket().set_nodelay(true);
+
+    // 克隆流以避免所有权问题
+    let a2b = match a.try_clone() {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("克隆流失败: {}", e);
+            return;
+        }
+    };
+
+    let b2a = match b.try_clone() {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("克隆流失败: {}", e);
+            return;
         }
     };
 
@@ -716,6 +1582,7 @@ fn tunnel(a: TcpStream, b: TcpStream) {
 
     // 根据连接类型选择缓冲区大小
     let buffer_size = if a2b
+        .socket()
         .peer_addr()
         .map(|addr| addr.port() == 443)
         .unwrap_or(false)
@@ -730,26 +1597,52 @@ fn tunnel(a: TcpStream, b: TcpStream) {
         let mut a2b = a2b;
         let mut b = b.try_clone().unwrap();
         let stop_signal = Arc::clone(&stop_signal);
+        let meter = meter.clone();
+        let throttle = throttle.clone();
+        let capture = capture.clone();
+        let activity = Arc::clone(&activity);
 
         thread::spawn(move || {
             let mut buf = vec![0u8; buffer_size];
             while !stop_signal.load(Ordering::Relaxed) {
-                match a2b.read(&mut buf) {
+                let limit = throttle
+                    .as_ref()
+                    .map_or(buf.len(), |t| t.chunk_size(Direction::Upload, buf.len()));
+                match a2b.read(&mut buf[..limit]) {
                     Ok(0) => break, // 连接关闭
                     Ok(n) => {
+                        activity.touch(false);
+                        if let Some(throttle) = &throttle {
+                            sliced_sleep(throttle.delay(Direction::Upload, n), || {
+                                stop_signal.load(Ordering::Relaxed)
+                            });
+                        }
                         if b.write_all(&buf[..n]).is_err() || b.flush().is_err() {
                             break;
                         }
+                        if let Some(meter) = &meter {
+                            meter.add_up(n as u64);
+                        }
+                        if let Some(capture) = &capture {
+                            capture.on_upload(&buf[..n]);
+                        }
                     }
-                    Err(e) => {
-                        if e.kind() != std::io::ErrorKind::WouldBlock {
+                    Err(e) if is_timeout(&e) => {
+                        if let Some(reason) = activity.expired() {
+                            // 两个方向会同时检测到超时，只由先停止的一方记录
+                            if !stop_signal.swap(true, Ordering::Relaxed) {
+                                debug!("隧道{}，关闭连接", reason);
+                            }
                             break;
                         }
                     }
+                    Err(_) => break,
                 }
             }
-            // 通知另一个线程也停止
+            // 通知另一个线程也停止，关闭连接使其阻塞的读取立即返回
             stop_signal.store(true, Ordering::Relaxed);
+            a2b.close();
+            b.close();
             // 显式释放缓冲区
             drop(buf);
         })
@@ -762,23 +1655,50 @@ fn tunnel(a: TcpStream, b: TcpStream) {
 
         thread::spawn(move || {
             let mut buf = vec![0u8; buffer_size];
+            let mut first = true;
             while !stop_signal.load(Ordering::Relaxed) {
-                match b2a.read(&mut buf) {
+                let limit = throttle
+                    .as_ref()
+                    .map_or(buf.len(), |t| t.chunk_size(Direction::Download, buf.len()));
+                match b2a.read(&mut buf[..limit]) {
                     Ok(0) => break, // 连接关闭
                     Ok(n) => {
+                        activity.touch(true);
+                        if let Some(throttle) = &throttle {
+                            sliced_sleep(throttle.delay(Direction::Download, n), || {
+                                stop_signal.load(Ordering::Relaxed)
+                            });
+                        }
                         if a.write_all(&buf[..n]).is_err() || a.flush().is_err() {
                             break;
                         }
+                        if let Some(meter) = &meter {
+                            if first {
+                                meter.record_response(&buf[..n]);
+                                first = false;
+                            }
+                            meter.add_down(n as u64);
+                        }
+                        if let Some(capture) = &capture {
+                            capture.on_download(&buf[..n]);
+                        }
                     }
-                    Err(e) => {
-                        if e.kind() != std::io::ErrorKind::WouldBlock {
+                    Err(e) if is_timeout(&e) => {
+                        if let Some(reason) = activity.expired() {
+                            // 两个方向会同时检测到超时，只由先停止的一方记录
+                            if !stop_signal.swap(true, Ordering::Relaxed) {
+                                debug!("隧道{}，关闭连接", reason);
+                            }
                             break;
                         }
                     }
+                    Err(_) => break,
                 }
             }
-            // 通知另一个线程也停止
+            // 通知另一个线程也停止，关闭连接使其阻塞的读取立即返回
             stop_signal.store(true, Ordering::Relaxed);
+            b2a.close();
+            a.close();
             // 显式释放缓冲区
             drop(buf);
         })
@@ -789,14 +1709,22 @@ fn tunnel(a: TcpStream, b: TcpStream) {
     let _ = t2.join();
 }
 
-fn handle_client(mut client_stream: TcpStream, settings: Arc<Mutex<ProxySettings>>) {
+// 读取超时在 Unix 上为 WouldBlock，在 Windows 上为 TimedOut
+pub fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+fn handle_client(mut client_stream: TcpStream, ctx: Arc<ProxyContext>, conn: &Arc<ConnectionInfo>) {
     // 设置TCP优化选项
     let _ = client_stream.set_nodelay(true);
 
-    // 设置超时
-    let timeout = Duration::from_secs(TIMEOUT);
-    let _ = client_stream.set_read_timeout(Some(timeout));
-    let _ = client_stream.set_write_timeout(Some(timeout));
+    // 读取请求头和协商阶段使用握手超时，转发阶段由 tunnel 重新设置
+    let timeouts = ctx.snapshot.load().settings.timeouts.clone();
+    let _ = client_stream.set_read_timeout(timeouts.handshake());
+    let _ = client_stream.set_write_timeout(timeouts.handshake());
 
     // 使用栈分配而不是堆分配来减少内存压力
     let mut buffer = [0u8; BUFFER_SIZE];
@@ -804,16 +1732,51 @@ fn handle_client(mut client_stream: TcpStream, settings: Arc<Mutex<ProxySettings
         Ok(0) => return, // 连接已关闭
         Ok(n) => n,
         Err(e) => {
-            println!("[proxy] 读取请求错误: {}", e);
+            warn!("读取请求错误: {}", e);
             return;
         }
     };
 
-    let request = match String::from_utf8_lossy(&buffer[..size]).to_string() {
-        s if s.is_empty() => return,
-        s => s,
+    // 入站认证：不需要认证的客户端（默认为本机）直接放行
+    let auth = ctx.snapshot.load().settings.inbound_auth.clone();
+    let auth_required = match client_stream.peer_addr() {
+        Ok(peer) => auth.required_for(&peer.ip()),
+        Err(_) => auth.enabled,
+    };
+
+    // SOCKS5 客户端以版本号 0x05 开头，HTTP 请求以方法名开头
+    if buffer[0] == 0x05 {
+        let auth = auth_required.then_some(&auth);
+        if let Err(e) = handle_socks5_client(client_stream, &buffer[..size], auth, &ctx, conn) {
+            warn!("SOCKS5 请求处理失败: {}", e);
+        }
+        return;
+    }
+
+    // 读取完整的请求头，之后的数据（请求体、客户端提前发送的下一个请求）留在 client 中
+    let mut client = MessageReader::new(client_stream, &buffer[..size]);
+    let request = match client.read_head(&mut || {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "读取请求头超时",
+        ))
+    }) {
+        Ok(Some(head)) => String::from_utf8_lossy(&head).to_string(),
+        Ok(None) => return,
+        Err(e) => {
+            warn!("读取请求错误: {}", e);
+            return;
+        }
     };
 
+    if auth_required && !auth.check_http_request(&request) {
+        warn!("代理认证失败: {:?}", client.get_ref().peer_addr());
+        conn.set_method(request.split_whitespace().next().unwrap_or_default());
+        conn.set_status(407);
+        let _ = client.get_mut().write_all(PROXY_AUTH_REQUIRED);
+        return;
+    }
+
     let mut lines = request.lines();
     let request_line = match lines.next() {
         Some(line) => line,
@@ -822,42 +1785,166 @@ fn handle_client(mut client_stream: TcpStream, settings: Arc<Mutex<ProxySettings
 
     let parts: Vec<&str> = request_line.split_whitespace().collect();
     if parts.len() < 2 {
-        println!("[proxy] 无效的请求行: {}", request_line);
+        warn!("无效的请求行: {}", request_line);
         return;
     }
 
-    println!("[proxy] 请求: {}", request_line);
+    debug!("请求: {}", request_line);
+    conn.set_method(parts[0]);
+    conn.set_target(parts[1]);
 
     // 检查是否是WebSocket升级请求
     let is_websocket = request.to_lowercase().contains("upgrade: websocket");
     if is_websocket {
-        println!("[proxy] 检测到WebSocket连接请求");
+        debug!("检测到WebSocket连接请求");
     }
 
     // 使用 Result 和 ? 操作符来简化错误处理
-    if let Err(e) = handle_request(
-        &mut client_stream,
-        &parts,
-        &request,
-        is_websocket,
-        &settings,
-    ) {
-        println!("[proxy] 处理请求失败: {}", e);
-        let _ = client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n");
+    if let Err(e) = handle_request(&mut client, &parts, &request, is_websocket, &ctx, conn) {
+        warn!("处理请求失败: {}", e);
+        conn.set_status(502);
+        let _ = client
+            .get_mut()
+            .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n");
+    }
+}
+
+// SOCKS5 入口：支持无认证和用户名密码认证，只支持 CONNECT 命令
+fn handle_socks5_client(
+    mut client_stream: TcpStream,
+    first: &[u8],
+    auth: Option<&InboundAuth>,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
+) -> std::io::Result<()> {
+    conn.set_method("SOCKS5");
+
+    // 首个数据包之后的内容继续从连接中读取
+    let mut input = first.chain(client_stream.try_clone()?);
+
+    // 协商认证方法：VER NMETHODS METHODS
+    let mut header = [0u8; 2];
+    input.read_exact(&mut header)?;
+    let mut methods = vec![0u8; header[1] as usize];
+    input.read_exact(&mut methods)?;
+
+    let method = if auth.is_some() { 0x02 } else { 0x00 };
+    if !methods.contains(&method) {
+        client_stream.write_all(&[0x05, 0xFF])?; // 没有可接受的认证方法
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "客户端不支持所需的认证方法",
+        ));
+    }
+    client_stream.write_all(&[0x05, method])?;
+
+    // 用户名密码认证（RFC 1929）：VER ULEN UNAME PLEN PASSWD
+    if let Some(auth) = auth {
+        let mut len = [0u8; 2];
+        input.read_exact(&mut len)?;
+        let mut username = vec![0u8; len[1] as usize];
+        input.read_exact(&mut username)?;
+        input.read_exact(&mut len[..1])?;
+        let mut password = vec![0u8; len[0] as usize];
+        input.read_exact(&mut password)?;
+
+        if !auth.check(&username, &password) {
+            warn!("SOCKS5 认证失败: {:?}", client_stream.peer_addr());
+            conn.set_status(407);
+            client_stream.write_all(&[0x01, 0x01])?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "SOCKS5 认证失败",
+            ));
+        }
+        client_stream.write_all(&[0x01, 0x00])?;
+    }
+
+    // 连接请求：VER CMD RSV ATYP DST.ADDR DST.PORT
+    let mut request = [0u8; 4];
+    input.read_exact(&mut request)?;
+    let host = match request[3] {
+        0x01 => {
+            let mut addr = [0u8; 4];
+            input.read_exact(&mut addr)?;
+            std::net::Ipv4Addr::from(addr).to_string()
+        }
+        0x03 => {
+            let mut len = [0u8; 1];
+            input.read_exact(&mut len)?;
+            let mut domain = vec![0u8; len[0] as usize];
+            input.read_exact(&mut domain)?;
+            String::from_utf8_lossy(&domain).to_string()
+        }
+        0x04 => {
+            let mut addr = [0u8; 16];
+            input.read_exact(&mut addr)?;
+            format!("[{}]", std::net::Ipv6Addr::from(addr))
+        }
+        _ => {
+            client_stream.write_all(&[0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0])?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "不支持的地址类型",
+            ));
+        }
+    };
+    let mut port = [0u8; 2];
+    input.read_exact(&mut port)?;
+    let target_addr = format!("{}:{}", host, u16::from_be_bytes(port));
+
+    if request[1] != 0x01 {
+        client_stream.write_all(&[0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0])?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "只支持 CONNECT 命令",
+        ));
+    }
+    debug!("SOCKS5 CONNECT请求到: {}", target_addr);
+    conn.set_target(&target_addr);
+
+    let snapshot = ctx.snapshot.load_full();
+    match connect_with_proxy_settings(&target_addr, &snapshot, ctx, conn) {
+        Ok(Upstream {
+            stream: target_stream,
+            meter,
+            host_slot: _host_slot,
+            throttle,
+        }) => {
+            conn.set_status(200);
+            client_stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])?;
+            let timeouts = snapshot.settings.timeouts.tunnel(false);
+            tunnel(
+                client_stream,
+                target_stream,
+                Some(meter),
+                Some(throttle),
+                None,
+                timeouts,
+            );
+            Ok(())
+        }
+        Err(e) => {
+            warn!("SOCKS5 连接失败: {} - {}", target_addr, e);
+            conn.set_status(502);
+            client_stream.write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])?;
+            Err(e)
+        }
     }
 }
 
 // 将请求处理逻辑分离到单独的函数
 fn handle_request(
-    client_stream: &mut TcpStream,
+    client: &mut MessageReader<TcpStream>,
     parts: &[&str],
     request: &str,
     is_websocket: bool,
-    settings: &Arc<Mutex<ProxySettings>>,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
 ) -> std::io::Result<()> {
     match parts[0].to_uppercase().as_str() {
-        "CONNECT" => handle_connect_request(client_stream, parts, is_websocket, settings),
-        _ => handle_http_request(client_stream, parts, request, is_websocket, settings),
+        "CONNECT" => handle_connect_request(client.get_mut(), parts, is_websocket, ctx, conn),
+        _ => handle_http_request(client, request, ctx, conn),
     }
 }
 
@@ -866,7 +1953,8 @@ fn handle_connect_request(
     client_stream: &mut TcpStream,
     parts: &[&str],
     _is_websocket: bool, // 添加下划线前缀表示有意未使用
-    settings: &Arc<Mutex<ProxySettings>>,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
 ) -> std::io::Result<()> {
     let host_port = parts[1];
     let (host, port) = match host_port.find(':') {
@@ -879,93 +1967,549 @@ fn handle_connect_request(
     };
 
     let target_addr = format!("{}:{}", host, port);
-    println!("[proxy] CONNECT请求到: {}", target_addr);
+    debug!("CONNECT请求到: {}", target_addr);
 
     if port == 8000 {
-        println!("[proxy] 可能是WebSocket CONNECT请求");
+        debug!("可能是WebSocket CONNECT请求");
     }
 
-    let proxy_settings = settings.lock().unwrap().clone();
+    let snapshot = ctx.snapshot.load_full();
+    // 隧道内容已加密，抓包时只记录耗时；解密的隧道按请求记录
+    let intercept = ctx.tls.intercepts(host);
+    let capture = if intercept {
+        None
+    } else {
+        ctx.har.begin_tunnel(conn.id, &target_addr)
+    };
 
-    match connect_with_proxy_settings(&target_addr, &proxy_settings) {
-        Ok(target_stream) => {
-            println!("[proxy] CONNECT隧道建立成功: {}", target_addr);
+    match connect_with_proxy_settings(&target_addr, &snapshot, ctx, conn) {
+        Ok(upstream) => {
+            debug!("CONNECT隧道建立成功: {}", target_addr);
+            if let Some(capture) = &capture {
+                capture.connected();
+            }
+            conn.set_status(200);
             client_stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
-            tunnel(client_stream.try_clone()?, target_stream);
+            if intercept && starts_tls_handshake(client_stream) {
+                return intercept_tls(client_stream, upstream, host, port, &snapshot, ctx, conn);
+            }
+
+            let Upstream {
+                stream: target_stream,
+                meter,
+                host_slot: _host_slot,
+                throttle,
+            } = upstream;
+            // 隧道内容已加密，无法区分 WebSocket，使用普通空闲超时
+            let timeouts = snapshot.settings.timeouts.tunnel(false);
+            tunnel(
+                client_stream.try_clone()?,
+                target_stream,
+                Some(meter),
+                Some(throttle),
+                None,
+                timeouts,
+            );
             Ok(())
         }
         Err(e) => {
-            println!("[proxy] CONNECT隧道建立失败: {} - {}", target_addr, e);
+            warn!("CONNECT隧道建立失败: {} - {}", target_addr, e);
+            if let Some(capture) = &capture {
+                capture.failed(&e.to_string());
+            }
             client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
             Err(e)
         }
     }
 }
 
-// 处理 HTTP 请求
-fn handle_http_request(
+// 客户端在隧道中发送的第一个字节是否为 TLS 握手记录
+fn starts_tls_handshake(client_stream: &TcpStream) -> bool {
+    let mut first = [0u8; 1];
+    matches!(client_stream.peek(&mut first), Ok(1) if first[0] == 0x16)
+}
+
+// 解密到指定主机的 HTTPS 隧道：以本地 CA 签发的证书与客户端握手，以 TLS 客户端连接目标，
+// 对解密后的请求应用与明文 HTTP 请求相同的改写后转发；无法签发证书时原样转发
+fn intercept_tls(
     client_stream: &mut TcpStream,
-    parts: &[&str],
+    upstream: Upstream,
+    host: &str,
+    port: u16,
+    snapshot: &ProxySnapshot,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
+) -> std::io::Result<()> {
+    let Upstream {
+        stream: target_stream,
+        meter,
+        host_slot: _host_slot,
+        throttle,
+    } = upstream;
+    let timeouts = &snapshot.settings.timeouts;
+
+    let server_conn = match ctx.tls.prepare(host) {
+        Ok(server_conn) => server_conn,
+        Err(e) => {
+            warn!("无法解密 {}，改为原样转发: {}", host, e);
+            tunnel(
+                client_stream.try_clone()?,
+                target_stream,
+                Some(meter),
+                Some(throttle),
+                None,
+                timeouts.tunnel(false),
+            );
+            return Ok(());
+        }
+    };
+
+    // 握手失败时连接已无法返回 HTTP 错误，直接关闭
+    let _ = target_stream.set_read_timeout(timeouts.handshake());
+    let _ = target_stream.set_write_timeout(timeouts.handshake());
+    let target = match ctx.tls.connect(target_stream, host) {
+        Ok(target) => target,
+        Err(e) => {
+            warn!("与目标 TLS 握手失败: {} - {}", host, e);
+            conn.set_status(502);
+            return Ok(());
+        }
+    };
+    let client = match ctx.tls.accept(client_stream.try_clone()?, server_conn) {
+        Ok(client) => client,
+        Err(e) => {
+            warn!(
+                "与客户端 TLS 握手失败（客户端可能未信任本地 CA）: {} - {}",
+                host, e
+            );
+            return Ok(());
+        }
+    };
+
+    // 解密后的请求与明文请求一样逐个读取，每个请求都单独改写后转发
+    let client_socket = client.socket().try_clone()?;
+    for stream in [client.socket(), target.socket()] {
+        let _ = stream.set_read_timeout(Some(TUNNEL_POLL_INTERVAL));
+        let _ = stream.set_write_timeout(timeouts.tunnel(false).idle);
+    }
+    let mut client = MessageReader::new(client, &[]);
+    let mut target = MessageReader::new(target, &[]);
+    let authority = if port == 443 {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, port)
+    };
+    while let Some(head) = next_request(&mut client, conn, timeouts) {
+        let mut responded = false;
+        let result = forward_decrypted_request(
+            &mut client,
+            &mut target,
+            &client_socket,
+            &head,
+            &authority,
+            (&meter, &throttle),
+            snapshot,
+            ctx,
+            conn,
+            &mut responded,
+        );
+        match result {
+            Ok(true) => finish_request(ctx, conn),
+            Ok(false) => break,
+            Err(e) => {
+                debug!("转发解密的请求失败: {} - {}", host, e);
+                if !responded {
+                    conn.set_status(502);
+                    let _ = client
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n");
+                }
+                break;
+            }
+        }
+    }
+    client.get_ref().close();
+    target.get_ref().close();
+    Ok(())
+}
+
+// 转发一个解密后的请求，返回连接能否继续处理下一个请求；responded 表示是否已有响应发给客户端
+#[allow(clippy::too_many_arguments)]
+fn forward_decrypted_request(
+    client: &mut MessageReader<TlsStream>,
+    target: &mut MessageReader<TlsStream>,
+    client_socket: &TcpStream,
+    head: &str,
+    authority: &str,
+    (meter, throttle): (&TrafficMeter, &HostThrottle),
+    snapshot: &ProxySnapshot,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
+    responded: &mut bool,
+) -> std::io::Result<bool> {
+    let host = extract_host(authority);
+    let mut parts = head.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let Some(request_target) = parts.next().filter(|t| t.starts_with('/')) else {
+        warn!("无效的请求行: {}", head.lines().next().unwrap_or_default());
+        return Ok(false);
+    };
+    conn.set_method(method);
+    let request_length = http_message::request_body_length(head)?;
+
+    // 解密后的请求使用与明文请求相同的 URL 改写规则
+    let (url, head) = match snapshot.rewrites.apply(&host, request_target) {
+        Some(UrlRewrite::Redirect(status, location)) => {
+            debug!(
+                "URL重定向: https://{}{} -> {} {}",
+                authority, request_target, status, location
+            );
+            conn.set_status(status);
+            client
+                .get_mut()
+                .write_all(url_rewrite::redirect_response(status, &location).as_bytes())?;
+            *responded = true;
+            return Ok(false);
+        }
+        Some(UrlRewrite::Rewrite(path)) => (
+            format!("https://{}{}", authority, path),
+            replace_request_target(head, &path),
+        ),
+        None => (
+            format!("https://{}{}", authority, request_target),
+            head.to_string(),
+        ),
+    };
+    debug!("解密的请求: {}", url);
+    conn.set_target(&url);
+
+    let upgraded = is_upgrade_request(&head);
+    let capture = if upgraded {
+        None
+    } else {
+        ctx.har.begin_http(conn.id, &url, &head)
+    };
+    if let Some(capture) = &capture {
+        capture.connected();
+    }
+
+    let modified_request =
+        modify_request(&head, &url[8..], authority.len(), false, &snapshot.headers)?;
+    let exchange = ExchangeContext {
+        conn,
+        client_socket,
+        meter,
+        throttle: Some(throttle),
+        capture: capture.as_deref(),
+        timeouts: snapshot.settings.timeouts.tunnel(false),
+    };
+    if upgraded {
+        exchange.send(
+            target.get_mut(),
+            modified_request.as_bytes(),
+            Direction::Upload,
+        )?;
+        upgrade_to_tunnel(
+            client,
+            target,
+            meter.clone(),
+            Some(throttle.clone()),
+            &snapshot.settings.timeouts,
+        )?;
+        return Ok(false);
+    }
+
+    let keep_alive = relay_exchange(
+        client,
+        target,
+        &modified_request,
+        method,
+        request_length,
+        &exchange,
+        responded,
+    )?;
+    Ok(keep_alive && !http_message::wants_close(&head))
+}
+
+// 处理明文 HTTP 请求：逐个读取同一客户端连接上的请求，每个请求都单独改写、清理认证头后转发，
+// 读完整个响应再处理下一个请求；目标不变时复用上游连接，协议升级后转为隧道
+fn handle_http_request(
+    client: &mut MessageReader<TcpStream>,
     request: &str,
-    is_websocket: bool,
-    settings: &Arc<Mutex<ProxySettings>>,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
 ) -> std::io::Result<()> {
-    let mut url = parts[1].to_string();
+    let timeouts = ctx.snapshot.load().settings.timeouts.clone();
+    let _ = client
+        .get_ref()
+        .set_read_timeout(Some(TUNNEL_POLL_INTERVAL));
+    let _ = client
+        .get_ref()
+        .set_write_timeout(timeouts.tunnel(false).idle);
+    let client_socket = client.get_ref().try_clone()?;
+
+    let mut upstream: Option<HttpUpstream> = None;
+    let mut request = request.to_string();
+    loop {
+        let snapshot = ctx.snapshot.load_full();
+        let mut responded = false;
+        let result = forward_http_request(
+            client,
+            &client_socket,
+            &request,
+            &mut upstream,
+            &snapshot,
+            ctx,
+            conn,
+            &mut responded,
+        );
+        match result {
+            Ok(true) => finish_request(ctx, conn),
+            Ok(false) => return Ok(()),
+            // 还没有响应发给客户端时由 handle_client 回复 502
+            Err(e) if !responded => return Err(e),
+            Err(e) => {
+                debug!("转发响应中断: {}", e);
+                return Ok(());
+            }
+        }
+
+        match next_request(client, conn, &snapshot.settings.timeouts) {
+            Some(next) => request = next,
+            None => return Ok(()),
+        }
+        debug!("请求: {}", request.lines().next().unwrap_or_default());
+    }
+}
+
+// 同一连接上的请求处理完毕，写入它的访问日志；最后一个请求在连接结束时写入
+fn finish_request(ctx: &ProxyContext, conn: &ConnectionInfo) {
+    if let Some(entry) = conn.finish_request() {
+        ctx.access_log.record(&entry);
+    }
+}
 
-    if url.contains("/api/admin/") {
-        url = url.replace("/api/admin/", "/admin/");
-        println!("[proxy] URL重写: {} -> {}", parts[1], url);
+// 明文请求的转发目标
+struct HttpTarget {
+    url: String,    // 完整 URL，用于抓包
+    addr: String,   // 目标 "host:port"
+    relative: bool, // 相对路径请求：按 Host 头直连，不经过分流规则
+    websocket: bool,
+    head: String, // 改写后发往目标的请求头
+}
+
+// 转发循环当前使用的上游连接，目标相同的后续请求复用
+struct HttpUpstream {
+    addr: String,
+    relative: bool,
+    reader: MessageReader<TcpStream>,
+    meter: TrafficMeter,
+    throttle: Option<HostThrottle>,
+    _host_slot: Option<HostSlot>, // 占用的目标主机并发名额，上游连接关闭时归还
+}
+
+// 转发一个明文请求，返回连接能否继续处理下一个请求；responded 表示是否已有响应发给客户端
+#[allow(clippy::too_many_arguments)]
+fn forward_http_request(
+    client: &mut MessageReader<TcpStream>,
+    client_socket: &TcpStream,
+    request: &str,
+    upstream: &mut Option<HttpUpstream>,
+    snapshot: &ProxySnapshot,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
+    responded: &mut bool,
+) -> std::io::Result<bool> {
+    let request_line = request.lines().next().unwrap_or_default();
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    if parts.len() < 2 {
+        warn!("无效的请求行: {}", request_line);
+        conn.set_status(400);
+        client
+            .get_mut()
+            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")?;
+        *responded = true;
+        return Ok(false);
     }
+    let method = parts[0];
+    conn.set_method(method);
+    conn.set_target(parts[1]);
+    let request_length = http_message::request_body_length(request)?;
+
+    // 按 URL 改写规则改写路径，或直接返回重定向
+    let rewrite = split_request_target(parts[1], request).and_then(|(authority, path_start)| {
+        let path = &parts[1][path_start..];
+        let rewrite = snapshot.rewrites.apply(&extract_host(authority), path);
+        rewrite.map(|rewrite| (rewrite, path_start))
+    });
+    let (url, request) = match rewrite {
+        Some((UrlRewrite::Redirect(status, location), _)) => {
+            debug!("URL重定向: {} -> {} {}", parts[1], status, location);
+            conn.set_status(status);
+            client
+                .get_mut()
+                .write_all(url_rewrite::redirect_response(status, &location).as_bytes())?;
+            *responded = true;
+            return Ok(false);
+        }
+        Some((UrlRewrite::Rewrite(path), path_start)) => {
+            let url = format!("{}{}", &parts[1][..path_start], path);
+            debug!("URL重写: {} -> {}", parts[1], url);
+            conn.set_target(&url);
+            let request = replace_request_target(request, &url);
+            (url, request)
+        }
+        None => (parts[1].to_string(), request.to_string()),
+    };
 
-    if url.starts_with("http://")
+    let is_websocket = request.to_lowercase().contains("upgrade: websocket");
+    let target = if url.starts_with("http://")
         || url.starts_with("https://")
         || url.starts_with("ws://")
         || url.starts_with("wss://")
     {
-        // 处理双斜杠问题：//www.core333.com//attachment -> //www.core333.com/attachment
-        let clean_url = url
-            .replace("//attachment", "/attachment")
-            .replace("//static", "/static")
-            .replace("//assets", "/assets")
-            .replace("//uploads", "/uploads")
-            .replace("//images", "/images")
-            .replace("//css", "/css")
-            .replace("//js", "/js");
-
-        println!("[proxy] URL清理: {} -> {}", url, clean_url);
-        handle_absolute_url(client_stream, &clean_url, request, is_websocket, settings)
+        absolute_target(&url, &request, is_websocket, snapshot)?
     } else if url.starts_with("//") {
-        // 处理协议相对路径中的双斜杠问题
-        let clean_url = url
-            .replace("//attachment", "/attachment")
-            .replace("//static", "/static")
-            .replace("//assets", "/assets")
-            .replace("//uploads", "/uploads")
-            .replace("//images", "/images")
-            .replace("//css", "/css")
-            .replace("//js", "/js");
-
-        println!("[proxy] 协议相对路径URL清理: {} -> {}", url, clean_url);
         // 处理协议相对路径（Protocol-relative URL）
-        handle_protocol_relative_url(client_stream, &clean_url, request, is_websocket, settings)
+        let url = protocol_relative_url(&url, &request);
+        absolute_target(&url, &request, is_websocket, snapshot)?
     } else if url.starts_with("/") {
-        handle_relative_url(client_stream, &url, request, is_websocket)
+        relative_target(&url, &request, snapshot, ctx, conn)
     } else {
-        println!("[proxy] 不支持的URL格式: {}", url);
-        client_stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")?;
-        Ok(())
+        warn!("不支持的URL格式: {}", url);
+        conn.set_status(400);
+        client
+            .get_mut()
+            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")?;
+        *responded = true;
+        return Ok(false);
+    };
+
+    // 抓包只记录普通的绝对 URL 请求，WebSocket 等协议升级的连接不记录
+    let upgraded = target.websocket || is_upgrade_request(&request);
+    let capture = if upgraded || target.relative {
+        None
+    } else {
+        ctx.har.begin_http(conn.id, &target.url, &request)
+    };
+
+    // 目标不变且上游连接仍然可用时复用，否则按分流规则重新连接
+    let current = match upstream.take() {
+        Some(current)
+            if current.addr == target.addr
+                && current.relative == target.relative
+                && !peer_closed(current.reader.get_ref()) =>
+        {
+            current
+        }
+        _ => match connect_http_target(&target, snapshot, ctx, conn) {
+            Ok(connected) => connected,
+            Err(e) => {
+                warn!("连接失败: {} - {}", target.addr, e);
+                if let Some(capture) = &capture {
+                    capture.failed(&e.to_string());
+                }
+                return Err(e);
+            }
+        },
+    };
+    let current = upstream.insert(current);
+    if let Some(capture) = &capture {
+        capture.connected();
     }
+    debug!(
+        "发送修改后的请求: {}",
+        target.head.lines().next().unwrap_or("")
+    );
+
+    let exchange = ExchangeContext {
+        conn,
+        client_socket,
+        meter: &current.meter,
+        throttle: current.throttle.as_ref(),
+        capture: capture.as_deref(),
+        timeouts: snapshot.settings.timeouts.tunnel(false),
+    };
+    if upgraded {
+        // WebSocket 等协议升级：发出请求头后转为隧道，响应和之后的数据原样转发
+        exchange.send(
+            current.reader.get_mut(),
+            target.head.as_bytes(),
+            Direction::Upload,
+        )?;
+        let (meter, throttle) = (current.meter.clone(), current.throttle.clone());
+        upgrade_to_tunnel(
+            client,
+            &mut current.reader,
+            meter,
+            throttle,
+            &snapshot.settings.timeouts,
+        )?;
+        *upstream = None;
+        return Ok(false);
+    }
+
+    let keep_alive = relay_exchange(
+        client,
+        &mut current.reader,
+        &target.head,
+        method,
+        request_length,
+        &exchange,
+        responded,
+    )?;
+    if !keep_alive {
+        *upstream = None;
+    }
+    Ok(keep_alive && !http_message::wants_close(&request))
 }
 
-// 处理绝对URL请求
-fn handle_absolute_url(
-    client_stream: &mut TcpStream,
+// 拆分请求目标，返回目标主机（可带端口）和路径的起始位置；相对路径的主机取自 Host 请求头
+fn split_request_target<'a>(target: &'a str, request: &'a str) -> Option<(&'a str, usize)> {
+    let authority_start = if target.starts_with("//") {
+        2
+    } else if target.starts_with('/') {
+        let host = request
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))?
+            .1
+            .trim();
+        return Some((host, 0));
+    } else {
+        target.find("://")? + 3
+    };
+    let path_start = target[authority_start..]
+        .find('/')
+        .map_or(target.len(), |pos| authority_start + pos);
+    Some((&target[authority_start..path_start], path_start))
+}
+
+// 替换请求行中的请求目标，请求头和请求体保持不变
+fn replace_request_target(request: &str, target: &str) -> String {
+    let (request_line, rest) = match request.split_once("\r\n") {
+        Some((line, rest)) => (line, Some(rest)),
+        None => (request, None),
+    };
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let version = parts.nth(1).unwrap_or("HTTP/1.1");
+    match rest {
+        Some(rest) => format!("{} {} {}\r\n{}", method, target, version, rest),
+        None => format!("{} {} {}", method, target, version),
+    }
+}
+
+// 绝对URL请求的转发目标
+fn absolute_target(
     url: &str,
     request: &str,
     is_websocket: bool,
-    settings: &Arc<Mutex<ProxySettings>>,
-) -> std::io::Result<()> {
-    println!("[proxy] 处理绝对URL请求: {}", url);
+    snapshot: &ProxySnapshot,
+) -> std::io::Result<HttpTarget> {
+    debug!("处理绝对URL请求: {}", url);
 
     let is_https = url.starts_with("https://");
     let is_ws = url.starts_with("ws://");
@@ -1013,86 +2557,26 @@ fn handle_absolute_url(
     };
 
     let target_addr = format!("{}:{}", host, port);
-    println!("[proxy] 目标地址: {}", target_addr);
+    debug!("目标地址: {}", target_addr);
 
-    let proxy_settings = settings.lock().unwrap().clone();
-
-    // 检查是否应该直连
-    if should_direct_connect(url, &proxy_settings) {
-        println!("[proxy] 使用直连方式访问: {}", url);
-        match direct_connect(&target_addr) {
-            Ok(mut target_stream) => {
-                let timeout = if is_websocket {
-                    Duration::from_secs(300)
-                } else {
-                    Duration::from_secs(TIMEOUT)
-                };
-
-                let _ = target_stream.set_read_timeout(Some(timeout));
-                let _ = target_stream.set_write_timeout(Some(timeout));
-
-                // 构建并发送修改后的请求
-                let modified_request =
-                    modify_request(request, url_without_scheme, host_end, is_websocket)?;
-                println!(
-                    "[proxy] 发送修改后的请求: {}",
-                    modified_request.lines().next().unwrap_or("")
-                );
-                target_stream.write_all(modified_request.as_bytes())?;
-
-                // 转发响应
-                tunnel(client_stream.try_clone()?, target_stream);
-                Ok(())
-            }
-            Err(e) => {
-                println!("[proxy] 直连失败: {}", e);
-                client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
-                Err(e)
-            }
-        }
-    } else {
-        println!("[proxy] 使用代理方式访问: {}", url);
-        match connect_with_proxy_settings(&target_addr, &proxy_settings) {
-            Ok(mut target_stream) => {
-                let timeout = if is_websocket {
-                    Duration::from_secs(300)
-                } else {
-                    Duration::from_secs(TIMEOUT)
-                };
-
-                let _ = target_stream.set_read_timeout(Some(timeout));
-                let _ = target_stream.set_write_timeout(Some(timeout));
-
-                // 构建并发送修改后的请求
-                let modified_request =
-                    modify_request(request, url_without_scheme, host_end, is_websocket)?;
-                println!(
-                    "[proxy] 发送修改后的请求: {}",
-                    modified_request.lines().next().unwrap_or("")
-                );
-                target_stream.write_all(modified_request.as_bytes())?;
-
-                // 转发响应
-                tunnel(client_stream.try_clone()?, target_stream);
-                Ok(())
-            }
-            Err(e) => {
-                println!("[proxy] 代理连接失败: {}", e);
-                client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
-                Err(e)
-            }
-        }
-    }
+    let head = modify_request(
+        request,
+        url_without_scheme,
+        host_end,
+        is_websocket,
+        &snapshot.headers,
+    )?;
+    Ok(HttpTarget {
+        url: url.to_string(),
+        addr: target_addr,
+        relative: false,
+        websocket: is_websocket,
+        head,
+    })
 }
 
-// 处理协议相对路径URL请求（如 //www.core333.com/path）
-fn handle_protocol_relative_url(
-    client_stream: &mut TcpStream,
-    url: &str,
-    request: &str,
-    is_websocket: bool,
-    settings: &Arc<Mutex<ProxySettings>>,
-) -> std::io::Result<()> {
+// 协议相对路径URL（如 //www.core333.com/path）转换为绝对URL
+fn protocol_relative_url(url: &str, request: &str) -> String {
     // 协议相对路径以 "//" 开头，需要根据当前请求的协议来决定使用 http 还是 https
     // 默认使用 HTTPS（大多数现代网站都支持 HTTPS）
     let mut use_https = true;
@@ -1115,68 +2599,26 @@ fn handle_protocol_relative_url(
     let protocol = if use_https { "https" } else { "http" };
     let full_url = format!("{}{}", protocol, url);
 
-    println!("[proxy] 协议相对路径 {} 转换为: {}", url, full_url);
-
-    // 检查智能分流：从URL中提取主机名进行判断
-    let proxy_settings = settings.lock().unwrap().clone();
-    if should_direct_connect(&full_url, &proxy_settings) {
-        println!("[proxy] 协议相对路径智能分流 - 直连: {}", full_url);
-        // 直接连接处理
-        let is_https = full_url.starts_with("https://");
-        let url_without_scheme = &full_url[if is_https { 8 } else { 7 }..];
-        let host_end = url_without_scheme
-            .find('/')
-            .unwrap_or(url_without_scheme.len());
-        let host_port = &url_without_scheme[..host_end];
-
-        let (host, port) = match host_port.find(':') {
-            Some(idx) => {
-                let host = &host_port[..idx];
-                let port = host_port[idx + 1..]
-                    .parse()
-                    .unwrap_or(if is_https { 443 } else { 80 });
-                (host, port)
-            }
-            None => (host_port, if is_https { 443 } else { 80 }),
-        };
-
-        let target_addr = format!("{}:{}", host, port);
-
-        match direct_connect(&target_addr) {
-            Ok(mut target_stream) => {
-                let modified_request =
-                    modify_request(request, url_without_scheme, host_end, is_websocket)?;
-                target_stream.write_all(modified_request.as_bytes())?;
-                tunnel(client_stream.try_clone()?, target_stream);
-                Ok(())
-            }
-            Err(e) => {
-                println!("[proxy] 直连失败: {}", e);
-                client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
-                Err(e)
-            }
-        }
-    } else {
-        // 使用现有的绝对URL处理函数（会通过代理）
-        handle_absolute_url(client_stream, &full_url, request, is_websocket, settings)
-    }
+    debug!("协议相对路径 {} 转换为: {}", url, full_url);
+    full_url
 }
 
-// 处理相对URL请求
-fn handle_relative_url(
-    client_stream: &mut TcpStream,
+// 相对URL请求的转发目标：Host 头指向的地址，没有时转发到本地开发服务器
+fn relative_target(
     url: &str,
     request: &str,
-    _is_websocket: bool,
-) -> std::io::Result<()> {
+    snapshot: &ProxySnapshot,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
+) -> HttpTarget {
     let mut target_host = "localhost:1420";
 
-    // 从请求头中获取Host
+    // 从请求头中获取Host，指向本地代理自身的Host会导致循环，忽略
     for line in request.lines() {
         if line.to_lowercase().starts_with("host:") {
             if let Some(colon_pos) = line.find(':') {
                 let host_value = line[colon_pos + 1..].trim();
-                if !host_value.is_empty() && !host_value.contains(":8080") {
+                if !host_value.is_empty() && !host_points_to_self(host_value, &ctx.loop_guard) {
                     target_host = host_value;
                     break;
                 }
@@ -1184,152 +2626,667 @@ fn handle_relative_url(
         }
     }
 
-    println!("[proxy] 相对路径请求 {} 转发到: {}", url, target_host);
+    debug!("相对路径请求 {} 转发到: {}", url, target_host);
+    conn.set_target(&format!("{}{}", target_host, url));
+
+    let head = apply_header_rules(
+        request,
+        None,
+        &extract_host(target_host),
+        is_upgrade_request(request),
+        &snapshot.headers,
+    );
+    HttpTarget {
+        url: format!("http://{}{}", target_host, url),
+        addr: target_host.to_string(),
+        relative: true,
+        websocket: false,
+        head,
+    }
+}
+
+// 连接明文请求的目标：绝对URL按分流规则直连或走代理，相对URL直连
+fn connect_http_target(
+    target: &HttpTarget,
+    snapshot: &ProxySnapshot,
+    ctx: &ProxyContext,
+    conn: &Arc<ConnectionInfo>,
+) -> std::io::Result<HttpUpstream> {
+    let timeouts = &snapshot.settings.timeouts;
+    let (stream, meter, throttle, host_slot) = if target.relative {
+        conn.set_route("direct", None, None);
+        let stream = resolve_direct(&target.addr, &ctx.loop_guard)
+            .and_then(|addrs| direct_connect(&target.addr, addrs, timeouts.connect()))?;
+        conn.set_tunneling();
+        let meter = ctx.traffic.open_connection(
+            &extract_host(&target.addr),
+            "direct",
+            None,
+            Some(Arc::clone(conn)),
+        );
+        (stream, meter, None, None)
+    } else {
+        let upstream = connect_with_proxy_settings(&target.addr, snapshot, ctx, conn)?;
+        (
+            upstream.stream,
+            upstream.meter,
+            Some(upstream.throttle),
+            Some(upstream.host_slot),
+        )
+    };
+    let _ = stream.set_nodelay(true);
+    let _ = stream.set_read_timeout(Some(TUNNEL_POLL_INTERVAL));
+    let _ = stream.set_write_timeout(timeouts.tunnel(false).idle);
+    Ok(HttpUpstream {
+        addr: target.addr.clone(),
+        relative: target.relative,
+        reader: MessageReader::new(stream, &[]),
+        meter,
+        throttle,
+        _host_slot: host_slot,
+    })
+}
 
-    match TcpStream::connect(target_host) {
-        Ok(mut server_stream) => {
-            let timeout = if _is_websocket {
-                Duration::from_secs(300)
-            } else {
-                Duration::from_secs(TIMEOUT)
-            };
+// 一次请求转发使用的计量、限速、抓包和超时
+struct ExchangeContext<'a> {
+    conn: &'a ConnectionInfo,
+    client_socket: &'a TcpStream, // 等待响应时检查客户端是否已断开
+    meter: &'a TrafficMeter,
+    throttle: Option<&'a HostThrottle>,
+    capture: Option<&'a HarCapture>,
+    timeouts: TunnelTimeouts,
+}
+
+impl ExchangeContext<'_> {
+    // 按限速写入另一侧并计量：限速时按限速句柄的分段大小逐段等待
+    fn send(
+        &self,
+        stream: &mut impl Write,
+        data: &[u8],
+        direction: Direction,
+    ) -> std::io::Result<()> {
+        match self.throttle {
+            Some(throttle) => {
+                let chunk = throttle.chunk_size(direction, data.len()).max(1);
+                for piece in data.chunks(chunk) {
+                    sliced_sleep(throttle.delay(direction, piece.len()), || {
+                        self.conn.is_closed()
+                    });
+                    stream.write_all(piece)?;
+                }
+            }
+            None => stream.write_all(data)?,
+        }
+        stream.flush()?;
+        match direction {
+            Direction::Upload => self.meter.add_up(data.len() as u64),
+            Direction::Download => self.meter.add_down(data.len() as u64),
+        }
+        Ok(())
+    }
+}
 
-            let _ = server_stream.set_read_timeout(Some(timeout));
-            let _ = server_stream.set_write_timeout(Some(timeout));
-            server_stream.write_all(request.as_bytes())?;
+// 转发请求头和请求体，再把完整的响应返回给客户端，返回目标连接能否继续使用
+// responded 表示是否已有响应数据发给客户端，之后出错时不能再回复 502
+fn relay_exchange<C: TunnelStream, T: TunnelStream>(
+    client: &mut MessageReader<C>,
+    target: &mut MessageReader<T>,
+    head: &str,
+    method: &str,
+    request_length: BodyLength,
+    exchange: &ExchangeContext,
+    responded: &mut bool,
+) -> std::io::Result<bool> {
+    // 客户端等待 100 Continue 后才发送请求体：由代理直接回复，转发的请求去掉 Expect 头
+    let head = if request_length != BodyLength::Empty
+        && http_message::has_token(head, "expect", "100-continue")
+    {
+        client
+            .get_mut()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        http_message::remove_header(head, "expect")
+    } else {
+        head.to_string()
+    };
+    exchange.send(target.get_mut(), head.as_bytes(), Direction::Upload)?;
+    if let Some(capture) = exchange.capture {
+        capture.sent(head.as_bytes());
+    }
 
-            tunnel(client_stream.try_clone()?, server_stream);
+    // 请求体：只检查空闲时间
+    let upload = TunnelActivity::new(TunnelTimeouts {
+        first_byte: None,
+        idle: exchange.timeouts.idle,
+    });
+    client.read_body(
+        request_length,
+        &mut || check_waiting(&upload, exchange.conn, None),
+        &mut |data| {
+            upload.touch(false);
+            exchange.send(target.get_mut(), data, Direction::Upload)?;
+            if let Some(capture) = exchange.capture {
+                capture.on_upload(data);
+            }
             Ok(())
+        },
+    )?;
+
+    // 响应：等待期间客户端断开则不再等待
+    let download = TunnelActivity::new(exchange.timeouts);
+    let mut wait = || check_waiting(&download, exchange.conn, Some(exchange.client_socket));
+    let response = loop {
+        let Some(response) = target.read_head(&mut wait)? else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "目标未返回响应就关闭了连接",
+            ));
+        };
+        download.touch(true);
+        exchange.send(client.get_mut(), &response, Direction::Download)?;
+        *responded = true;
+        let status = http_message::status_code(&String::from_utf8_lossy(&response));
+        // 中间响应（如 100 Continue）之后还有最终响应
+        if matches!(status, Some(100..=199)) && status != Some(101) {
+            continue;
+        }
+        if let Some(status) = status {
+            exchange.conn.set_status(status);
+        }
+        if let Some(capture) = exchange.capture {
+            capture.on_download(&response);
+        }
+        break String::from_utf8_lossy(&response).into_owned();
+    };
+
+    let length = http_message::response_body_length(&response, method);
+    target.read_body(length, &mut wait, &mut |data| {
+        download.touch(true);
+        exchange.send(client.get_mut(), data, Direction::Download)?;
+        if let Some(capture) = exchange.capture {
+            capture.on_download(data);
         }
+        Ok(())
+    })?;
+
+    // 没有长度信息的响应以关闭连接结束；101 之后连接已不再是 HTTP
+    Ok(length != BodyLength::UntilClose
+        && http_message::status_code(&response) != Some(101)
+        && !http_message::wants_close(&response))
+}
+
+// 协议升级后转为隧道：先把双方已读取但未处理的数据转发给另一侧
+fn upgrade_to_tunnel<C: TunnelStream, T: TunnelStream>(
+    client: &mut MessageReader<C>,
+    target: &mut MessageReader<T>,
+    meter: TrafficMeter,
+    throttle: Option<HostThrottle>,
+    timeouts: &TimeoutSettings,
+) -> std::io::Result<()> {
+    let pending = client.take_buffered();
+    target.get_mut().write_all(&pending)?;
+    meter.add_up(pending.len() as u64);
+    let pending = target.take_buffered();
+    client.get_mut().write_all(&pending)?;
+    meter.add_down(pending.len() as u64);
+
+    // 转发响应，WebSocket 等协议升级的连接使用单独的空闲超时
+    tunnel(
+        TunnelStream::try_clone(client.get_ref())?,
+        TunnelStream::try_clone(target.get_ref())?,
+        Some(meter),
+        throttle,
+        None,
+        timeouts.tunnel(true),
+    );
+    Ok(())
+}
+
+// 等待同一连接上的下一个请求：空闲超过 idle_secs、客户端关闭或连接被关闭时返回 None
+fn next_request<C: TunnelStream>(
+    client: &mut MessageReader<C>,
+    conn: &ConnectionInfo,
+    timeouts: &TimeoutSettings,
+) -> Option<String> {
+    let activity = TunnelActivity::new(TunnelTimeouts {
+        first_byte: None,
+        idle: timeouts.tunnel(false).idle,
+    });
+    match client.read_head(&mut || check_waiting(&activity, conn, None)) {
+        Ok(Some(head)) => Some(String::from_utf8_lossy(&head).into_owned()),
+        Ok(None) => None,
         Err(e) => {
-            println!("[proxy] 连接失败: {} - {}", target_host, e);
-            client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
-            Err(e)
+            debug!("不再等待下一个请求: {}", e);
+            None
         }
     }
 }
 
-// 修改请求头
+// 转发过程中读取超时时的检查：连接已被关闭、客户端已断开或超时则停止等待
+fn check_waiting(
+    activity: &TunnelActivity,
+    conn: &ConnectionInfo,
+    client: Option<&TcpStream>,
+) -> std::io::Result<()> {
+    if conn.is_closed() || client.is_some_and(peer_closed) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "客户端连接已关闭",
+        ));
+    }
+    match activity.expired() {
+        Some(reason) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, reason)),
+        None => Ok(()),
+    }
+}
+
+// 对方是否已关闭连接（或本地已关闭该连接），不消耗连接中的数据
+fn peer_closed(socket: &TcpStream) -> bool {
+    if socket.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0u8; 1];
+    let closed = match socket.peek(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !is_timeout(&e) && e.kind() != std::io::ErrorKind::Interrupted,
+    };
+    let _ = socket.set_nonblocking(false);
+    closed
+}
+
+// 请求是否要求协议升级（Upgrade 头，如 WebSocket、h2c）
+fn is_upgrade_request(request: &str) -> bool {
+    request
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .any(|line| {
+            line.split_once(':')
+                .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("upgrade"))
+        })
+}
+
+// 检查 Host 头是否指向本地代理自身（未带端口时按 80 端口处理）
+fn host_points_to_self(host_value: &str, loop_guard: &LoopGuard) -> bool {
+    let has_port = match host_value.rfind(']') {
+        Some(bracket) => host_value[bracket..].contains(':'),
+        None => host_value.contains(':'),
+    };
+    if has_port {
+        loop_guard.is_self_target(host_value)
+    } else {
+        loop_guard.is_self_target(&format!("{}:80", host_value))
+    }
+}
+
+// 修改请求：改写请求行，请求头交给请求头规则处理
 fn modify_request(
     request: &str,
     url_without_scheme: &str,
     host_end: usize,
     _is_websocket: bool, // 添加下划线前缀表示有意未使用
+    header_rules: &HeaderRules,
 ) -> std::io::Result<String> {
-    let mut modified_request = String::new();
-    let mut is_first_line = true;
+    let request_line = request.lines().next().and_then(|line| {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 2 {
+            return None;
+        }
+        let mut path = parts[1].to_string();
+        if _is_websocket {
+            if let Some(slash_pos) = url_without_scheme[host_end..].find('/') {
+                path = url_without_scheme[host_end + slash_pos..].to_string();
+            } else {
+                path = "/".to_string();
+            }
+        }
 
-    for line in request.lines() {
-        if is_first_line {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 2 {
-                let mut path = parts[1].to_string();
-                if path.contains("/api/admin/") {
-                    path = path.replace("/api/admin/", "/admin/");
+        Some(format!(
+            "{} {} {}",
+            parts[0],
+            path,
+            parts.get(2).unwrap_or(&"HTTP/1.1")
+        ))
+    });
+
+    let host = extract_host(&url_without_scheme[..host_end]);
+    let upgraded = _is_websocket || is_upgrade_request(request);
+    Ok(apply_header_rules(
+        request,
+        request_line,
+        &host,
+        upgraded,
+        header_rules,
+    ))
+}
+
+// 按请求头规则改写请求头，请求体原样保留；request_line 为 None 时沿用原请求行
+// 入站认证凭据只用于本地代理，不能转发给目标服务器，始终删除 Proxy-Authorization
+// 明文请求和解密后的请求都逐个读取后转发，同一连接上的每个请求都经过这里
+// WebSocket 等协议升级的握手请求（upgraded）不应用请求头规则，只删除 Proxy-Authorization
+fn apply_header_rules(
+    request: &str,
+    request_line: Option<String>,
+    host: &str,
+    upgraded: bool,
+    header_rules: &HeaderRules,
+) -> String {
+    let (head, body) = match request.find("\r\n\r\n") {
+        Some(pos) => (&request[..pos], &request[pos + 4..]),
+        None => (request.trim_end_matches("\r\n"), ""),
+    };
+    let mut lines = head.lines();
+    let original_line = lines.next().unwrap_or("");
+
+    let mut headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("proxy-authorization"))
+        .collect();
+    if !upgraded {
+        header_rules.apply(host, &mut headers);
+    }
+
+    let mut modified_request = request_line.unwrap_or_else(|| original_line.to_string());
+    modified_request.push_str("\r\n");
+    for (name, value) in &headers {
+        modified_request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    modified_request.push_str("\r\n");
+    modified_request.push_str(body);
+    modified_request
+}
+
+// 并发连接数已达上限时拒绝客户端：HTTP 客户端回复 503，SOCKS5 客户端回复一般性失败（0x01）
+fn reject_busy_client(mut client_stream: TcpStream) {
+    let _ = client_stream.set_read_timeout(Some(REJECT_TIMEOUT));
+    let _ = client_stream.set_write_timeout(Some(REJECT_TIMEOUT));
+    let mut buffer = [0u8; 512];
+    let size = match client_stream.read(&mut buffer) {
+        Ok(0) | Err(_) => return,
+        Ok(n) => n,
+    };
+
+    if buffer[0] != 0x05 {
+        let _ = client_stream.write_all(SERVICE_UNAVAILABLE);
+        return;
+    }
+
+    // SOCKS5 需要先完成方法协商才能回复连接请求；客户端只支持认证时直接拒绝协商
+    let methods = buffer
+        .get(2..size.min(2 + buffer[1] as usize))
+        .unwrap_or(&[]);
+    if !methods.contains(&0x00) {
+        let _ = client_stream.write_all(&[0x05, 0xFF]);
+        return;
+    }
+    if client_stream.write_all(&[0x05, 0x00]).is_ok() && client_stream.read(&mut buffer).is_ok() {
+        let _ = client_stream.write_all(&[0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    }
+}
+
+// 接受连接：检查客户端是否允许访问后交给处理线程
+// 监听套接字为非阻塞模式，定期检查停止信号；线程退出时释放监听套接字
+fn accept_loop(listener: TcpListener, context: Arc<ProxyContext>) {
+    let mut consecutive_errors = 0;
+    const MAX_ERRORS: u32 = 5;
+
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("设置非阻塞监听失败: {}", e);
+        return;
+    }
+
+    while !context.stopping.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((client_stream, peer)) => {
+                consecutive_errors = 0; // 重置错误计数
+
+                // 部分平台上接受的连接会继承监听套接字的非阻塞模式
+                if let Err(e) = client_stream.set_nonblocking(false) {
+                    warn!("设置阻塞模式失败: {}", e);
+                    continue;
                 }
 
-                if _is_websocket {
-                    if let Some(slash_pos) = url_without_scheme[host_end..].find('/') {
-                        path = url_without_scheme[host_end + slash_pos..].to_string();
-                    } else {
-                        path = "/".to_string();
-                    }
+                // 访问控制：拒绝不在允许网段内的客户端
+                if !context.snapshot.load().client_acl.allows(&peer.ip()) {
+                    warn!("拒绝未授权的客户端: {}", peer);
+                    continue;
                 }
 
-                modified_request.push_str(&format!(
-                    "{} {} {}\r\n",
-                    parts[0],
-                    path,
-                    parts.get(2).unwrap_or(&"HTTP/1.1")
-                ));
+                let context_clone = Arc::clone(&context);
+
+                thread::spawn(move || {
+                    // 登记连接，处理结束时自动注销；无法登记的连接无法被关闭，直接拒绝
+                    let Some(guard) = context_clone.connections.register(&client_stream, peer)
+                    else {
+                        warn!("登记连接失败，关闭连接: {}", peer);
+                        return;
+                    };
+
+                    // 限制并发连接数：达到总数或单客户端上限时排队，队列已满或超时则拒绝
+                    let limits = context_clone.snapshot.load().settings.limits.clone();
+                    let Some(_slot) = context_clone.limiter.acquire_client(peer.ip(), &limits)
+                    else {
+                        warn!("并发连接数已达上限，拒绝连接: {}", peer);
+                        reject_busy_client(client_stream);
+                        return;
+                    };
+                    // 排队期间代理已停止
+                    if context_clone.stopping.load(Ordering::Relaxed) {
+                        return;
+                    }
+
+                    let _ = client_stream.set_nodelay(true); // 优化网络性能
+                    handle_client(client_stream, Arc::clone(&context_clone), guard.info());
+                    // 连接结束时写入最后一个请求或隧道的访问日志，之前的请求已逐个写入
+                    if let Some(entry) = guard.info().access_entry() {
+                        context_clone.access_log.record(&entry);
+                    }
+                });
             }
-            is_first_line = false;
-        } else {
-            if _is_websocket
-                || (!line.to_lowercase().starts_with("accept-encoding:")
-                    && !line.to_lowercase().starts_with("proxy-connection:"))
-            {
-                modified_request.push_str(line);
-                modified_request.push_str("\r\n");
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Err(e) => {
+                consecutive_errors += 1;
+                warn!("接受连接错误: {}", e);
+
+                if consecutive_errors >= MAX_ERRORS {
+                    error!("连续错误过多，暂停接受新连接");
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                    consecutive_errors = 0;
+                }
             }
         }
     }
-    modified_request.push_str("\r\n");
-
-    Ok(modified_request)
 }
 
 impl ProxyServer {
-    pub fn start_auto_port(range_start: u16, range_end: u16) -> Option<Self> {
-        let settings = Arc::new(Mutex::new(ProxySettings::default()));
+    // 按监听设置启动代理：绑定所有监听地址，每个地址一个接受连接的线程
+    pub fn start(settings: ProxySettings) -> Result<Self, String> {
+        Self::start_with_stats(settings, SessionStats::persistent())
+    }
 
-        for port in range_start..=range_end {
-            let addr = format!("127.0.0.1:{}", port);
-            if let Ok(listener) = TcpListener::bind(&addr) {
-                println!("[proxy] 监听端口: {}", port);
-                let settings_clone = Arc::clone(&settings);
+    fn start_with_stats(settings: ProxySettings, stats: SessionStats) -> Result<Self, String> {
+        let (port, listeners) = bind_listeners(&settings.listen)?;
+
+        // 记录实际监听地址，用于循环代理检测
+        let listen_addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .filter_map(|l| l.local_addr().ok())
+            .collect();
+        info!("监听地址: {:?}", listen_addrs);
+        if !settings.listen.is_loopback_only() && settings.listen.allowed_clients.is_empty() {
+            warn!("未设置允许的客户端网段，仅接受本机连接");
+        }
 
-                thread::spawn(move || {
-                    let mut consecutive_errors = 0;
-                    const MAX_ERRORS: u32 = 5;
-
-                    for stream in listener.incoming() {
-                        match stream {
-                            Ok(client_stream) => {
-                                consecutive_errors = 0; // 重置错误计数
-                                let settings_clone = Arc::clone(&settings_clone);
-
-                                // 限制最大并发连接数
-                                if thread::available_parallelism()
-                                    .map(|n| n.get() as u32)
-                                    .unwrap_or(4)
-                                    > 32
-                                {
-                                    println!("[proxy] 警告: 并发连接数过高");
-                                    std::thread::sleep(std::time::Duration::from_millis(100));
-                                    continue;
-                                }
+        let context = Arc::new(ProxyContext::new(settings, listen_addrs, stats));
+        let accept_threads = listeners
+            .into_iter()
+            .map(|listener| {
+                let context = Arc::clone(&context);
+                thread::spawn(move || accept_loop(listener, context))
+            })
+            .collect();
+
+        Ok(ProxyServer {
+            port,
+            context,
+            accept_threads,
+        })
+    }
 
-                                thread::spawn(move || {
-                                    let _ = client_stream.set_nodelay(true); // 优化网络性能
-                                    handle_client(client_stream, settings_clone);
-                                });
-                            }
-                            Err(e) => {
-                                consecutive_errors += 1;
-                                println!("[proxy] 接受连接错误: {}", e);
-
-                                if consecutive_errors >= MAX_ERRORS {
-                                    println!("[proxy] 连续错误过多，暂停接受新连接");
-                                    std::thread::sleep(std::time::Duration::from_secs(1));
-                                    consecutive_errors = 0;
-                                }
-                            }
-                        }
-                    }
-                });
+    // 停止代理：停止接受新连接并释放监听端口，等待进行中的连接结束，
+    // 超过 drain_timeout 后强制关闭剩余连接；drain_timeout 为 0 时立即关闭
+    pub fn stop(&mut self, drain_timeout: Duration) -> StopReport {
+        self.context.stopping.store(true, Ordering::Relaxed);
+        for handle in self.accept_threads.drain(..) {
+            let _ = handle.join();
+        }
+        info!("已停止接受新连接，释放端口 {}", self.port);
+
+        let connections = &self.context.connections;
+        let in_flight = connections.count();
+        let mut report = StopReport::default();
+        if in_flight > 0 {
+            info!(
+                "等待 {} 个进行中的连接结束（最多 {:?}）",
+                in_flight, drain_timeout
+            );
+            if !connections.wait_idle(drain_timeout) {
+                report.aborted = connections.abort_all();
+                warn!("强制关闭 {} 个未结束的连接", report.aborted);
+            }
+            report.drained = in_flight.saturating_sub(report.aborted);
+        }
+        if let Err(e) = self.context.traffic.persist() {
+            warn!("保存流量历史失败: {}", e);
+        }
+        info!("代理已停止: {:?}", report);
+        report
+    }
 
-                return Some(ProxyServer { port, settings });
+    // 按新设置重启代理（可修改监听地址和端口），统计数据保留
+    // 新设置无法绑定时按原设置恢复，并返回错误
+    pub fn restart(
+        &mut self,
+        mut settings: ProxySettings,
+        drain_timeout: Duration,
+    ) -> Result<StopReport, String> {
+        settings.listen.validate()?;
+        let previous = self.get_proxy_settings();
+        rule_subscription::keep_cached_rules(&mut settings, &previous);
+        let stats = SessionStats {
+            rule_stats: Arc::clone(&self.context.rule_stats),
+            traffic: Arc::clone(&self.context.traffic),
+            access_log: Arc::clone(&self.context.access_log),
+            har: Arc::clone(&self.context.har),
+            tls: Arc::clone(&self.context.tls),
+        };
+        let report = self.stop(drain_timeout);
+
+        match Self::start_with_stats(settings, stats.clone()) {
+            Ok(server) => {
+                *self = server;
+                if let Err(e) = save_settings_to_file(&self.get_proxy_settings()) {
+                    warn!("保存配置文件失败: {}", e);
+                }
+                info!("代理已重启: {}", self.local_url());
+                Ok(report)
+            }
+            Err(e) => {
+                error!("按新设置启动失败，恢复原设置: {}", e);
+                *self = Self::start_with_stats(previous, stats)
+                    .map_err(|e2| format!("按新设置启动失败: {}；恢复原设置也失败: {}", e, e2))?;
+                Err(format!("按新设置启动失败，已恢复原设置: {}", e))
             }
         }
-        None
+    }
+
+    // 新增：从本机访问代理的地址，如 "http://127.0.0.1:8080"
+    pub fn local_url(&self) -> String {
+        match local_proxy_addr(self.context.loop_guard.listen_addrs()) {
+            Some(addr) => format!("http://{}", addr),
+            None => format!("http://127.0.0.1:{}", self.port),
+        }
     }
 
     // 新增：更新代理设置
-    pub fn update_proxy_settings(&self, new_settings: ProxySettings) {
-        if let Ok(mut settings) = self.settings.lock() {
-            *settings = new_settings.clone();
-            println!("[proxy] 代理设置已更新: {:?}", *settings);
-            
-            // 自动保存到文件
-            if let Err(e) = save_settings_to_file(&new_settings) {
-                println!("[proxy] ⚠️ 保存配置文件失败: {}", e);
-            }
+    pub fn update_proxy_settings(&self, mut new_settings: ProxySettings) {
+        rule_subscription::keep_cached_rules(
+            &mut new_settings,
+            &self.context.snapshot.load().settings,
+        );
+        let snapshot = ProxySnapshot::new(new_settings);
+        info!(
+            "代理设置已更新: 启用={}, 类型={:?}（{} 条直连规则, {} 条代理规则）",
+            snapshot.settings.enabled,
+            snapshot.settings.proxy_type,
+            snapshot.rules.direct.len(),
+            snapshot.rules.proxy.len()
+        );
+
+        // 自动保存到文件
+        if let Err(e) = save_settings_to_file(&snapshot.settings) {
+            warn!("保存配置文件失败: {}", e);
         }
+
+        self.context.snapshot.store(Arc::new(snapshot));
+        self.context.apply_settings();
+        self.context.reload_system_proxy();
     }
 
     // 新增：获取当前代理设置
     pub fn get_proxy_settings(&self) -> ProxySettings {
-        self.settings.lock().unwrap().clone()
+        self.context.snapshot.load().settings.clone()
+    }
+
+    // 新增：获取规则命中统计，包含尚未命中的已配置规则
+    pub fn get_rule_stats(&self) -> Vec<RuleStatsEntry> {
+        let rules = &self.context.snapshot.load().rules;
+        let mut configured = Vec::new();
+        for (direct, proxy) in [
+            (&rules.conditional.direct, &rules.conditional.proxy),
+            (&rules.profile.direct, &rules.profile.proxy),
+            (&rules.direct, &rules.proxy),
+        ] {
+            configured.push((RouteReason::DirectRule, RouteAction::Direct, direct.rules()));
+            configured.push((RouteReason::ProxyRule, RouteAction::Proxy, proxy.rules()));
+        }
+        self.context.rule_stats.snapshot(&configured)
+    }
+
+    // 新增：清空规则命中统计
+    pub fn reset_rule_stats(&self) {
+        self.context.rule_stats.reset();
+    }
+
+    // 新增：获取本次运行的流量统计（全局、按目标主机、按上游）
+    pub fn get_traffic_stats(&self) -> TrafficSnapshot {
+        self.context.traffic.snapshot()
+    }
+
+    // 新增：获取当前和最高并发连接数
+    pub fn get_concurrency_stats(&self) -> ConcurrencyStats {
+        self.context.limiter.stats()
+    }
+
+    // 新增：当前连接列表
+    pub fn list_connections(&self) -> Vec<ConnectionEntry> {
+        self.context.connections.list()
+    }
+
+    // 新增：关闭指定连接，连接不存在（已结束）时返回 false
+    pub fn close_connection(&self, id: u64) -> bool {
+        let closed = self.context.connections.close(id);
+        if closed {
+            info!("已关闭连接 #{}", id);
+        }
+        closed
+    }
+
+    // 新增：获取共享上下文，供需要在锁外执行的耗时操作使用
+    pub fn context(&self) -> Arc<ProxyContext> {
+        self.context.clone()
     }
 }
@@ -0,0 +1,204 @@
+use crate::bypass::IpCidr;
+use crate::network_env::{self, NetworkEnv};
+use crate::proxy_server::{ProxySettings, RouteAction};
+use chrono::{Datelike, Local, Timelike};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+// 时间段：本地时间，weekdays 为 1（周一）到 7（周日），为空表示每天
+// end 早于 start 表示跨越午夜，如 22:00 - 06:00
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Schedule {
+    #[serde(default)]
+    pub weekdays: Vec<u8>,
+    pub start: String, // "HH:MM"
+    pub end: String,   // "HH:MM"
+}
+
+// 网络条件：gateway 为默认网关地址，cidr 为本机任一网卡地址所在网段；同时设置时需同时满足
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct NetworkMatch {
+    #[serde(default)]
+    pub gateway: Option<String>,
+    #[serde(default)]
+    pub cidr: Option<String>,
+}
+
+// 生效条件：schedules 任一满足且 networks 任一满足；列表为空表示不限制
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct RuleCondition {
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
+    #[serde(default)]
+    pub networks: Vec<NetworkMatch>,
+}
+
+// 按条件生效的单条分流规则
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConditionalRule {
+    pub rule: String, // 域名、IP 或 CIDR，格式同 direct_domains
+    pub action: RouteAction,
+    pub condition: RuleCondition,
+}
+
+// 配置方案：条件满足时其规则优先于静态规则，并可覆盖默认动作；按列表顺序取第一个满足条件的方案
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoutingProfile {
+    pub name: String,
+    pub condition: RuleCondition,
+    #[serde(default)]
+    pub direct_domains: Vec<String>,
+    #[serde(default)]
+    pub proxy_domains: Vec<String>,
+    #[serde(default)]
+    pub default_route: Option<RouteAction>,
+}
+
+// 判断条件时的环境：当前本地时间和网络
+#[derive(Debug, Clone)]
+pub struct ConditionEnv {
+    pub weekday: u8,        // 1（周一）到 7（周日）
+    pub minute_of_day: u16, // 0 - 1439
+    pub network: NetworkEnv,
+}
+
+impl ConditionEnv {
+    // 采集当前环境；只有设置中用到网络条件时才检测网络
+    pub fn current(settings: &ProxySettings) -> Self {
+        if uses_network_conditions(settings) {
+            Self::now(network_env::detect())
+        } else {
+            Self::now(NetworkEnv::default())
+        }
+    }
+
+    // 使用已检测到的网络环境和当前时间
+    pub fn now(network: NetworkEnv) -> Self {
+        let now = Local::now();
+        Self {
+            weekday: now.weekday().number_from_monday() as u8,
+            minute_of_day: (now.hour() * 60 + now.minute()) as u16,
+            network,
+        }
+    }
+}
+
+// 当前生效的方案和条件规则，变化时才需要重新编译分流规则
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ActiveConditions {
+    pub profile: Option<String>, // 生效的配置方案名称
+    pub rules: Vec<usize>,       // 生效的条件规则下标
+}
+
+impl ActiveConditions {
+    pub fn evaluate(settings: &ProxySettings, env: &ConditionEnv) -> Self {
+        Self {
+            profile: settings
+                .profiles
+                .iter()
+                .find(|p| p.condition.matches(env))
+                .map(|p| p.name.clone()),
+            rules: settings
+                .conditional_rules
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| r.condition.matches(env))
+                .map(|(i, _)| i)
+                .collect(),
+        }
+    }
+}
+
+impl RuleCondition {
+    pub fn matches(&self, env: &ConditionEnv) -> bool {
+        (self.schedules.is_empty() || self.schedules.iter().any(|s| s.matches(env)))
+            && (self.networks.is_empty() || self.networks.iter().any(|n| n.matches(&env.network)))
+    }
+
+    // 校验时间格式、星期和网段，返回第一个错误
+    pub fn validate(&self) -> Result<(), String> {
+        for schedule in &self.schedules {
+            for time in [&schedule.start, &schedule.end] {
+                parse_hhmm(time).ok_or(format!("无效的时间: {}（格式应为 HH:MM）", time))?;
+            }
+            if let Some(day) = schedule.weekdays.iter().find(|d| !(1..=7).contains(*d)) {
+                return Err(format!("无效的星期: {}（应为 1-7）", day));
+            }
+        }
+        for network in &self.networks {
+            if network.gateway.is_none() && network.cidr.is_none() {
+                return Err("网络条件至少需要设置网关或网段".to_string());
+            }
+            if let Some(gateway) = &network.gateway {
+                gateway
+                    .parse::<IpAddr>()
+                    .map_err(|_| format!("无效的网关地址: {}", gateway))?;
+            }
+            if let Some(cidr) = &network.cidr {
+                IpCidr::parse(cidr).ok_or(format!("无效的网段: {}", cidr))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Schedule {
+    fn matches(&self, env: &ConditionEnv) -> bool {
+        let (Some(start), Some(end)) = (parse_hhmm(&self.start), parse_hhmm(&self.end)) else {
+            return false;
+        };
+        let now = env.minute_of_day;
+        let on_day = |day: u8| self.weekdays.is_empty() || self.weekdays.contains(&day);
+
+        if start <= end {
+            on_day(env.weekday) && now >= start && now < end
+        } else if now >= start {
+            on_day(env.weekday)
+        } else {
+            // 跨午夜时段的后半段属于前一天开始的时段
+            let previous_day = if env.weekday == 1 { 7 } else { env.weekday - 1 };
+            now < end && on_day(previous_day)
+        }
+    }
+}
+
+impl NetworkMatch {
+    fn matches(&self, network: &NetworkEnv) -> bool {
+        let gateway_ok = match &self.gateway {
+            Some(gateway) => match gateway.parse::<IpAddr>() {
+                Ok(gateway) => network.gateways.contains(&gateway),
+                Err(_) => false,
+            },
+            None => true,
+        };
+        let cidr_ok = match &self.cidr {
+            Some(cidr) => match IpCidr::parse(cidr) {
+                Some(cidr) => network.addresses.iter().any(|ip| cidr.contains(ip)),
+                None => false,
+            },
+            None => true,
+        };
+        gateway_ok && cidr_ok
+    }
+}
+
+fn uses_network_conditions(settings: &ProxySettings) -> bool {
+    settings
+        .conditional_rules
+        .iter()
+        .map(|r| &r.condition)
+        .chain(settings.profiles.iter().map(|p| &p.condition))
+        .any(|c| !c.networks.is_empty())
+}
+
+// 解析 "HH:MM"，返回当天的分钟数；"24:00" 表示一天结束
+fn parse_hhmm(time: &str) -> Option<u16> {
+    let (hour, minute) = time.trim().split_once(':')?;
+    let hour: u16 = hour.parse().ok()?;
+    let minute: u16 = minute.parse().ok()?;
+    match (hour, minute) {
+        (24, 0) => Some(24 * 60),
+        (0..=23, 0..=59) => Some(hour * 60 + minute),
+        _ => None,
+    }
+}
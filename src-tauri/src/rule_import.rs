@@ -0,0 +1,402 @@
+use crate::bypass::IpCidr;
+use crate::proxy_server::{ProxySettings, RouteAction};
+use base64::alphabet;
+use base64::engine::general_purpose::GeneralPurposeConfig;
+use base64::engine::{DecodePaddingMode, GeneralPurpose};
+use base64::Engine;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+// 支持导入的规则格式
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RuleFormat {
+    Gfwlist,      // base64 编码的 gfwlist / AutoProxy 规则
+    Clash,        // Clash 配置中的 rules: 列表
+    SwitchyOmega, // SwitchyOmega 备份 JSON
+}
+
+// 成功转换的规则
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedRule {
+    pub source: String,      // 原始规则文本
+    pub rule: String,        // 转换后的域名/IP/CIDR 规则
+    pub action: RouteAction, // 直连或走代理
+}
+
+// 无法转换的规则
+#[derive(Debug, Clone, Serialize)]
+pub struct UnsupportedRule {
+    pub source: String,
+    pub reason: String,
+}
+
+// 导入预览报告
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleImportReport {
+    pub format: RuleFormat,
+    pub accepted: Vec<ImportedRule>,
+    pub unsupported: Vec<UnsupportedRule>,
+    pub default_route: Option<RouteAction>, // Clash MATCH / SwitchyOmega 默认情景模式 / gfwlist 固定直连
+}
+
+impl RuleImportReport {
+    fn new(format: RuleFormat) -> Self {
+        Self {
+            format,
+            accepted: Vec::new(),
+            unsupported: Vec::new(),
+            default_route: None,
+        }
+    }
+
+    fn accept(&mut self, source: &str, rule: String, action: RouteAction) {
+        self.accepted.push(ImportedRule {
+            source: source.to_string(),
+            rule,
+            action,
+        });
+    }
+
+    fn reject(&mut self, source: &str, reason: impl Into<String>) {
+        self.unsupported.push(UnsupportedRule {
+            source: source.to_string(),
+            reason: reason.into(),
+        });
+    }
+}
+
+// 解析规则内容，生成预览报告（不修改设置）
+pub fn parse_rules(format: RuleFormat, content: &str) -> Result<RuleImportReport, String> {
+    let mut report = RuleImportReport::new(format);
+    match format {
+        RuleFormat::Gfwlist => {
+            let text = decode_gfwlist(content)?;
+            parse_autoproxy(&text, RouteAction::Proxy, &mut report);
+            // gfwlist 只列出需要代理的域名，其余的应直连
+            report.default_route = Some(RouteAction::Direct);
+        }
+        RuleFormat::Clash => parse_clash(content, &mut report)?,
+        RuleFormat::SwitchyOmega => parse_switchy_omega(content, &mut report)?,
+    }
+    info!(
+        "{:?} 规则解析完成: 接受 {} 条, 不支持 {} 条",
+        format,
+        report.accepted.len(),
+        report.unsupported.len()
+    );
+    Ok(report)
+}
+
+// 将导入结果写入设置；replace 为 true 时先清空原有的直连/代理列表
+pub fn apply_import(settings: &mut ProxySettings, report: &RuleImportReport, replace: bool) {
+    if replace {
+        settings.direct_domains.clear();
+        settings.proxy_domains.clear();
+    }
+
+    let mut direct_seen: HashSet<String> = settings
+        .direct_domains
+        .iter()
+        .map(|d| d.to_lowercase())
+        .collect();
+    let mut proxy_seen: HashSet<String> = settings
+        .proxy_domains
+        .iter()
+        .map(|d| d.to_lowercase())
+        .collect();
+
+    for entry in &report.accepted {
+        let (list, seen) = match entry.action {
+            RouteAction::Direct => (&mut settings.direct_domains, &mut direct_seen),
+            RouteAction::Proxy => (&mut settings.proxy_domains, &mut proxy_seen),
+        };
+        if seen.insert(entry.rule.to_lowercase()) {
+            list.push(entry.rule.clone());
+        }
+    }
+
+    if let Some(route) = report.default_route {
+        settings.default_route = route;
+    }
+}
+
+// gfwlist 通常是 base64 编码的；已经是明文时直接使用
+fn decode_gfwlist(content: &str) -> Result<String, String> {
+    let trimmed = content.trim_start();
+    if trimmed.starts_with("[AutoProxy") || trimmed.starts_with('!') || trimmed.contains("||") {
+        return Ok(content.to_string());
+    }
+
+    let compact: String = content.chars().filter(|c| !c.is_whitespace()).collect();
+    let engine = GeneralPurpose::new(
+        &alphabet::STANDARD,
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+    let bytes = engine
+        .decode(compact.as_bytes())
+        .map_err(|e| format!("gfwlist base64 解码失败: {}", e))?;
+    String::from_utf8(bytes).map_err(|e| format!("gfwlist 内容不是有效的 UTF-8: {}", e))
+}
+
+// 解析 AutoProxy 规则文本；match_action 为普通规则的动作，"@@" 例外规则取相反动作
+fn parse_autoproxy(text: &str, match_action: RouteAction, report: &mut RuleImportReport) {
+    let exception_action = match match_action {
+        RouteAction::Proxy => RouteAction::Direct,
+        RouteAction::Direct => RouteAction::Proxy,
+    };
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+            continue;
+        }
+
+        let (pattern, action) = match line.strip_prefix("@@") {
+            Some(rest) => (rest, exception_action),
+            None => (line, match_action),
+        };
+
+        match autoproxy_to_rule(pattern) {
+            Ok(rule) => report.accept(line, rule, action),
+            Err(reason) => report.reject(line, reason),
+        }
+    }
+}
+
+fn autoproxy_to_rule(pattern: &str) -> Result<String, &'static str> {
+    if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+        return Err("不支持正则表达式规则");
+    }
+
+    let host = if let Some(rest) = pattern.strip_prefix("||") {
+        // ||example.com 匹配域名及子域名
+        host_without_path(rest)?
+    } else if let Some(rest) = pattern.strip_prefix('|') {
+        // |http://example.com 匹配 URL 前缀，只接受不带路径的
+        let rest = rest
+            .strip_prefix("http://")
+            .or_else(|| rest.strip_prefix("https://"))
+            .unwrap_or(rest);
+        host_without_path(rest)?
+    } else if let Some(rest) = pattern.strip_prefix('.') {
+        host_without_path(rest)?
+    } else {
+        if pattern.contains('/') {
+            return Err("不支持 URL 关键字规则");
+        }
+        if pattern.contains('*') {
+            return Err("不支持通配符规则");
+        }
+        pattern
+    };
+
+    domain_rule(host).ok_or("无法识别的域名")
+}
+
+// 去掉末尾的 "^" 或 "/"，仍包含路径的规则不支持
+fn host_without_path(rest: &str) -> Result<&str, &'static str> {
+    let rest = rest.trim_end_matches('^').trim_end_matches('/');
+    if rest.contains('/') || rest.contains('^') {
+        return Err("不支持包含路径的规则");
+    }
+    if rest.contains('*') {
+        return Err("不支持通配符规则");
+    }
+    Ok(rest)
+}
+
+// 校验并规范化域名、IP 或 CIDR 规则
+fn domain_rule(value: &str) -> Option<String> {
+    let value = value.trim().trim_end_matches('.').to_lowercase();
+    if value.parse::<IpAddr>().is_ok() {
+        return Some(value);
+    }
+    if let Some(cidr) = IpCidr::parse(&value) {
+        return Some(cidr.to_string());
+    }
+    let value = value.trim_start_matches("*.").trim_start_matches('.');
+    let valid = !value.is_empty()
+        && value.contains('.')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+    if valid {
+        Some(value.to_string())
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClashConfig {
+    #[serde(default)]
+    rules: Vec<String>,
+}
+
+// 解析 Clash 配置中的 rules: 列表
+fn parse_clash(content: &str, report: &mut RuleImportReport) -> Result<(), String> {
+    let config: ClashConfig =
+        serde_yaml::from_str(content).map_err(|e| format!("Clash 配置解析失败: {}", e))?;
+    if config.rules.is_empty() {
+        return Err("Clash 配置中未找到 rules 列表".to_string());
+    }
+
+    for line in &config.rules {
+        let parts: Vec<&str> = line.split(',').map(|p| p.trim()).collect();
+        let rule_type = parts[0].to_uppercase();
+
+        if rule_type == "MATCH" || rule_type == "FINAL" {
+            match parts.get(1).map(|p| clash_policy(p)) {
+                Some(Ok(action)) => report.default_route = Some(action),
+                Some(Err(reason)) => report.reject(line, reason),
+                None => report.reject(line, "规则格式错误"),
+            }
+            continue;
+        }
+
+        if parts.len() < 3 {
+            report.reject(line, "规则格式错误");
+            continue;
+        }
+
+        let action = match clash_policy(parts[2]) {
+            Ok(action) => action,
+            Err(reason) => {
+                report.reject(line, reason);
+                continue;
+            }
+        };
+
+        match rule_type.as_str() {
+            "DOMAIN-SUFFIX" | "IP-CIDR" | "IP-CIDR6" => match domain_rule(parts[1]) {
+                Some(rule) => report.accept(line, rule, action),
+                None => report.reject(line, "无法识别的域名或网段"),
+            },
+            // DOMAIN 为精确匹配，转换为 "=域名" 规则，不匹配子域名
+            "DOMAIN" => match domain_rule(parts[1]) {
+                Some(rule) if rule.parse::<IpAddr>().is_ok() => report.accept(line, rule, action),
+                Some(rule) => report.accept(line, format!("={}", rule), action),
+                None => report.reject(line, "无法识别的域名"),
+            },
+            _ => report.reject(line, format!("不支持的规则类型: {}", rule_type)),
+        }
+    }
+    Ok(())
+}
+
+fn clash_policy(policy: &str) -> Result<RouteAction, &'static str> {
+    match policy.to_uppercase().as_str() {
+        "DIRECT" => Ok(RouteAction::Direct),
+        "REJECT" | "REJECT-DROP" | "REJECT-TINYGIF" => Err("不支持拒绝策略"),
+        // 其他策略（代理组名称）都视为走代理
+        _ => Ok(RouteAction::Proxy),
+    }
+}
+
+// 解析 SwitchyOmega 备份 JSON（"+情景模式名" 为各情景模式）
+fn parse_switchy_omega(content: &str, report: &mut RuleImportReport) -> Result<(), String> {
+    let backup: Value =
+        serde_json::from_str(content).map_err(|e| format!("SwitchyOmega 备份解析失败: {}", e))?;
+    let profiles = backup
+        .as_object()
+        .ok_or("SwitchyOmega 备份格式错误".to_string())?;
+
+    let mut found = false;
+    for (key, profile) in profiles {
+        if !key.starts_with('+') {
+            continue;
+        }
+        let profile_type = profile["profileType"].as_str().unwrap_or_default();
+        match profile_type {
+            "SwitchProfile" => {
+                found = true;
+                if let Some(rules) = profile["rules"].as_array() {
+                    for rule in rules {
+                        let action = omega_action(rule["profileName"].as_str());
+                        parse_omega_condition(&rule["condition"], action, report);
+                    }
+                }
+                if let Some(default) = profile["defaultProfileName"].as_str() {
+                    report.default_route = Some(omega_action(Some(default)));
+                }
+            }
+            "RuleListProfile" => {
+                found = true;
+                let format = profile["format"].as_str().unwrap_or("AutoProxy");
+                let rule_list = profile["ruleList"].as_str().unwrap_or_default();
+                if format.eq_ignore_ascii_case("AutoProxy") {
+                    let action = omega_action(profile["matchProfileName"].as_str());
+                    match decode_gfwlist(rule_list) {
+                        Ok(text) => parse_autoproxy(&text, action, report),
+                        Err(e) => report.reject(key, e),
+                    }
+                } else {
+                    report.reject(key, format!("不支持的规则列表格式: {}", format));
+                }
+            }
+            "FixedProfile" => {
+                found = true;
+                if let Some(bypass_list) = profile["bypassList"].as_array() {
+                    for condition in bypass_list {
+                        parse_omega_condition(condition, RouteAction::Direct, report);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if found {
+        Ok(())
+    } else {
+        Err("SwitchyOmega 备份中未找到情景模式".to_string())
+    }
+}
+
+// SwitchyOmega 中 "direct" 表示直连，其余情景模式都视为走代理
+fn omega_action(profile_name: Option<&str>) -> RouteAction {
+    match profile_name {
+        Some("direct") => RouteAction::Direct,
+        _ => RouteAction::Proxy,
+    }
+}
+
+fn parse_omega_condition(condition: &Value, action: RouteAction, report: &mut RuleImportReport) {
+    let condition_type = condition["conditionType"].as_str().unwrap_or_default();
+    let pattern = condition["pattern"].as_str().unwrap_or_default();
+    let source = format!("{}: {}", condition_type, pattern);
+
+    match condition_type {
+        "HostWildcardCondition" | "BypassCondition" => {
+            if pattern == "<local>" {
+                report.reject(&source, "局域网地址默认直连，无需导入");
+                return;
+            }
+            // "*.example.com" 在 SwitchyOmega 中同时匹配 example.com 本身
+            let host = pattern.trim_start_matches("**.").trim_start_matches("*.");
+            if host.contains('*') || host.contains('?') {
+                report.reject(&source, "不支持通配符规则");
+                return;
+            }
+            let host = host.trim_start_matches('[').replace(']', "");
+            match domain_rule(&host) {
+                Some(rule) => report.accept(&source, rule, action),
+                None => report.reject(&source, "无法识别的域名或网段"),
+            }
+        }
+        "IpCondition" => {
+            let ip = condition["ip"].as_str().unwrap_or_default();
+            let prefix = condition["prefixLength"].as_u64().unwrap_or_default();
+            let source = format!("{}: {}/{}", condition_type, ip, prefix);
+            match IpCidr::parse(&format!("{}/{}", ip, prefix)) {
+                Some(cidr) => report.accept(&source, cidr.to_string(), action),
+                None => report.reject(&source, "无法识别的网段"),
+            }
+        }
+        "" => report.reject(&source, "缺少条件类型"),
+        _ => report.reject(&source, format!("不支持的条件类型: {}", condition_type)),
+    }
+}
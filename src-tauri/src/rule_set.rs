@@ -0,0 +1,184 @@
+use crate::bypass::{normalize_host, IpCidr};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// 域名后缀树：按标签倒序存储（com -> example -> www），查找耗时只与主机名的标签数有关
+#[derive(Debug, Default)]
+pub struct DomainTrie {
+    root: TrieNode,
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: HashMap<Box<str>, TrieNode>,
+    rule: Option<usize>,  // 匹配域名本身及子域名的规则
+    exact: Option<usize>, // 只匹配域名本身的规则
+}
+
+impl DomainTrie {
+    // exact 为 true 时规则只匹配域名本身，不匹配子域名
+    pub fn insert(&mut self, domain: &str, rule: usize, exact: bool) {
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        if exact {
+            node.exact.get_or_insert(rule);
+        } else {
+            node.rule.get_or_insert(rule);
+        }
+    }
+
+    // 查找命中的规则：域名本身或其任意父域名，返回最具体的规则
+    // 同一域名同时有精确规则和后缀规则时，精确规则优先
+    pub fn find(&self, host: &str) -> Option<usize> {
+        let mut node = &self.root;
+        let mut matched = None;
+        for label in host.rsplit('.') {
+            match node.children.get(label) {
+                Some(child) => {
+                    node = child;
+                    if node.rule.is_some() {
+                        matched = node.rule;
+                    }
+                }
+                None => return matched,
+            }
+        }
+        node.exact.or(matched)
+    }
+}
+
+// CIDR 前缀树：IPv4 / IPv6 各一棵二叉树，按位查找最长前缀匹配
+#[derive(Debug)]
+pub struct CidrTree {
+    v4: BitTrie,
+    v6: BitTrie,
+}
+
+impl Default for CidrTree {
+    fn default() -> Self {
+        Self {
+            v4: BitTrie::new(),
+            v6: BitTrie::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BitTrie {
+    // 节点数组，children[i] 为 0 表示无子节点（根节点不会被引用）
+    children: Vec<[u32; 2]>,
+    rules: Vec<Option<usize>>,
+}
+
+impl BitTrie {
+    fn new() -> Self {
+        Self {
+            children: vec![[0, 0]],
+            rules: vec![None],
+        }
+    }
+
+    fn insert(&mut self, bits: u128, width: u32, prefix: u8, rule: usize) {
+        let mut node = 0usize;
+        for i in 0..prefix as u32 {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            if self.children[node][bit] == 0 {
+                self.children.push([0, 0]);
+                self.rules.push(None);
+                self.children[node][bit] = (self.children.len() - 1) as u32;
+            }
+            node = self.children[node][bit] as usize;
+        }
+        self.rules[node].get_or_insert(rule);
+    }
+
+    fn find(&self, bits: u128, width: u32) -> Option<usize> {
+        let mut node = 0usize;
+        let mut matched = self.rules[0];
+        for i in 0..width {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            match self.children[node][bit] {
+                0 => break,
+                next => node = next as usize,
+            }
+            if self.rules[node].is_some() {
+                matched = self.rules[node];
+            }
+        }
+        matched
+    }
+}
+
+impl CidrTree {
+    pub fn insert(&mut self, cidr: &IpCidr, rule: usize) {
+        match cidr.addr() {
+            IpAddr::V4(v4) => self
+                .v4
+                .insert(u32::from(v4) as u128, 32, cidr.prefix(), rule),
+            IpAddr::V6(v6) => self.v6.insert(u128::from(v6), 128, cidr.prefix(), rule),
+        }
+    }
+
+    pub fn find(&self, ip: &IpAddr) -> Option<usize> {
+        match ip.to_canonical() {
+            IpAddr::V4(v4) => self.v4.find(u32::from(v4) as u128, 32),
+            IpAddr::V6(v6) => self.v6.find(u128::from(v6), 128),
+        }
+    }
+}
+
+// 编译后的直连规则：设置变化时编译一次，之后每个请求只做查找
+#[derive(Debug, Default)]
+pub struct CompiledRules {
+    rules: Vec<String>,
+    domains: DomainTrie,
+    cidrs: CidrTree,
+}
+
+impl CompiledRules {
+    // 编译直连规则列表，支持 "example.com"、".example.com"、"*.example.com"、IP 和 CIDR
+    // "=example.com" 只匹配 example.com 本身，不匹配子域名
+    pub fn compile(entries: &[String]) -> Self {
+        let mut compiled = Self::default();
+        for entry in entries {
+            let index = compiled.rules.len();
+            let (pattern, exact) = match entry.trim().strip_prefix('=') {
+                Some(rest) => (rest, true),
+                None => (entry.as_str(), false),
+            };
+            if let Some(cidr) = IpCidr::parse(pattern) {
+                compiled.cidrs.insert(&cidr, index);
+            } else {
+                let domain = normalize_host(pattern);
+                let domain = domain.trim_start_matches("*.").trim_start_matches('.');
+                if domain.is_empty() {
+                    continue;
+                }
+                compiled.domains.insert(domain, index, exact);
+            }
+            compiled.rules.push(entry.clone());
+        }
+        compiled
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    // 编译后的规则原文
+    pub fn rules(&self) -> &[String] {
+        &self.rules
+    }
+
+    // 查找主机命中的规则，返回原始规则文本
+    pub fn find(&self, host: &str) -> Option<&str> {
+        let host = normalize_host(host);
+        let index = match host.parse::<IpAddr>() {
+            Ok(ip) => self.cidrs.find(&ip),
+            Err(_) => self.domains.find(&host),
+        };
+        index.map(|i| self.rules[i].as_str())
+    }
+}
@@ -0,0 +1,132 @@
+use crate::proxy_server::{RouteAction, RouteReason};
+use crate::rule_subscription::unix_now;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// 统计的键：命中的规则及其动作
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RuleKey {
+    reason: RouteReason,
+    rule: Option<String>,
+    action: RouteAction,
+}
+
+// 单条规则的计数器，连接转发数据时无锁累加
+#[derive(Debug, Default)]
+pub struct RuleCounter {
+    hits: AtomicU64,
+    bytes_up: AtomicU64,   // 客户端 -> 目标
+    bytes_down: AtomicU64, // 目标 -> 客户端
+    last_hit: AtomicU64,   // 上次命中时间（Unix 秒）
+}
+
+impl RuleCounter {
+    pub fn add_up(&self, bytes: u64) {
+        self.bytes_up.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_down(&self, bytes: u64) {
+        self.bytes_down.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+// 返回给前端的统计条目
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleStatsEntry {
+    pub reason: RouteReason,
+    pub rule: Option<String>,
+    pub action: RouteAction,
+    pub hits: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub last_hit: Option<u64>,
+}
+
+// 各规则的命中统计
+#[derive(Debug, Default)]
+pub struct RuleStats {
+    counters: Mutex<HashMap<RuleKey, Arc<RuleCounter>>>,
+}
+
+impl RuleStats {
+    // 记录一次命中，返回该规则的计数器用于累计流量
+    pub fn record_hit(
+        &self,
+        reason: RouteReason,
+        rule: Option<&str>,
+        action: RouteAction,
+    ) -> Arc<RuleCounter> {
+        let key = RuleKey {
+            reason,
+            rule: rule.map(|r| r.to_string()),
+            action,
+        };
+        let counter = match self.counters.lock() {
+            Ok(mut counters) => counters.entry(key).or_default().clone(),
+            Err(_) => Arc::new(RuleCounter::default()),
+        };
+        counter.hits.fetch_add(1, Ordering::Relaxed);
+        counter.last_hit.store(unix_now(), Ordering::Relaxed);
+        counter
+    }
+
+    // 导出统计；configured 中尚未命中过的规则以零计数列出，便于清理无用规则
+    pub fn snapshot(
+        &self,
+        configured: &[(RouteReason, RouteAction, &[String])],
+    ) -> Vec<RuleStatsEntry> {
+        let counters = match self.counters.lock() {
+            Ok(counters) => counters.clone(),
+            Err(_) => HashMap::new(),
+        };
+
+        let mut entries: Vec<RuleStatsEntry> = counters
+            .iter()
+            .map(|(key, counter)| {
+                let last_hit = counter.last_hit.load(Ordering::Relaxed);
+                RuleStatsEntry {
+                    reason: key.reason,
+                    rule: key.rule.clone(),
+                    action: key.action,
+                    hits: counter.hits.load(Ordering::Relaxed),
+                    bytes_up: counter.bytes_up.load(Ordering::Relaxed),
+                    bytes_down: counter.bytes_down.load(Ordering::Relaxed),
+                    last_hit: (last_hit > 0).then_some(last_hit),
+                }
+            })
+            .collect();
+
+        for (reason, action, rules) in configured {
+            for rule in rules.iter() {
+                let key = RuleKey {
+                    reason: *reason,
+                    rule: Some(rule.clone()),
+                    action: *action,
+                };
+                if !counters.contains_key(&key) {
+                    entries.push(RuleStatsEntry {
+                        reason: *reason,
+                        rule: Some(rule.clone()),
+                        action: *action,
+                        hits: 0,
+                        bytes_up: 0,
+                        bytes_down: 0,
+                        last_hit: None,
+                    });
+                }
+            }
+        }
+
+        // 命中多的在前
+        entries.sort_by(|a, b| b.hits.cmp(&a.hits).then(a.rule.cmp(&b.rule)));
+        entries
+    }
+
+    pub fn reset(&self) {
+        if let Ok(mut counters) = self.counters.lock() {
+            counters.clear();
+        }
+    }
+}
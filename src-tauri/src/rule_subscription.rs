@@ -0,0 +1,383 @@
+use crate::inbound_auth::InboundAuth;
+use crate::proxy_server::{proxy_authority, ProxySettings, RouteAction};
+use crate::rule_import::{parse_rules, RuleFormat};
+use log::{debug, info, warn};
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_REFRESH_INTERVAL_SECS: u64 = 24 * 60 * 60; // 默认每天刷新一次
+pub const MIN_REFRESH_INTERVAL_SECS: u64 = 5 * 60; // 最短刷新间隔 5 分钟
+const DOWNLOAD_TIMEOUT: u64 = 30; // 下载超时（秒）
+
+// 远程规则订阅
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleSubscription {
+    pub id: String,
+    pub url: String,
+    pub format: RuleFormat,
+    pub refresh_interval_secs: u64,
+    pub enabled: bool,
+    #[serde(default)]
+    pub last_checked: Option<u64>, // 上次检查时间（Unix 秒）
+    #[serde(default)]
+    pub last_updated: Option<u64>, // 上次成功更新规则的时间（Unix 秒）
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    // 订阅提供的规则缓存在单独的文件中，不写入代理设置、不返回给前端
+    #[serde(skip)]
+    pub rules: SubscriptionRules,
+}
+
+// 订阅下载的规则
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionRules {
+    pub direct_domains: Vec<String>, // 订阅提供的直连规则
+    pub proxy_domains: Vec<String>,  // 订阅提供的代理规则
+}
+
+// 规则可能有上万条，日志中只输出数量
+impl fmt::Debug for SubscriptionRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubscriptionRules")
+            .field("direct_domains", &self.direct_domains.len())
+            .field("proxy_domains", &self.proxy_domains.len())
+            .finish()
+    }
+}
+
+impl RuleSubscription {
+    pub fn new(url: String, format: RuleFormat, refresh_interval_secs: u64) -> Self {
+        Self {
+            id: format!("sub-{}", unix_now_millis()),
+            url,
+            format,
+            refresh_interval_secs: refresh_interval_secs.max(MIN_REFRESH_INTERVAL_SECS),
+            enabled: true,
+            last_checked: None,
+            last_updated: None,
+            etag: None,
+            last_error: None,
+            rules: SubscriptionRules::default(),
+        }
+    }
+
+    // 是否到了刷新时间
+    pub fn is_due(&self, now: u64) -> bool {
+        match self.last_checked {
+            Some(last) => now.saturating_sub(last) >= self.refresh_interval_secs,
+            None => true,
+        }
+    }
+}
+
+// 一次刷新的结果
+#[derive(Debug)]
+pub enum RefreshOutcome {
+    Updated {
+        rules: SubscriptionRules,
+        etag: Option<String>,
+    },
+    NotModified, // 服务器返回 304，规则未变化
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn unix_now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+// 创建下载客户端；传入本地代理地址时，下载请求经过代理自身的分流规则
+// 本地代理启用了入站认证时带上凭据，否则仅监听局域网地址或对本机也要求认证时会被拒绝（407）
+pub fn build_client(proxy: Option<(&str, &InboundAuth)>) -> Result<Client, String> {
+    let mut builder = Client::builder().timeout(Duration::from_secs(DOWNLOAD_TIMEOUT));
+    builder = match proxy {
+        Some((url, auth)) => {
+            let mut proxy = reqwest::Proxy::all(url).map_err(|e| format!("代理地址无效: {}", e))?;
+            if auth.enabled {
+                proxy = proxy.basic_auth(&auth.username, &auth.password);
+            }
+            builder.proxy(proxy)
+        }
+        None => builder.no_proxy(),
+    };
+    builder
+        .build()
+        .map_err(|e| format!("创建HTTP客户端失败: {}", e))
+}
+
+// 下载并校验订阅内容；失败时返回错误，由调用方保留旧规则
+pub async fn fetch_subscription(
+    client: &Client,
+    sub: &RuleSubscription,
+) -> Result<RefreshOutcome, String> {
+    // 订阅地址中可能带有令牌，日志只记录主机
+    info!("刷新规则订阅: {} ({})", sub.id, proxy_authority(&sub.url));
+
+    let mut request = client.get(&sub.url);
+    if let Some(etag) = &sub.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("下载失败: {}", e))?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        debug!("规则未变化: {}", sub.id);
+        return Ok(RefreshOutcome::NotModified);
+    }
+    if !response.status().is_success() {
+        return Err(format!("服务器返回错误状态: {}", response.status()));
+    }
+
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let content = response
+        .text()
+        .await
+        .map_err(|e| format!("读取响应失败: {}", e))?;
+
+    let report = parse_rules(sub.format, &content)?;
+    if report.accepted.is_empty() {
+        return Err("订阅内容中没有可用规则".to_string());
+    }
+
+    let mut rules = SubscriptionRules::default();
+    for entry in report.accepted {
+        match entry.action {
+            RouteAction::Direct => rules.direct_domains.push(entry.rule),
+            RouteAction::Proxy => rules.proxy_domains.push(entry.rule),
+        }
+    }
+    info!(
+        "订阅已更新: {} (直连 {} 条, 代理 {} 条, 不支持 {} 条)",
+        sub.id,
+        rules.direct_domains.len(),
+        rules.proxy_domains.len(),
+        report.unsupported.len()
+    );
+
+    Ok(RefreshOutcome::Updated { rules, etag })
+}
+
+// 把刷新结果写回设置中对应的订阅；失败时只记录错误，保留上一次的规则
+pub fn apply_refresh_results(
+    settings: &mut ProxySettings,
+    results: Vec<(String, Result<RefreshOutcome, String>)>,
+    now: u64,
+) {
+    for (id, result) in results {
+        let Some(sub) = settings.subscriptions.iter_mut().find(|s| s.id == id) else {
+            // 刷新期间订阅已被删除
+            continue;
+        };
+        sub.last_checked = Some(now);
+        match result {
+            Ok(RefreshOutcome::Updated { rules, etag }) => {
+                sub.rules = rules;
+                sub.etag = etag;
+                sub.last_updated = Some(now);
+                sub.last_error = None;
+            }
+            Ok(RefreshOutcome::NotModified) => {
+                sub.last_updated = Some(now);
+                sub.last_error = None;
+            }
+            Err(e) => {
+                warn!("订阅刷新失败，保留旧规则: {} - {}", id, e);
+                sub.last_error = Some(e);
+            }
+        }
+    }
+}
+
+// 前端提交的设置不含订阅规则，沿用当前设置中同一订阅的规则
+pub fn keep_cached_rules(settings: &mut ProxySettings, current: &ProxySettings) {
+    for sub in settings.subscriptions.iter_mut() {
+        if sub.rules != SubscriptionRules::default() {
+            continue;
+        }
+        if let Some(cached) = current.subscriptions.iter().find(|c| c.id == sub.id) {
+            sub.rules = cached.rules.clone();
+        }
+    }
+}
+
+// 从缓存文件读取订阅规则；没有缓存的订阅清除 ETag 和检查时间，以便尽快重新下载
+pub fn load_cache(settings: &mut ProxySettings, path: &Path) {
+    let mut cache: HashMap<String, SubscriptionRules> = match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("订阅规则缓存解析失败: {}", e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    };
+    for sub in settings.subscriptions.iter_mut() {
+        match cache.remove(&sub.id) {
+            Some(rules) => sub.rules = rules,
+            None => {
+                sub.etag = None;
+                sub.last_checked = None;
+            }
+        }
+    }
+}
+
+// 把订阅规则写入缓存文件，已删除的订阅不再保留
+pub fn save_cache(settings: &ProxySettings, path: &Path) -> Result<(), String> {
+    let cache: HashMap<&str, &SubscriptionRules> = settings
+        .subscriptions
+        .iter()
+        .map(|sub| (sub.id.as_str(), &sub.rules))
+        .collect();
+    let json = serde_json::to_string(&cache).map_err(|e| format!("序列化订阅规则失败: {}", e))?;
+    fs::write(path, json).map_err(|e| format!("写入订阅规则缓存失败: {}", e))?;
+    debug!("订阅规则缓存已保存: {:?}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const ETAG_VALUE: &str = "\"v1\"";
+
+    // 本地 HTTP 服务：返回 gfwlist 规则，请求带匹配的 If-None-Match 时返回 304
+    fn serve_rules(requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/gfwlist.txt", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut not_modified = false;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    let lower = line.to_lowercase();
+                    if lower.starts_with("if-none-match:") && line.contains(ETAG_VALUE) {
+                        not_modified = true;
+                    }
+                }
+                let response = if not_modified {
+                    "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+                } else {
+                    let body = "[AutoProxy 0.2.9]\n! comment\n||google.com\n@@||cn.example.com\n";
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        ETAG_VALUE,
+                        body.len(),
+                        body
+                    )
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn refresh_updates_rules_and_honours_etag() {
+        let url = serve_rules(2);
+        let client = build_client(None).unwrap();
+        let mut settings = ProxySettings::default();
+        settings
+            .subscriptions
+            .push(RuleSubscription::new(url, RuleFormat::Gfwlist, 0));
+        let id = settings.subscriptions[0].id.clone();
+
+        let outcome = fetch_subscription(&client, &settings.subscriptions[0]).await;
+        apply_refresh_results(&mut settings, vec![(id.clone(), outcome)], 100);
+        let sub = &settings.subscriptions[0];
+        assert_eq!(sub.rules.proxy_domains, vec!["google.com".to_string()]);
+        assert_eq!(sub.rules.direct_domains, vec!["cn.example.com".to_string()]);
+        assert_eq!(sub.etag.as_deref(), Some(ETAG_VALUE));
+        assert_eq!(sub.last_updated, Some(100));
+        assert_eq!(sub.last_error, None);
+
+        // 第二次请求带 ETag，服务器返回 304，保留已有规则
+        let outcome = fetch_subscription(&client, &settings.subscriptions[0]).await;
+        assert!(matches!(outcome, Ok(RefreshOutcome::NotModified)));
+        apply_refresh_results(&mut settings, vec![(id, outcome)], 200);
+        let sub = &settings.subscriptions[0];
+        assert_eq!(sub.rules.proxy_domains, vec!["google.com".to_string()]);
+        assert_eq!(sub.last_checked, Some(200));
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_keeps_previous_rules() {
+        let client = build_client(None).unwrap();
+        let mut settings = ProxySettings::default();
+        let mut sub = RuleSubscription::new(
+            "http://127.0.0.1:1/unreachable".to_string(),
+            RuleFormat::Gfwlist,
+            0,
+        );
+        sub.rules.proxy_domains = vec!["google.com".to_string()];
+        let id = sub.id.clone();
+        settings.subscriptions.push(sub);
+
+        let outcome = fetch_subscription(&client, &settings.subscriptions[0]).await;
+        assert!(outcome.is_err());
+        apply_refresh_results(&mut settings, vec![(id, outcome)], 100);
+        let sub = &settings.subscriptions[0];
+        assert_eq!(sub.rules.proxy_domains, vec!["google.com".to_string()]);
+        assert!(sub.last_error.is_some());
+    }
+
+    #[test]
+    fn cache_round_trip_and_missing_entries_reset_etag() {
+        let path =
+            std::env::temp_dir().join(format!("rule_subscriptions_{}.json", unix_now_millis()));
+        let mut settings = ProxySettings::default();
+        let mut cached = RuleSubscription::new("http://a/".to_string(), RuleFormat::Gfwlist, 0);
+        cached.id = "cached".to_string();
+        cached.rules.proxy_domains = vec!["google.com".to_string()];
+        settings.subscriptions.push(cached);
+        save_cache(&settings, &path).unwrap();
+
+        // 设置文件中不含规则
+        let json = serde_json::to_string(&settings).unwrap();
+        assert!(!json.contains("google.com"));
+        let mut loaded: ProxySettings = serde_json::from_str(&json).unwrap();
+        let mut missing = RuleSubscription::new("http://b/".to_string(), RuleFormat::Gfwlist, 0);
+        missing.id = "missing".to_string();
+        missing.etag = Some(ETAG_VALUE.to_string());
+        missing.last_checked = Some(100);
+        loaded.subscriptions.push(missing);
+
+        load_cache(&mut loaded, &path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            loaded.subscriptions[0].rules.proxy_domains,
+            vec!["google.com".to_string()]
+        );
+        assert_eq!(loaded.subscriptions[1].etag, None);
+        assert_eq!(loaded.subscriptions[1].last_checked, None);
+    }
+}
@@ -0,0 +1,201 @@
+use arc_swap::ArcSwap;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// 读取的最小块大小，限速很低时也不至于逐字节转发
+const MIN_CHUNK: usize = 1024;
+
+// 带宽限制（字节/秒，0 表示不限制），用于模拟慢速网络或限制后台下载
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct BandwidthSettings {
+    pub enabled: bool,
+    // 所有连接合计的上传 / 下载速率
+    pub upload_bps: u64,
+    pub download_bps: u64,
+    // 按目标主机限速，每个匹配的主机单独计算
+    pub hosts: Vec<HostBandwidth>,
+    // 建立上游连接时额外增加的延迟（毫秒），每个上游连接只等待一次，不影响之后的读写
+    pub latency_ms: u64,
+}
+
+// 单个主机规则：host 为 "*"（所有主机）、域名（同时匹配子域名）或 IP
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostBandwidth {
+    pub host: String,
+    #[serde(default)]
+    pub upload_bps: u64,
+    #[serde(default)]
+    pub download_bps: u64,
+}
+
+impl BandwidthSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(rule) = self.hosts.iter().find(|h| h.host.trim().is_empty()) {
+            return Err(format!("主机限速规则缺少主机: {:?}", rule));
+        }
+        Ok(())
+    }
+
+    // 第一条匹配该主机的规则
+    fn host_rule(&self, host: &str) -> Option<&HostBandwidth> {
+        self.hosts.iter().find(|rule| {
+            let pattern = rule
+                .host
+                .trim()
+                .trim_start_matches("*.")
+                .to_ascii_lowercase();
+            pattern == "*"
+                || host == pattern
+                || host
+                    .strip_suffix(pattern.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Upload,   // 客户端 -> 目标
+    Download, // 目标 -> 客户端
+}
+
+// 令牌桶：容量为一秒的流量，令牌不足时允许透支，按透支量计算等待时间
+#[derive(Debug)]
+struct TokenBucket {
+    state: Mutex<(f64, Instant)>, // (可用令牌, 上次补充时间)
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+}
+
+impl TokenBucket {
+    // 消耗 n 字节的令牌，返回需要等待的时间
+    fn reserve(&self, n: usize, rate: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if rate == 0 {
+            *state = (0.0, now);
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+        let refill = now.duration_since(state.1).as_secs_f64() * rate;
+        state.0 = (state.0 + refill).min(rate) - n as f64;
+        state.1 = now;
+        if state.0 >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.0 / rate)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BucketPair {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl BucketPair {
+    fn get(&self, direction: Direction) -> &TokenBucket {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+}
+
+// 全局和各主机的令牌桶；速率每次从当前设置读取，修改设置对进行中的连接立即生效
+#[derive(Debug, Default)]
+pub struct Throttle {
+    settings: ArcSwap<BandwidthSettings>,
+    global: BucketPair,
+    hosts: Mutex<HashMap<String, Arc<BucketPair>>>,
+}
+
+impl Throttle {
+    pub fn configure(&self, settings: &BandwidthSettings) {
+        if **self.settings.load() != *settings {
+            info!("带宽限制: {:?}", settings);
+            self.settings.store(Arc::new(settings.clone()));
+        }
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        let settings = self.settings.load();
+        (settings.enabled && settings.latency_ms > 0)
+            .then(|| Duration::from_millis(settings.latency_ms))
+    }
+
+    // 为一个到 host 的连接取得限速句柄
+    pub fn for_host(self: &Arc<Self>, host: &str) -> HostThrottle {
+        let host = host.to_ascii_lowercase();
+        let buckets = {
+            let mut hosts = self.hosts.lock().unwrap();
+            // 清理没有连接在使用的主机
+            hosts.retain(|_, buckets| Arc::strong_count(buckets) > 1);
+            Arc::clone(hosts.entry(host.clone()).or_default())
+        };
+        HostThrottle {
+            throttle: Arc::clone(self),
+            host,
+            buckets,
+        }
+    }
+}
+
+// 单个连接使用的限速句柄，隧道两个方向各持有一份
+#[derive(Clone)]
+pub struct HostThrottle {
+    throttle: Arc<Throttle>,
+    host: String,
+    buckets: Arc<BucketPair>,
+}
+
+impl HostThrottle {
+    // 当前对该方向生效的速率：(全局, 主机)
+    fn rates(&self, settings: &BandwidthSettings, direction: Direction) -> (u64, u64) {
+        if !settings.enabled {
+            return (0, 0);
+        }
+        let host_rule = settings.host_rule(&self.host);
+        match direction {
+            Direction::Upload => (
+                settings.upload_bps,
+                host_rule.map_or(0, |rule| rule.upload_bps),
+            ),
+            Direction::Download => (
+                settings.download_bps,
+                host_rule.map_or(0, |rule| rule.download_bps),
+            ),
+        }
+    }
+
+    // 每次读取的最大字节数：限速时按较低速率的四分之一读取，使等待时间保持在较短范围
+    pub fn chunk_size(&self, direction: Direction, buffer_size: usize) -> usize {
+        let settings = self.throttle.settings.load();
+        let lowest = match self.rates(&settings, direction) {
+            (0, 0) => return buffer_size,
+            (0, rate) | (rate, 0) => rate,
+            (global, host) => global.min(host),
+        };
+        (lowest as usize / 4).clamp(MIN_CHUNK.min(buffer_size), buffer_size)
+    }
+
+    // 消耗 n 字节的额度，返回转发前需要等待的时间
+    pub fn delay(&self, direction: Direction, n: usize) -> Duration {
+        let settings = self.throttle.settings.load();
+        let (global, host) = self.rates(&settings, direction);
+        let global_wait = self.throttle.global.get(direction).reserve(n, global);
+        let host_wait = self.buckets.get(direction).reserve(n, host);
+        global_wait.max(host_wait)
+    }
+}
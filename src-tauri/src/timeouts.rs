@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// 各阶段的超时（秒），0 表示不限制
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TimeoutSettings {
+    // 建立到目标或上游代理的 TCP 连接
+    pub connect_secs: u64,
+    // 读取客户端请求头、SOCKS5 协商以及与上游代理的 CONNECT / SOCKS5 握手
+    pub handshake_secs: u64,
+    // 请求发出后等待目标返回第一个字节；默认不限制，长轮询等请求的响应可能很久才返回
+    pub first_byte_secs: u64,
+    // 隧道双向都没有数据的最长时间
+    pub idle_secs: u64,
+    // WebSocket 等协议升级后的连接的空闲时间
+    // 只对能看到请求头的连接生效：明文 ws:// 和已解密的 HTTPS；
+    // 未解密的 CONNECT / SOCKS5 隧道（包括 wss://）无法识别协议升级，使用 idle_secs
+    pub websocket_idle_secs: u64,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        Self {
+            connect_secs: 10,
+            handshake_secs: 10,
+            first_byte_secs: 0,
+            idle_secs: 300,
+            websocket_idle_secs: 3600,
+        }
+    }
+}
+
+// 隧道转发阶段使用的超时
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelTimeouts {
+    pub first_byte: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+fn secs(value: u64) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_secs(value))
+}
+
+impl TimeoutSettings {
+    pub fn connect(&self) -> Option<Duration> {
+        secs(self.connect_secs)
+    }
+
+    pub fn handshake(&self) -> Option<Duration> {
+        secs(self.handshake_secs)
+    }
+
+    // upgraded 为 WebSocket 等协议升级的连接，使用单独的空闲时间
+    pub fn tunnel(&self, upgraded: bool) -> TunnelTimeouts {
+        TunnelTimeouts {
+            first_byte: secs(self.first_byte_secs),
+            idle: secs(if upgraded {
+                self.websocket_idle_secs
+            } else {
+                self.idle_secs
+            }),
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use crate::proxy_server::TunnelStream;
+use crate::rule_set::CompiledRules;
+use arc_swap::ArcSwap;
+use chrono::{Datelike, Duration as ChronoDuration, Utc};
+use log::{info, warn};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+};
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const CA_CERT_FILE_NAME: &str = "ca.crt";
+const CA_KEY_FILE_NAME: &str = "ca.key";
+const CA_COMMON_NAME: &str = "liuyao_desktop_tauri Local CA";
+const CA_VALID_DAYS: i64 = 3650;
+const LEAF_VALID_DAYS: i64 = 397; // 浏览器不接受有效期超过 398 天的服务器证书
+const MAX_CACHED_LEAVES: usize = 1000; // 缓存的站点证书数，超出后清空重新签发
+const TLS_READ_SIZE: usize = 16 * 1024;
+
+// HTTPS 解密设置：只对列表中的主机解密 CONNECT 隧道，其余主机仍然原样转发
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct TlsInterceptSettings {
+    pub enabled: bool,
+    // 解密的主机，支持 "example.com"、".example.com"、"*.example.com"、IP 和 CIDR
+    pub hosts: Vec<String>,
+}
+
+// 本地 CA：首次使用时生成并保存到配置目录，用户将其安装为受信任的根证书后才能解密
+struct LocalCa {
+    issuer: Certificate, // 由保存的密钥重新构建，只用于签发站点证书
+    key: KeyPair,
+    der: CertificateDer<'static>, // 保存的证书原文，随站点证书一起发送
+    pem: String,
+}
+
+// CA 的名称固定，由保存的密钥即可重新构建签发者
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, CA_COMMON_NAME);
+    name.push(DnType::OrganizationName, "liuyao_desktop_tauri");
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    params
+}
+
+// 从今天前一天开始、有效 days 天
+fn set_validity(params: &mut CertificateParams, days: i64) {
+    let ymd = |date: chrono::NaiveDate| {
+        rcgen::date_time_ymd(date.year(), date.month() as u8, date.day() as u8)
+    };
+    let today = Utc::now().date_naive();
+    params.not_before = ymd(today - ChronoDuration::days(1));
+    params.not_after = ymd(today + ChronoDuration::days(days));
+}
+
+impl LocalCa {
+    // 读取保存的 CA，不存在时生成新的；dir 为空时只在内存中生成
+    fn load_or_create(dir: Option<&Path>) -> Result<Self, String> {
+        if let Some(dir) = dir {
+            let cert_path = dir.join(CA_CERT_FILE_NAME);
+            let key_path = dir.join(CA_KEY_FILE_NAME);
+            if cert_path.exists() && key_path.exists() {
+                let pem = fs::read_to_string(&cert_path)
+                    .map_err(|e| format!("读取 CA 证书失败: {}", e))?;
+                let key_pem = fs::read_to_string(&key_path)
+                    .map_err(|e| format!("读取 CA 私钥失败: {}", e))?;
+                return Self::from_pem(pem, &key_pem);
+            }
+        }
+
+        let key = KeyPair::generate().map_err(|e| format!("生成 CA 私钥失败: {}", e))?;
+        let mut params = ca_params();
+        set_validity(&mut params, CA_VALID_DAYS);
+        let cert = params
+            .self_signed(&key)
+            .map_err(|e| format!("生成 CA 证书失败: {}", e))?;
+        let pem = cert.pem();
+        if let Some(dir) = dir {
+            save_ca(dir, &pem, &key.serialize_pem())?;
+            info!("已生成本地 CA: {}", dir.join(CA_CERT_FILE_NAME).display());
+        }
+        Self::from_pem(pem, &key.serialize_pem())
+    }
+
+    fn from_pem(pem: String, key_pem: &str) -> Result<Self, String> {
+        let key = KeyPair::from_pem(key_pem).map_err(|e| format!("无效的 CA 私钥: {}", e))?;
+        let issuer = ca_params()
+            .self_signed(&key)
+            .map_err(|e| format!("加载 CA 失败: {}", e))?;
+        let der = CertificateDer::from_pem_slice(pem.as_bytes())
+            .map_err(|e| format!("无效的 CA 证书: {}", e))?;
+        Ok(Self {
+            issuer,
+            key,
+            der,
+            pem,
+        })
+    }
+
+    // 为主机签发服务器证书，返回证书链（站点证书和 CA）及私钥
+    fn issue(
+        &self,
+        host: &str,
+    ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+        let key = KeyPair::generate().map_err(|e| format!("生成站点私钥失败: {}", e))?;
+        let mut params = CertificateParams::new(vec![host.to_string()])
+            .map_err(|e| format!("无效的主机名 {}: {}", host, e))?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.use_authority_key_identifier_extension = true;
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        set_validity(&mut params, LEAF_VALID_DAYS);
+        let cert = params
+            .signed_by(&key, &self.issuer, &self.key)
+            .map_err(|e| format!("签发站点证书失败: {}", e))?;
+        let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+        Ok((vec![cert.der().clone(), self.der.clone()], key.into()))
+    }
+}
+
+fn save_ca(dir: &Path, cert_pem: &str, key_pem: &str) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("创建 CA 目录失败: {}", e))?;
+    write_private_key(&dir.join(CA_KEY_FILE_NAME), key_pem)
+        .map_err(|e| format!("保存 CA 私钥失败: {}", e))?;
+    fs::write(dir.join(CA_CERT_FILE_NAME), cert_pem).map_err(|e| format!("保存 CA 证书失败: {}", e))
+}
+
+// 私钥只允许当前用户读取：创建文件时即设置 0600，避免写入期间被其他用户读取
+#[cfg(unix)]
+fn write_private_key(path: &Path, key_pem: &str) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(key_pem.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private_key(path: &Path, key_pem: &str) -> io::Result<()> {
+    fs::write(path, key_pem)?;
+    restrict_to_owner(path);
+    Ok(())
+}
+
+// Windows：去掉继承的权限，只授予当前用户完全控制
+#[cfg(windows)]
+fn restrict_to_owner(path: &Path) {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x08000000; // 不弹出控制台窗口
+
+    let Ok(user) = std::env::var("USERNAME") else {
+        warn!("无法获取当前用户名，CA 私钥权限未限制: {:?}", path);
+        return;
+    };
+    let grant = format!("{}:F", user);
+    let result = std::process::Command::new("icacls")
+        .arg(path)
+        .args(["/inheritance:r", "/grant:r", grant.as_str()])
+        .creation_flags(CREATE_NO_WINDOW)
+        .output();
+    match result {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => warn!(
+            "限制 CA 私钥权限失败: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => warn!("限制 CA 私钥权限失败: {}", e),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn restrict_to_owner(_path: &Path) {}
+
+// HTTPS 解密：按主机签发证书与客户端握手，再以普通 TLS 客户端连接目标
+pub struct TlsInterceptor {
+    dir: Option<PathBuf>,
+    settings: ArcSwap<TlsInterceptSettings>,
+    hosts: ArcSwap<CompiledRules>,
+    ca: Mutex<Option<Arc<LocalCa>>>, // 首次使用时加载或生成
+    leaves: Mutex<HashMap<String, Arc<ServerConfig>>>, // 按主机缓存的服务器配置
+    client_config: Mutex<Option<Arc<ClientConfig>>>, // 连接目标使用系统信任的根证书
+}
+
+impl Default for TlsInterceptor {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl TlsInterceptor {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            settings: ArcSwap::default(),
+            hosts: ArcSwap::default(),
+            ca: Mutex::new(None),
+            leaves: Mutex::default(),
+            client_config: Mutex::new(None),
+        }
+    }
+
+    pub fn configure(&self, settings: &TlsInterceptSettings) {
+        if **self.settings.load() == *settings {
+            return;
+        }
+        info!("HTTPS 解密: {:?}", settings);
+        self.hosts
+            .store(Arc::new(CompiledRules::compile(&settings.hosts)));
+        self.settings.store(Arc::new(settings.clone()));
+    }
+
+    // 是否解密到该主机的隧道
+    pub fn intercepts(&self, host: &str) -> bool {
+        self.settings.load().enabled && self.hosts.load().find(host).is_some()
+    }
+
+    fn ca(&self) -> Result<Arc<LocalCa>, String> {
+        let mut ca = self.ca.lock().unwrap();
+        if let Some(ca) = ca.as_ref() {
+            return Ok(Arc::clone(ca));
+        }
+        let loaded = Arc::new(LocalCa::load_or_create(self.dir.as_deref())?);
+        *ca = Some(Arc::clone(&loaded));
+        Ok(loaded)
+    }
+
+    // CA 证书（PEM），用于安装到系统或浏览器的受信任根证书
+    pub fn ca_certificate(&self) -> Result<String, String> {
+        Ok(self.ca()?.pem.clone())
+    }
+
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, String> {
+        if let Some(config) = self.leaves.lock().unwrap().get(host) {
+            return Ok(Arc::clone(config));
+        }
+        let (chain, key) = self.ca()?.issue(host)?;
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|e| format!("站点证书无效: {}", e))?;
+        // 解密后按 HTTP/1.1 解析请求，不协商 HTTP/2
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let config = Arc::new(config);
+
+        let mut leaves = self.leaves.lock().unwrap();
+        if leaves.len() >= MAX_CACHED_LEAVES {
+            leaves.clear();
+        }
+        leaves.insert(host.to_string(), Arc::clone(&config));
+        Ok(config)
+    }
+
+    fn client_config(&self) -> Result<Arc<ClientConfig>, String> {
+        let mut client_config = self.client_config.lock().unwrap();
+        if let Some(config) = client_config.as_ref() {
+            return Ok(Arc::clone(config));
+        }
+        let native = rustls_native_certs::load_native_certs();
+        for e in &native.errors {
+            warn!("读取系统根证书失败: {}", e);
+        }
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(native.certs);
+        if added == 0 {
+            return Err("没有可用的系统根证书".to_string());
+        }
+        let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let config = Arc::new(config);
+        *client_config = Some(Arc::clone(&config));
+        Ok(config)
+    }
+
+    // 准备与客户端握手，CA 或站点证书不可用时返回错误，调用方可改为原样转发
+    pub fn prepare(&self, host: &str) -> Result<ServerConnection, String> {
+        let config = self.server_config(host)?;
+        ServerConnection::new(config).map_err(|e| e.to_string())
+    }
+
+    // 以签发的证书与客户端完成握手
+    pub fn accept(&self, stream: TcpStream, conn: ServerConnection) -> io::Result<TlsStream> {
+        TlsStream::handshake(stream, Connection::from(conn))
+    }
+
+    // 作为 TLS 客户端与目标握手并校验目标证书
+    pub fn connect(&self, stream: TcpStream, host: &str) -> io::Result<TlsStream> {
+        let config = self.client_config().map_err(io::Error::other)?;
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let conn = ClientConnection::new(config, name).map_err(io::Error::other)?;
+        TlsStream::handshake(stream, Connection::from(conn))
+    }
+}
+
+// 已完成握手的 TLS 连接，克隆后可由隧道的两个线程分别读写
+pub struct TlsStream {
+    socket: TcpStream,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl TlsStream {
+    fn handshake(mut socket: TcpStream, mut conn: Connection) -> io::Result<Self> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+        Ok(Self {
+            socket,
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // 发送待发送的 TLS 数据（加密后的数据、握手后消息和告警）
+    fn flush_tls(&self, conn: &mut Connection) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0u8; TLS_READ_SIZE];
+        loop {
+            {
+                let mut conn = self.conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Ok(n) => return Ok(n),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    // 对方未发送 close_notify 就关闭了连接，按正常结束处理
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            // 在锁外等待数据，另一个线程可以同时写入
+            let n = (&self.socket).read(&mut raw)?;
+            let mut conn = self.conn.lock().unwrap();
+            let mut data = &raw[..n];
+            loop {
+                conn.read_tls(&mut data)?;
+                if let Err(e) = conn.process_new_packets() {
+                    let _ = self.flush_tls(&mut conn);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+                if data.is_empty() {
+                    break;
+                }
+            }
+            self.flush_tls(&mut conn)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut written = 0;
+        while written < buf.len() {
+            written += conn.writer().write(&buf[written..])?;
+            self.flush_tls(&mut conn)?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        self.flush_tls(&mut conn)
+    }
+}
+
+impl TunnelStream for TlsStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            socket: self.socket.try_clone()?,
+            conn: Arc::clone(&self.conn),
+        })
+    }
+
+    fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    fn close(&self) {
+        let mut conn = self.conn.lock().unwrap();
+        conn.send_close_notify();
+        let _ = self.flush_tls(&mut conn);
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
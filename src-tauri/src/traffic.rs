@@ -0,0 +1,384 @@
+use crate::connections::ConnectionInfo;
+use crate::rule_stats::RuleCounter;
+use crate::rule_subscription::unix_now;
+use chrono::Local;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const HISTORY_DAYS: usize = 90; // 保留的历史天数
+const HISTORY_TOP_ENTRIES: usize = 100; // 每天保留流量最大的主机数
+const IDLE_COUNTER_LIMIT: usize = 500; // 已没有连接的主机/上游计数器最多保留的个数
+
+// 流量合计
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct TrafficTotals {
+    pub bytes_up: u64,   // 客户端 -> 目标
+    pub bytes_down: u64, // 目标 -> 客户端
+    pub connections: u64,
+}
+
+impl TrafficTotals {
+    fn add(&mut self, other: &TrafficTotals) {
+        self.bytes_up += other.bytes_up;
+        self.bytes_down += other.bytes_down;
+        self.connections += other.connections;
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == TrafficTotals::default()
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes_up + self.bytes_down
+    }
+}
+
+// 单个主机/上游的计数器：本次运行的累计值，以及尚未写入历史的增量
+#[derive(Debug, Default)]
+pub struct TrafficCounter {
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    connections: AtomicU64,
+    pending_up: AtomicU64,
+    pending_down: AtomicU64,
+    pending_connections: AtomicU64,
+}
+
+impl TrafficCounter {
+    fn add_up(&self, bytes: u64) {
+        self.bytes_up.fetch_add(bytes, Ordering::Relaxed);
+        self.pending_up.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn add_down(&self, bytes: u64) {
+        self.bytes_down.fetch_add(bytes, Ordering::Relaxed);
+        self.pending_down.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn add_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.pending_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn totals(&self) -> TrafficTotals {
+        TrafficTotals {
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+        }
+    }
+
+    fn pending(&self) -> TrafficTotals {
+        TrafficTotals {
+            bytes_up: self.pending_up.load(Ordering::Relaxed),
+            bytes_down: self.pending_down.load(Ordering::Relaxed),
+            connections: self.pending_connections.load(Ordering::Relaxed),
+        }
+    }
+
+    fn take_pending(&self) -> TrafficTotals {
+        TrafficTotals {
+            bytes_up: self.pending_up.swap(0, Ordering::Relaxed),
+            bytes_down: self.pending_down.swap(0, Ordering::Relaxed),
+            connections: self.pending_connections.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.bytes_up.store(0, Ordering::Relaxed);
+        self.bytes_down.store(0, Ordering::Relaxed);
+        self.connections.store(0, Ordering::Relaxed);
+    }
+}
+
+// 一个连接的流量计量：同时累加到连接列表、命中规则、目标主机、上游和全局计数器
+#[derive(Clone)]
+pub struct TrafficMeter {
+    connection: Option<Arc<ConnectionInfo>>,
+    rule: Option<Arc<RuleCounter>>,
+    counters: [Arc<TrafficCounter>; 3],
+}
+
+impl TrafficMeter {
+    pub fn add_up(&self, bytes: u64) {
+        if let Some(connection) = &self.connection {
+            connection.add_up(bytes);
+        }
+        if let Some(rule) = &self.rule {
+            rule.add_up(bytes);
+        }
+        for counter in &self.counters {
+            counter.add_up(bytes);
+        }
+    }
+
+    pub fn add_down(&self, bytes: u64) {
+        if let Some(connection) = &self.connection {
+            connection.add_down(bytes);
+        }
+        if let Some(rule) = &self.rule {
+            rule.add_down(bytes);
+        }
+        for counter in &self.counters {
+            counter.add_down(bytes);
+        }
+    }
+
+    // 目标返回的第一段数据，用于记录 HTTP 状态码
+    pub fn record_response(&self, head: &[u8]) {
+        if let Some(connection) = &self.connection {
+            connection.record_response(head);
+        }
+    }
+}
+
+// 返回给前端的统计条目
+#[derive(Debug, Clone, Serialize)]
+pub struct TrafficEntry {
+    pub key: String,
+    #[serde(flatten)]
+    pub totals: TrafficTotals,
+}
+
+// 本次运行的流量统计
+#[derive(Debug, Clone, Serialize)]
+pub struct TrafficSnapshot {
+    pub since: u64, // 开始统计的时间（Unix 秒）
+    pub total: TrafficTotals,
+    pub hosts: Vec<TrafficEntry>,
+    pub upstreams: Vec<TrafficEntry>,
+}
+
+// 某一天的流量
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DayTraffic {
+    pub total: TrafficTotals,
+    #[serde(default)]
+    pub hosts: BTreeMap<String, TrafficTotals>,
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, TrafficTotals>,
+}
+
+// 按天保存的历史流量，键为本地日期 "YYYY-MM-DD"
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrafficHistory {
+    pub days: BTreeMap<String, DayTraffic>,
+}
+
+impl TrafficHistory {
+    fn load(path: &PathBuf) -> Self {
+        match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!("解析流量历史失败，重新开始记录: {}", e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn save(&self, path: &PathBuf) -> Result<(), String> {
+        let json = serde_json::to_string(self).map_err(|e| format!("序列化流量历史失败: {}", e))?;
+        fs::write(path, json).map_err(|e| format!("写入流量历史失败: {}", e))
+    }
+
+    // 只保留最近的天数，每天只保留流量最大的主机
+    fn prune(&mut self) {
+        while self.days.len() > HISTORY_DAYS {
+            self.days.pop_first();
+        }
+        for day in self.days.values_mut() {
+            keep_top(&mut day.hosts);
+        }
+    }
+}
+
+fn keep_top(entries: &mut BTreeMap<String, TrafficTotals>) {
+    if entries.len() <= HISTORY_TOP_ENTRIES {
+        return;
+    }
+    let mut sorted: Vec<(String, TrafficTotals)> = std::mem::take(entries).into_iter().collect();
+    sorted.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes()));
+    sorted.truncate(HISTORY_TOP_ENTRIES);
+    entries.extend(sorted);
+}
+
+// 全局、按目标主机、按上游的流量统计；history_path 为空时不保存历史
+#[derive(Debug)]
+pub struct TrafficStats {
+    since: AtomicU64,
+    global: Arc<TrafficCounter>,
+    hosts: Mutex<HashMap<String, Arc<TrafficCounter>>>,
+    upstreams: Mutex<HashMap<String, Arc<TrafficCounter>>>,
+    history_path: Option<PathBuf>,
+    history: Mutex<Option<TrafficHistory>>, // 首次使用时从文件加载
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl TrafficStats {
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        Self {
+            since: AtomicU64::new(unix_now()),
+            global: Arc::default(),
+            hosts: Mutex::default(),
+            upstreams: Mutex::default(),
+            history_path,
+            history: Mutex::new(None),
+        }
+    }
+
+    // 记录一个新连接，返回用于累计流量的计量器
+    pub fn open_connection(
+        &self,
+        host: &str,
+        upstream: &str,
+        rule: Option<Arc<RuleCounter>>,
+        connection: Option<Arc<ConnectionInfo>>,
+    ) -> TrafficMeter {
+        let counter = |map: &Mutex<HashMap<String, Arc<TrafficCounter>>>, key: &str| {
+            Arc::clone(map.lock().unwrap().entry(key.to_string()).or_default())
+        };
+        let meter = TrafficMeter {
+            connection,
+            rule,
+            counters: [
+                Arc::clone(&self.global),
+                counter(&self.hosts, &host.to_ascii_lowercase()),
+                counter(&self.upstreams, upstream),
+            ],
+        };
+        for counter in &meter.counters {
+            counter.add_connection();
+        }
+        meter
+    }
+
+    // 全局累计值，用于计算实时速率
+    pub fn total(&self) -> TrafficTotals {
+        self.global.totals()
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        let entries = |map: &Mutex<HashMap<String, Arc<TrafficCounter>>>| {
+            let mut entries: Vec<TrafficEntry> = map
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(key, counter)| TrafficEntry {
+                    key: key.clone(),
+                    totals: counter.totals(),
+                })
+                .filter(|entry| !entry.totals.is_empty())
+                .collect();
+            entries.sort_by_key(|entry| std::cmp::Reverse(entry.totals.bytes()));
+            entries
+        };
+        TrafficSnapshot {
+            since: self.since.load(Ordering::Relaxed),
+            total: self.global.totals(),
+            hosts: entries(&self.hosts),
+            upstreams: entries(&self.upstreams),
+        }
+    }
+
+    // 清空本次运行的统计，不影响已记录和待写入的历史
+    pub fn reset(&self) {
+        self.since.store(unix_now(), Ordering::Relaxed);
+        self.global.reset();
+        for map in [&self.hosts, &self.upstreams] {
+            for counter in map.lock().unwrap().values() {
+                counter.reset();
+            }
+        }
+    }
+
+    // 将尚未写入的增量计入今天的历史并保存到文件
+    pub fn persist(&self) -> Result<(), String> {
+        let Some(path) = &self.history_path else {
+            return Ok(());
+        };
+        let mut history = self.history.lock().unwrap();
+        let history = history.get_or_insert_with(|| TrafficHistory::load(path));
+
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let day = history.days.entry(today).or_default();
+        let pending = self.global.take_pending();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        day.total.add(&pending);
+        take_pending_into(&self.hosts, &mut day.hosts);
+        take_pending_into(&self.upstreams, &mut day.upstreams);
+        history.prune();
+        history.save(path)
+    }
+
+    // 历史流量：尚未写入文件的增量只合并到返回的副本中，不写文件
+    pub fn history(&self) -> TrafficHistory {
+        let Some(path) = &self.history_path else {
+            return TrafficHistory::default();
+        };
+        let mut history = self.history.lock().unwrap();
+        let mut history = history
+            .get_or_insert_with(|| TrafficHistory::load(path))
+            .clone();
+
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let day = history.days.entry(today).or_default();
+        day.total.add(&self.global.pending());
+        add_pending(&self.hosts, &mut day.hosts);
+        add_pending(&self.upstreams, &mut day.upstreams);
+        history.prune();
+        history
+    }
+}
+
+// 将各计数器的增量累加到当天记录，不清空增量
+fn add_pending(
+    map: &Mutex<HashMap<String, Arc<TrafficCounter>>>,
+    day: &mut BTreeMap<String, TrafficTotals>,
+) {
+    for (key, counter) in map.lock().unwrap().iter() {
+        let pending = counter.pending();
+        if !pending.is_empty() {
+            day.entry(key.clone()).or_default().add(&pending);
+        }
+    }
+}
+
+// 取出各计数器的增量累加到当天记录，并清理已无连接使用的计数器：
+// 没有流量的直接删除，其余只保留本次运行流量最大的 IDLE_COUNTER_LIMIT 个，避免访问过的主机无限累积
+fn take_pending_into(
+    map: &Mutex<HashMap<String, Arc<TrafficCounter>>>,
+    day: &mut BTreeMap<String, TrafficTotals>,
+) {
+    let mut map = map.lock().unwrap();
+    for (key, counter) in map.iter() {
+        let pending = counter.take_pending();
+        if !pending.is_empty() {
+            day.entry(key.clone()).or_default().add(&pending);
+        }
+    }
+    map.retain(|_, counter| Arc::strong_count(counter) > 1 || !counter.totals().is_empty());
+
+    let mut idle: Vec<(String, u64)> = map
+        .iter()
+        .filter(|(_, counter)| Arc::strong_count(counter) == 1)
+        .map(|(key, counter)| (key.clone(), counter.totals().bytes()))
+        .collect();
+    if idle.len() > IDLE_COUNTER_LIMIT {
+        idle.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        for (key, _) in &idle[IDLE_COUNTER_LIMIT..] {
+            map.remove(key);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::bypass::{domain_matches, normalize_host};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+// URL 改写规则：转发请求前按顺序对匹配主机的请求路径执行
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct UrlRewriteSettings {
+    pub rules: Vec<UrlRewriteRule>,
+}
+
+// 默认只对 core333.com 修正后台路径和资源路径前多余的斜杠
+impl Default for UrlRewriteSettings {
+    fn default() -> Self {
+        let rewrite = |pattern: &str, replacement: &str| UrlRewriteRule {
+            host: "core333.com".to_string(),
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            redirect: None,
+        };
+        Self {
+            rules: vec![
+                rewrite("/api/admin/", "/admin/"),
+                rewrite("//(attachment|static|assets|uploads|images|css|js)", "/$1"),
+            ],
+        }
+    }
+}
+
+// 单条规则：host 为 "*"（所有主机）、域名（同时匹配子域名）或 IP
+// pattern 匹配路径（含查询参数），replacement 中可用 $1 引用分组
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UrlRewriteRule {
+    pub host: String,
+    pub pattern: String,
+    pub replacement: String,
+    // 为 301 或 302 时不转发请求，直接重定向到替换后的地址（路径或完整 URL）
+    #[serde(default)]
+    pub redirect: Option<u16>,
+}
+
+impl UrlRewriteSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        for rule in &self.rules {
+            if rule.host.trim().is_empty() {
+                return Err(format!("URL 改写规则缺少主机: {:?}", rule));
+            }
+            Regex::new(&rule.pattern)
+                .map_err(|e| format!("无效的正则表达式 {}: {}", rule.pattern, e))?;
+            if let Some(status) = rule.redirect {
+                if status != 301 && status != 302 {
+                    return Err(format!("不支持的重定向状态码: {}", status));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// 改写结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum UrlRewrite {
+    Rewrite(String),       // 改写后的路径
+    Redirect(u16, String), // 状态码和 Location
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    host: String, // 规范化后的主机，"*" 表示所有主机
+    pattern: Regex,
+    replacement: String,
+    redirect: Option<u16>,
+}
+
+// 编译后的 URL 改写规则，随配置快照更新
+#[derive(Debug, Default)]
+pub struct UrlRewrites {
+    rules: Vec<CompiledRule>,
+}
+
+impl UrlRewrites {
+    // 编译规则，无效的正则表达式跳过
+    pub fn compile(settings: &UrlRewriteSettings) -> Self {
+        let rules = settings
+            .rules
+            .iter()
+            .filter_map(|rule| match Regex::new(&rule.pattern) {
+                Ok(pattern) => Some(CompiledRule {
+                    host: normalize_host(rule.host.trim().trim_start_matches("*.")),
+                    pattern,
+                    replacement: rule.replacement.clone(),
+                    redirect: rule.redirect,
+                }),
+                Err(e) => {
+                    warn!("忽略无效的 URL 改写规则 {}: {}", rule.pattern, e);
+                    None
+                }
+            })
+            .collect();
+        Self { rules }
+    }
+
+    // 依次执行匹配的规则，改写规则可叠加，命中重定向规则时立即返回；路径未变化时返回 None
+    pub fn apply(&self, host: &str, path: &str) -> Option<UrlRewrite> {
+        let host = normalize_host(host);
+        let mut rewritten = path.to_string();
+        for rule in &self.rules {
+            if rule.host != "*" && !domain_matches(&host, &rule.host, true) {
+                continue;
+            }
+            if !rule.pattern.is_match(&rewritten) {
+                continue;
+            }
+            let replaced = rule
+                .pattern
+                .replace_all(&rewritten, rule.replacement.as_str())
+                .into_owned();
+            if let Some(status) = rule.redirect {
+                return Some(UrlRewrite::Redirect(status, replaced));
+            }
+            rewritten = replaced;
+        }
+        (rewritten != path).then_some(UrlRewrite::Rewrite(rewritten))
+    }
+}
+
+// 重定向响应，响应后关闭连接
+pub fn redirect_response(status: u16, location: &str) -> String {
+    let reason = if status == 301 {
+        "Moved Permanently"
+    } else {
+        "Found"
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason, location
+    )
+}